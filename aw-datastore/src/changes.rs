@@ -0,0 +1,74 @@
+use std::collections::VecDeque;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+/* How many changes to keep, users which fall further behind have to assume everything changed */
+const MAX_CHANGES: usize = 10000;
+
+/// A change to a bucket or its events, recorded so that data derived from the events (such as
+/// cached query results) can be invalidated when the events it was derived from change
+#[derive(Debug, Clone)]
+pub struct BucketChange {
+    pub seq: u64,
+    pub bucket_id: String,
+    /// Time range of the changed events, None if any event in the bucket might have changed
+    /// (such as when the bucket was created, deleted or imported)
+    pub range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl BucketChange {
+    /// Whether the change might affect events within the given time range
+    pub fn overlaps(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> bool {
+        match &self.range {
+            Some((change_start, change_end)) => change_start <= end && change_end >= start,
+            None => true,
+        }
+    }
+}
+
+pub struct ChangeLog {
+    changes: VecDeque<BucketChange>,
+    last_seq: u64,
+}
+
+impl ChangeLog {
+    pub fn new() -> ChangeLog {
+        ChangeLog {
+            changes: VecDeque::new(),
+            last_seq: 0,
+        }
+    }
+
+    pub fn push(&mut self, bucket_id: &str, range: Option<(DateTime<Utc>, DateTime<Utc>)>) {
+        self.last_seq += 1;
+        if self.changes.len() >= MAX_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(BucketChange {
+            seq: self.last_seq,
+            bucket_id: bucket_id.to_string(),
+            range,
+        });
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Returns all changes after the change with the given sequence number, or None if some
+    /// of them have already been dropped from the log
+    pub fn changes_since(&self, seq: u64) -> Option<Vec<BucketChange>> {
+        let first_kept_seq = self.last_seq - self.changes.len() as u64;
+        if seq < first_kept_seq {
+            return None;
+        }
+        Some(
+            self.changes
+                .iter()
+                .filter(|change| change.seq > seq)
+                .cloned()
+                .collect(),
+        )
+    }
+}
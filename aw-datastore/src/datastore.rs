@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 use chrono::DateTime;
 use chrono::Duration;
@@ -10,14 +11,22 @@ use rusqlite::Connection;
 use serde_json::value::Value;
 
 use aw_models::Bucket;
+use aw_models::BucketImportResult;
 use aw_models::BucketMetadata;
+use aw_models::BucketsExport;
 use aw_models::Event;
+use aw_models::ImportConflictStrategy;
+use aw_models::ImportSummary;
 use aw_models::KeyValue;
 
 use rusqlite::params;
 use rusqlite::types::ToSql;
+use rusqlite::types::Value as SqlValue;
 
 use super::DatastoreError;
+use super::EventFilter;
+use super::EventNotification;
+use crate::reader::PendingEvents;
 
 fn _get_db_version(conn: &Connection) -> i32 {
     conn.pragma_query_value(None, "user_version", |row| row.get(0))
@@ -171,8 +180,50 @@ fn _migrate_v3_to_v4(conn: &Connection) {
         .expect("Failed to update database version!");
 }
 
+fn _event_from_row(row: &rusqlite::Row) -> Result<Event, rusqlite::Error> {
+    let id = row.get(0)?;
+    let starttime_ns: i64 = row.get(1)?;
+    let endtime_ns: i64 = row.get(2)?;
+    let data_str: String = row.get(3)?;
+
+    let time_seconds: i64 = (starttime_ns / 1_000_000_000) as i64;
+    let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
+    let duration_ns = endtime_ns - starttime_ns;
+    let data: serde_json::map::Map<String, Value> = serde_json::from_str(&data_str).unwrap();
+
+    Ok(Event {
+        id: Some(id),
+        timestamp: DateTime::<Utc>::from_utc(
+            NaiveDateTime::from_timestamp(time_seconds, time_subnanos),
+            Utc,
+        ),
+        duration: Duration::nanoseconds(duration_ns),
+        data,
+    })
+}
+
+/// The start and end of the event in nanoseconds, as they are stored in the events table
+fn _event_range_ns(event: &Event) -> (i64, i64) {
+    let starttime_ns = event.timestamp.timestamp_nanos();
+    let duration_ns = event.duration.num_nanoseconds().unwrap_or(0);
+    (starttime_ns, starttime_ns + duration_ns)
+}
+
+/// SQL condition leaving out the events with pending changes and its parameters, numbered from
+/// first_param
+fn _pending_ids_sql(pending: &PendingEvents, first_param: usize) -> (String, Vec<SqlValue>) {
+    if pending.is_empty() {
+        return ("1".to_string(), Vec::new());
+    }
+    let placeholders: Vec<String> = (0..pending.len())
+        .map(|i| format!("?{}", first_param + i))
+        .collect();
+    let params = pending.keys().map(|id| SqlValue::Integer(*id)).collect();
+    (format!("id NOT IN ({})", placeholders.join(", ")), params)
+}
+
 pub struct DatastoreInstance {
-    buckets_cache: HashMap<String, Bucket>,
+    buckets_cache: Arc<RwLock<HashMap<String, Bucket>>>,
     first_init: bool,
     pub db_version: i32,
 }
@@ -201,7 +252,7 @@ impl DatastoreInstance {
         }
 
         let mut ds = DatastoreInstance {
-            buckets_cache: HashMap::new(),
+            buckets_cache: Arc::new(RwLock::new(HashMap::new())),
             first_init,
             db_version,
         };
@@ -209,6 +260,16 @@ impl DatastoreInstance {
         Ok(ds)
     }
 
+    /// Returns an instance sharing the buckets cache with this one, used to serve reads from
+    /// separate read-only connections while this instance keeps handling the writes.
+    pub(crate) fn reader_instance(&self) -> DatastoreInstance {
+        DatastoreInstance {
+            buckets_cache: Arc::clone(&self.buckets_cache),
+            first_init: false,
+            db_version: self.db_version,
+        }
+    }
+
     fn get_stored_buckets(&mut self, conn: &Connection) -> Result<(), DatastoreError> {
         let mut stmt = match conn.prepare(
             "
@@ -295,7 +356,10 @@ impl DatastoreInstance {
         for bucket in buckets {
             match bucket {
                 Ok(b) => {
-                    self.buckets_cache.insert(b.id.clone(), b.clone());
+                    self.buckets_cache
+                        .write()
+                        .unwrap()
+                        .insert(b.id.clone(), b.clone());
                 }
                 Err(e) => {
                     return Err(DatastoreError::InternalError(format!(
@@ -370,7 +434,10 @@ impl DatastoreInstance {
                 let events = bucket.events;
                 bucket.events = None;
                 // Cache bucket
-                self.buckets_cache.insert(bucket.id.clone(), bucket.clone());
+                self.buckets_cache
+                    .write()
+                    .unwrap()
+                    .insert(bucket.id.clone(), bucket.clone());
                 // Insert events
                 if let Some(events) = events {
                     self.insert_events(conn, &bucket.id, events.take_inner())?;
@@ -397,6 +464,193 @@ impl DatastoreInstance {
         }
     }
 
+    /// Imports all buckets and their events, either everything is imported or nothing is
+    ///
+    /// Returns the notifications for the changes the import made along with the summary.
+    pub fn import(
+        &mut self,
+        conn: &Connection,
+        import: BucketsExport,
+        strategy: ImportConflictStrategy,
+    ) -> Result<(ImportSummary, Vec<EventNotification>), DatastoreError> {
+        // The worker keeps a transaction open, so use a savepoint to be able to roll back only
+        // the changes done by the import
+        if let Err(err) = conn.execute_batch("SAVEPOINT import;") {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to create import savepoint: {}",
+                err
+            )));
+        }
+        // The buckets cache is shared with the readers, so the import works on a copy of it which
+        // only replaces the shared one once the import has succeeded. Otherwise the readers could
+        // see buckets of an import which gets rolled back.
+        let import_cache = self.buckets_cache.read().unwrap().clone();
+        let shared_cache =
+            std::mem::replace(&mut self.buckets_cache, Arc::new(RwLock::new(import_cache)));
+        let result = self
+            .import_buckets(conn, import, strategy)
+            .and_then(|imported| match conn.execute_batch("RELEASE import;") {
+                Ok(_) => Ok(imported),
+                Err(err) => Err(DatastoreError::InternalError(format!(
+                    "Failed to release import savepoint: {}",
+                    err
+                ))),
+            });
+        let import_cache = std::mem::replace(&mut self.buckets_cache, shared_cache);
+        match result {
+            Ok(imported) => {
+                *self.buckets_cache.write().unwrap() =
+                    std::mem::take(&mut *import_cache.write().unwrap());
+                Ok(imported)
+            }
+            Err(e) => {
+                if let Err(err) = conn.execute_batch("ROLLBACK TO import; RELEASE import;") {
+                    panic!("Failed to roll back import! {}", err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn import_buckets(
+        &mut self,
+        conn: &Connection,
+        import: BucketsExport,
+        strategy: ImportConflictStrategy,
+    ) -> Result<(ImportSummary, Vec<EventNotification>), DatastoreError> {
+        let mut summary = ImportSummary::default();
+        let mut notifications = Vec::new();
+        for (bucket_id, mut bucket) in import.buckets {
+            if bucket.id.is_empty() {
+                bucket.id = bucket_id;
+            }
+            let (mut events, mut skipped) = match bucket.events.take() {
+                Some(events) => {
+                    let total = events.len();
+                    let events = events.take_inner();
+                    let skipped = total - events.len();
+                    (events, skipped)
+                }
+                None => (Vec::new(), 0),
+            };
+            // Event ids from another database are meaningless here and could overwrite events
+            // in other buckets, so let them be reassigned
+            for event in &mut events {
+                event.id = None;
+            }
+
+            let exported_id = bucket.id.clone();
+            let exists = self.buckets_cache.read().unwrap().contains_key(&bucket.id);
+            match strategy {
+                _ if !exists => {
+                    self.create_bucket(conn, bucket.clone())?;
+                    notifications.push(EventNotification::BucketCreated {
+                        bucket_id: bucket.id.clone(),
+                    });
+                }
+                ImportConflictStrategy::Fail => {
+                    return Err(DatastoreError::BucketAlreadyExists(bucket.id))
+                }
+                ImportConflictStrategy::Skip => {
+                    info!("Skipping import of already existing bucket {}", bucket.id);
+                    skipped += events.len();
+                    events.clear();
+                }
+                ImportConflictStrategy::Replace => {
+                    info!("Replacing already existing bucket {}", bucket.id);
+                    self.delete_bucket(conn, &bucket.id)?;
+                    self.create_bucket(conn, bucket.clone())?;
+                    notifications.push(EventNotification::BucketDeleted {
+                        bucket_id: bucket.id.clone(),
+                    });
+                    notifications.push(EventNotification::BucketCreated {
+                        bucket_id: bucket.id.clone(),
+                    });
+                }
+                ImportConflictStrategy::Merge => {
+                    let event_count = events.len();
+                    events = self.dedup_existing_events(conn, &bucket.id, events)?;
+                    skipped += event_count - events.len();
+                }
+                ImportConflictStrategy::Rename => {
+                    bucket.id = self.unused_bucket_id(&bucket.id);
+                    info!(
+                        "Importing already existing bucket {} as {}",
+                        exported_id, bucket.id
+                    );
+                    self.create_bucket(conn, bucket.clone())?;
+                    notifications.push(EventNotification::BucketCreated {
+                        bucket_id: bucket.id.clone(),
+                    });
+                }
+            }
+
+            let events = self.insert_events(conn, &bucket.id, events)?;
+            let inserted = events.len();
+            if !events.is_empty() {
+                notifications.push(EventNotification::Inserted {
+                    bucket_id: bucket.id.clone(),
+                    events,
+                });
+            }
+            info!(
+                "Imported bucket {} with {} events ({} skipped)",
+                bucket.id, inserted, skipped
+            );
+            summary.buckets.insert(
+                exported_id,
+                BucketImportResult {
+                    imported_as: bucket.id,
+                    inserted,
+                    skipped,
+                },
+            );
+        }
+        Ok((summary, notifications))
+    }
+
+    /// Removes the events which already exist in the bucket, as well as duplicates among the
+    /// events themselves
+    fn dedup_existing_events(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        events: Vec<Event>,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        let starttime = events.iter().map(|e| e.timestamp).min();
+        let endtime = events.iter().map(|e| e.calculate_endtime()).max();
+        if starttime.is_none() {
+            return Ok(events);
+        }
+        // Events are not hashable, so only compare events with the same timestamp
+        let mut seen: HashMap<i64, Vec<Event>> = HashMap::new();
+        for event in self.get_events(conn, bucket_id, starttime, endtime, None, None)? {
+            seen.entry(event.timestamp.timestamp_nanos())
+                .or_default()
+                .push(event);
+        }
+        let mut new_events = Vec::new();
+        for event in events {
+            let same_timestamp = seen.entry(event.timestamp.timestamp_nanos()).or_default();
+            if !same_timestamp.contains(&event) {
+                same_timestamp.push(event.clone());
+                new_events.push(event);
+            }
+        }
+        Ok(new_events)
+    }
+
+    fn unused_bucket_id(&self, bucket_id: &str) -> String {
+        let buckets_cache = self.buckets_cache.read().unwrap();
+        let mut new_id = format!("{}-imported", bucket_id);
+        let mut n = 1;
+        while buckets_cache.contains_key(&new_id) {
+            n += 1;
+            new_id = format!("{}-imported-{}", bucket_id, n);
+        }
+        new_id
+    }
+
     pub fn delete_bucket(
         &mut self,
         conn: &Connection,
@@ -411,7 +665,7 @@ impl DatastoreInstance {
         // Delete bucket itself
         match conn.execute("DELETE FROM buckets WHERE id = ?1", &[&bucket.bid]) {
             Ok(_) => {
-                self.buckets_cache.remove(bucket_id);
+                self.buckets_cache.write().unwrap().remove(bucket_id);
                 Ok(())
             }
             Err(err) => match err {
@@ -427,15 +681,15 @@ impl DatastoreInstance {
     }
 
     pub fn get_bucket(&self, bucket_id: &str) -> Result<Bucket, DatastoreError> {
-        let cached_bucket = self.buckets_cache.get(bucket_id);
-        match cached_bucket {
+        let buckets_cache = self.buckets_cache.read().unwrap();
+        match buckets_cache.get(bucket_id) {
             Some(bucket) => Ok(bucket.clone()),
             None => Err(DatastoreError::NoSuchBucket(bucket_id.to_string())),
         }
     }
 
     pub fn get_buckets(&self) -> HashMap<String, Bucket> {
-        self.buckets_cache.clone()
+        self.buckets_cache.read().unwrap().clone()
     }
 
     pub fn insert_events(
@@ -563,10 +817,14 @@ impl DatastoreInstance {
         }
         /* Update buchets_cache if start or end has been updated */
         if update {
-            self.buckets_cache.insert(bucket.id.clone(), bucket.clone());
+            self.buckets_cache
+                .write()
+                .unwrap()
+                .insert(bucket.id.clone(), bucket.clone());
         }
     }
 
+    /// Replaces the last event with the event, which has to have the id of the last event
     pub fn replace_last_event(
         &mut self,
         conn: &Connection,
@@ -574,13 +832,20 @@ impl DatastoreInstance {
         event: &Event,
     ) -> Result<(), DatastoreError> {
         let mut bucket = self.get_bucket(&bucket_id)?;
+        let event_id = match event.id {
+            Some(id) => id,
+            None => {
+                return Err(DatastoreError::InternalError(
+                    "Can't replace the last event with an event without an id".to_string(),
+                ))
+            }
+        };
 
         let mut stmt = match conn.prepare(
             "
                 UPDATE events
                 SET starttime = ?2, endtime = ?3, data = ?4
-                WHERE bucketrow = ?1
-                    AND endtime = (SELECT max(endtime) FROM events WHERE bucketrow = ?1)
+                WHERE bucketrow = ?1 AND id = ?5
             ",
         ) {
             Ok(stmt) => stmt,
@@ -607,6 +872,7 @@ impl DatastoreInstance {
             &starttime_nanos,
             &endtime_nanos,
             &data as &dyn ToSql,
+            &event_id,
         ]) {
             Ok(_) => self.update_endtime(&mut bucket, event),
             Err(err) => {
@@ -619,6 +885,10 @@ impl DatastoreInstance {
         Ok(())
     }
 
+    /// Returns the event the heartbeat was inserted as, and whether it was merged into the last
+    /// event of the bucket
+    ///
+    /// The returned event has no id, last_heartbeat keeps the event as it is stored with its id.
     pub fn heartbeat(
         &mut self,
         conn: &Connection,
@@ -626,7 +896,7 @@ impl DatastoreInstance {
         heartbeat: Event,
         pulsetime: f64,
         last_heartbeat: &mut HashMap<String, Option<Event>>,
-    ) -> Result<Event, DatastoreError> {
+    ) -> Result<(Event, bool), DatastoreError> {
         self.get_bucket(&bucket_id)?;
         if !last_heartbeat.contains_key(bucket_id) {
             last_heartbeat.insert(bucket_id.to_string(), None);
@@ -636,34 +906,44 @@ impl DatastoreInstance {
             Some(last_event) => last_event,
             None => {
                 // last heartbeat was not in cache, fetch from DB
-                let mut last_event_vec = self.get_events(conn, &bucket_id, None, None, Some(1))?;
+                let mut last_event_vec =
+                    self.get_events(conn, &bucket_id, None, None, Some(1), None)?;
                 match last_event_vec.pop() {
                     Some(last_event) => last_event,
                     None => {
                         // There was no last event, insert and return
-                        self.insert_events(conn, &bucket_id, vec![heartbeat.clone()])?;
-                        return Ok(heartbeat);
+                        let mut inserted =
+                            self.insert_events(conn, &bucket_id, vec![heartbeat.clone()])?;
+                        last_heartbeat.insert(bucket_id.to_string(), inserted.pop());
+                        return Ok((heartbeat, false));
                     }
                 }
             }
         };
-        let inserted_heartbeat = match aw_transform::heartbeat(&last_event, &heartbeat, pulsetime) {
-            Some(merged_heartbeat) => {
-                self.replace_last_event(conn, &bucket_id, &merged_heartbeat)?;
-                merged_heartbeat
-            }
-            None => {
-                debug!("Failed to merge heartbeat!");
-                self.insert_events(conn, &bucket_id, vec![heartbeat.clone()])?;
-                heartbeat
-            }
+        let (stored_heartbeat, merged) =
+            match aw_transform::heartbeat(&last_event, &heartbeat, pulsetime) {
+                Some(mut merged_heartbeat) => {
+                    merged_heartbeat.id = last_event.id;
+                    self.replace_last_event(conn, &bucket_id, &merged_heartbeat)?;
+                    (merged_heartbeat, true)
+                }
+                None => {
+                    debug!("Failed to merge heartbeat!");
+                    let mut inserted =
+                        self.insert_events(conn, &bucket_id, vec![heartbeat.clone()])?;
+                    (inserted.pop().unwrap(), false)
+                }
+            };
+        let inserted_heartbeat = Event {
+            id: None,
+            ..stored_heartbeat.clone()
         };
-        last_heartbeat.insert(bucket_id.to_string(), Some(inserted_heartbeat.clone()));
-        Ok(inserted_heartbeat)
+        last_heartbeat.insert(bucket_id.to_string(), Some(stored_heartbeat));
+        Ok((inserted_heartbeat, merged))
     }
 
     pub fn get_event(
-        &mut self,
+        &self,
         conn: &Connection,
         bucket_id: &str,
         event_id: i64,
@@ -689,28 +969,7 @@ impl DatastoreInstance {
         };
 
         // TODO: Refactor to share row-parsing logic with get_events
-        let row = match stmt.query_row(&[&bucket.bid.unwrap(), &event_id], |row| {
-            let id = row.get(0)?;
-            let starttime_ns: i64 = row.get(1)?;
-            let endtime_ns: i64 = row.get(2)?;
-            let data_str: String = row.get(3)?;
-
-            let time_seconds: i64 = (starttime_ns / 1_000_000_000) as i64;
-            let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
-            let duration_ns = endtime_ns - starttime_ns;
-            let data: serde_json::map::Map<String, Value> =
-                serde_json::from_str(&data_str).unwrap();
-
-            Ok(Event {
-                id: Some(id),
-                timestamp: DateTime::<Utc>::from_utc(
-                    NaiveDateTime::from_timestamp(time_seconds, time_subnanos),
-                    Utc,
-                ),
-                duration: Duration::nanoseconds(duration_ns),
-                data,
-            })
-        }) {
+        let row = match stmt.query_row(&[&bucket.bid.unwrap(), &event_id], _event_from_row) {
             Ok(rows) => rows,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
@@ -724,12 +983,37 @@ impl DatastoreInstance {
     }
 
     pub fn get_events(
-        &mut self,
+        &self,
         conn: &Connection,
         bucket_id: &str,
         starttime_opt: Option<DateTime<Utc>>,
         endtime_opt: Option<DateTime<Utc>>,
         limit_opt: Option<u64>,
+        filter_opt: Option<&EventFilter>,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        self.get_events_with_pending(
+            conn,
+            bucket_id,
+            starttime_opt,
+            endtime_opt,
+            limit_opt,
+            filter_opt,
+            &PendingEvents::new(),
+        )
+    }
+
+    /// Like get_events, but with the pending changes to the events of the bucket applied to the
+    /// events read from the connection, for connections which can't see those changes yet
+    #[allow(clippy::too_many_arguments)]
+    pub fn get_events_with_pending(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        filter_opt: Option<&EventFilter>,
+        pending: &PendingEvents,
     ) -> Result<Vec<Event>, DatastoreError> {
         let bucket = self.get_bucket(&bucket_id)?;
 
@@ -751,18 +1035,45 @@ impl DatastoreInstance {
             Some(l) => l as i64,
             None => -1,
         };
+        // The SQL condition of a filter can match more events than the filter, so the limit is
+        // applied once the events have been checked against the filter. The rows are read lazily,
+        // so no more rows than needed are read anyway.
+        let sql_limit = match filter_opt {
+            Some(_) => -1,
+            None => limit,
+        };
 
-        let mut stmt = match conn.prepare(
+        let mut params = vec![
+            SqlValue::Integer(bucket.bid.unwrap()),
+            SqlValue::Integer(starttime_filter_ns),
+            SqlValue::Integer(endtime_filter_ns),
+            SqlValue::Integer(sql_limit),
+        ];
+        let (pending_ids_sql, mut pending_params) = _pending_ids_sql(pending, params.len() + 1);
+        params.append(&mut pending_params);
+        let data_filter_sql = match filter_opt {
+            Some(filter) => {
+                let (sql, mut filter_params) = filter.to_sql(params.len() + 1);
+                params.append(&mut filter_params);
+                sql
+            }
+            None => "1".to_string(),
+        };
+
+        let mut stmt = match conn.prepare(&format!(
             "
                 SELECT id, starttime, endtime, data
                 FROM events
                 WHERE bucketrow = ?1
                     AND endtime >= ?2
                     AND starttime <= ?3
+                    AND {}
+                    AND {}
                 ORDER BY starttime DESC
                 LIMIT ?4
             ;",
-        ) {
+            pending_ids_sql, data_filter_sql
+        )) {
             Ok(stmt) => stmt,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
@@ -772,51 +1083,159 @@ impl DatastoreInstance {
             }
         };
 
-        let rows = match stmt.query_map(
-            &[
-                &bucket.bid.unwrap(),
-                &starttime_filter_ns,
-                &endtime_filter_ns,
-                &limit,
-            ],
-            |row| {
-                let id = row.get(0)?;
-                let mut starttime_ns: i64 = row.get(1)?;
-                let mut endtime_ns: i64 = row.get(2)?;
-                let data_str: String = row.get(3)?;
-
-                if starttime_ns < starttime_filter_ns {
-                    starttime_ns = starttime_filter_ns
-                }
-                if endtime_ns > endtime_filter_ns {
-                    endtime_ns = endtime_filter_ns
-                }
-                let duration_ns = endtime_ns - starttime_ns;
+        let rows = match stmt.query_map(rusqlite::params_from_iter(params.iter()), |row| {
+            let id = row.get(0)?;
+            let mut starttime_ns: i64 = row.get(1)?;
+            let mut endtime_ns: i64 = row.get(2)?;
+            let data_str: String = row.get(3)?;
 
-                let time_seconds: i64 = (starttime_ns / 1_000_000_000) as i64;
-                let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
-                let data: serde_json::map::Map<String, Value> =
-                    serde_json::from_str(&data_str).unwrap();
+            if starttime_ns < starttime_filter_ns {
+                starttime_ns = starttime_filter_ns
+            }
+            if endtime_ns > endtime_filter_ns {
+                endtime_ns = endtime_filter_ns
+            }
+            let duration_ns = endtime_ns - starttime_ns;
+
+            let time_seconds: i64 = (starttime_ns / 1_000_000_000) as i64;
+            let time_subnanos: u32 = (starttime_ns % 1_000_000_000) as u32;
+            let data: serde_json::map::Map<String, Value> =
+                serde_json::from_str(&data_str).unwrap();
+
+            Ok(Event {
+                id: Some(id),
+                timestamp: DateTime::<Utc>::from_utc(
+                    NaiveDateTime::from_timestamp(time_seconds, time_subnanos),
+                    Utc,
+                ),
+                duration: Duration::nanoseconds(duration_ns),
+                data,
+            })
+        }) {
+            Ok(rows) => rows,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to map get_events SQL statement: {}",
+                    err
+                )))
+            }
+        };
+        for row in rows {
+            if limit >= 0 && list.len() as i64 >= limit {
+                break;
+            }
+            match row {
+                Ok(event) => match filter_opt {
+                    // Not all of the filter might be possible to check in SQL
+                    Some(filter) if !filter.matches(&event) => (),
+                    _ => list.push(event),
+                },
+                Err(err) => warn!("Corrupt event in bucket {}: {}", bucket_id, err),
+            };
+        }
 
-                Ok(Event {
-                    id: Some(id),
+        if !pending.is_empty() {
+            for event in pending.values().flatten() {
+                let (mut starttime_ns, mut endtime_ns) = _event_range_ns(event);
+                if endtime_ns < starttime_filter_ns || starttime_ns > endtime_filter_ns {
+                    continue;
+                }
+                if let Some(filter) = filter_opt {
+                    if !filter.matches(event) {
+                        continue;
+                    }
+                }
+                // Cut off at the time range the same way as the events read from the table
+                starttime_ns = starttime_ns.max(starttime_filter_ns);
+                endtime_ns = endtime_ns.min(endtime_filter_ns);
+                list.push(Event {
+                    id: event.id,
                     timestamp: DateTime::<Utc>::from_utc(
-                        NaiveDateTime::from_timestamp(time_seconds, time_subnanos),
+                        NaiveDateTime::from_timestamp(
+                            starttime_ns / 1_000_000_000,
+                            (starttime_ns % 1_000_000_000) as u32,
+                        ),
                         Utc,
                     ),
-                    duration: Duration::nanoseconds(duration_ns),
-                    data,
-                })
-            },
-        ) {
+                    duration: Duration::nanoseconds(endtime_ns - starttime_ns),
+                    data: event.data.clone(),
+                });
+            }
+            list.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+            if limit >= 0 {
+                list.truncate(limit as usize);
+            }
+        }
+
+        Ok(list)
+    }
+
+    /// Get up to `limit` events with an id larger than `after_id`, ordered by id.
+    ///
+    /// Unlike get_events the events are never cut off at a time range, which makes it useful
+    /// for iterating over all events in a bucket in chunks.
+    pub fn get_events_after(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        after_id: Option<i64>,
+        limit: u64,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        self.get_events_after_with_pending(conn, bucket_id, after_id, limit, &PendingEvents::new())
+    }
+
+    /// Like get_events_after, but with the pending changes to the events of the bucket applied
+    pub fn get_events_after_with_pending(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        after_id: Option<i64>,
+        limit: u64,
+        pending: &PendingEvents,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        let bucket = self.get_bucket(&bucket_id)?;
+
+        let (pending_ids_sql, pending_params) = _pending_ids_sql(pending, 4);
+        let mut stmt = match conn.prepare(&format!(
+            "
+                SELECT id, starttime, endtime, data
+                FROM events
+                WHERE bucketrow = ?1
+                    AND id > ?2
+                    AND {}
+                ORDER BY id ASC
+                LIMIT ?3
+            ;",
+            pending_ids_sql
+        )) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to prepare get_events_after SQL statement: {}",
+                    err
+                )))
+            }
+        };
+
+        let after_id = after_id.unwrap_or(-1);
+        let limit = limit as i64;
+        let mut params = vec![
+            SqlValue::Integer(bucket.bid.unwrap()),
+            SqlValue::Integer(after_id),
+            SqlValue::Integer(limit),
+        ];
+        params.extend(pending_params);
+        let rows = match stmt.query_map(rusqlite::params_from_iter(params.iter()), _event_from_row)
+        {
             Ok(rows) => rows,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
-                    "Failed to map get_events SQL statement: {}",
+                    "Failed to map get_events_after SQL statement: {}",
                     err
                 )))
             }
         };
+        let mut list = Vec::new();
         for row in rows {
             match row {
                 Ok(event) => list.push(event),
@@ -824,6 +1243,18 @@ impl DatastoreInstance {
             };
         }
 
+        if !pending.is_empty() {
+            list.extend(
+                pending
+                    .values()
+                    .flatten()
+                    .filter(|event| event.id > Some(after_id))
+                    .cloned(),
+            );
+            list.sort_by_key(|event| event.id);
+            list.truncate(limit as usize);
+        }
+
         Ok(list)
     }
 
@@ -833,6 +1264,24 @@ impl DatastoreInstance {
         bucket_id: &str,
         starttime_opt: Option<DateTime<Utc>>,
         endtime_opt: Option<DateTime<Utc>>,
+    ) -> Result<i64, DatastoreError> {
+        self.get_event_count_with_pending(
+            conn,
+            bucket_id,
+            starttime_opt,
+            endtime_opt,
+            &PendingEvents::new(),
+        )
+    }
+
+    /// Like get_event_count, but with the pending changes to the events of the bucket applied
+    pub fn get_event_count_with_pending(
+        &self,
+        conn: &Connection,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        pending: &PendingEvents,
     ) -> Result<i64, DatastoreError> {
         let bucket = self.get_bucket(&bucket_id)?;
 
@@ -849,13 +1298,16 @@ impl DatastoreInstance {
             return Ok(0);
         }
 
-        let mut stmt = match conn.prepare(
+        let (pending_ids_sql, pending_params) = _pending_ids_sql(pending, 4);
+        let mut stmt = match conn.prepare(&format!(
             "
             SELECT count(*) FROM events
             WHERE bucketrow = ?1
                 AND endtime >= ?2
-                AND starttime <= ?3",
-        ) {
+                AND starttime <= ?3
+                AND {}",
+            pending_ids_sql
+        )) {
             Ok(stmt) => stmt,
             Err(err) => {
                 return Err(DatastoreError::InternalError(format!(
@@ -865,24 +1317,32 @@ impl DatastoreInstance {
             }
         };
 
-        let count = match stmt.query_row(
-            &[
-                &bucket.bid.unwrap(),
-                &starttime_filter_ns,
-                &endtime_filter_ns,
-            ],
-            |row| row.get(0),
-        ) {
-            Ok(count) => count,
-            Err(err) => {
-                return Err(DatastoreError::InternalError(format!(
-                    "Failed to query get_event_count SQL statement: {}",
-                    err
-                )))
-            }
-        };
+        let mut params = vec![
+            SqlValue::Integer(bucket.bid.unwrap()),
+            SqlValue::Integer(starttime_filter_ns),
+            SqlValue::Integer(endtime_filter_ns),
+        ];
+        params.extend(pending_params);
+        let count: i64 =
+            match stmt.query_row(rusqlite::params_from_iter(params.iter()), |row| row.get(0)) {
+                Ok(count) => count,
+                Err(err) => {
+                    return Err(DatastoreError::InternalError(format!(
+                        "Failed to query get_event_count SQL statement: {}",
+                        err
+                    )))
+                }
+            };
+        let pending_count = pending
+            .values()
+            .flatten()
+            .filter(|event| {
+                let (starttime_ns, endtime_ns) = _event_range_ns(event);
+                endtime_ns >= starttime_filter_ns && starttime_ns <= endtime_filter_ns
+            })
+            .count();
 
-        Ok(count)
+        Ok(count + pending_count as i64)
     }
 
     pub fn insert_key_value(
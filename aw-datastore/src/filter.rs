@@ -0,0 +1,96 @@
+use rusqlite::types::Value as SqlValue;
+use serde_json::Value;
+
+use aw_models::Event;
+
+/// Conditions on the data of events, so that only the matching events are read from a bucket
+///
+/// As much of the filter as possible is evaluated by SQLite, so that events which don't match
+/// are never loaded.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    /// Only keep events where data[key] is equal to one of the values, for every key
+    pub keyvals: Vec<(String, Vec<Value>)>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        self.keyvals
+            .iter()
+            .all(|(key, vals)| match event.data.get(key) {
+                Some(val) => vals.contains(val),
+                None => false,
+            })
+    }
+
+    /// SQL condition on the data column and its parameters, numbered from first_param
+    ///
+    /// Events matching the filter always match the condition, but values which SQLite can't
+    /// compare exactly (such as objects and lists) are left out so more events might match it.
+    /// The events still have to be checked with matches, before any limit is applied.
+    pub(crate) fn to_sql(&self, first_param: usize) -> (String, Vec<SqlValue>) {
+        let mut sql = "json_valid(data)".to_string();
+        let mut params = Vec::new();
+        for (key, vals) in self.keyvals.iter() {
+            // Keys with quotes can't be quoted in a JSON path
+            if key.contains('"') {
+                continue;
+            }
+            let path_param = first_param + params.len();
+            let mut conditions = Vec::new();
+            let mut comparable = true;
+            let mut vals_params = Vec::new();
+            for val in vals {
+                let type_check = format!("json_type(data, ?{})", path_param);
+                let condition = match val {
+                    Value::Null => format!("{} = 'null'", type_check),
+                    Value::Bool(true) => format!("{} = 'true'", type_check),
+                    Value::Bool(false) => format!("{} = 'false'", type_check),
+                    // Floats might be parsed slightly differently by SQLite, so only their
+                    // type is compared
+                    Value::Number(n) if n.is_f64() => format!("{} = 'real'", type_check),
+                    Value::Number(n) => match n.as_i64() {
+                        Some(i) => {
+                            vals_params.push(SqlValue::Integer(i));
+                            format!(
+                                "({} = 'integer' AND json_extract(data, ?{}) = ?{})",
+                                type_check,
+                                path_param,
+                                path_param + vals_params.len()
+                            )
+                        }
+                        None => {
+                            comparable = false;
+                            break;
+                        }
+                    },
+                    Value::String(s) => {
+                        vals_params.push(SqlValue::Text(s.clone()));
+                        format!(
+                            "({} = 'text' AND json_extract(data, ?{}) = ?{})",
+                            type_check,
+                            path_param,
+                            path_param + vals_params.len()
+                        )
+                    }
+                    Value::Array(_) | Value::Object(_) => {
+                        comparable = false;
+                        break;
+                    }
+                };
+                conditions.push(condition);
+            }
+            if !comparable {
+                continue;
+            }
+            if conditions.is_empty() {
+                sql.push_str(" AND 0");
+                continue;
+            }
+            params.push(SqlValue::Text(format!("$.\"{}\"", key)));
+            params.append(&mut vals_params);
+            sql.push_str(&format!(" AND ({})", conditions.join(" OR ")));
+        }
+        (sql, params)
+    }
+}
@@ -15,11 +15,24 @@ macro_rules! json_map {
     }};
 }
 
+mod changes;
 mod datastore;
+mod filter;
 mod legacy_import;
+mod ndjson;
+mod notifications;
+mod reader;
 mod worker;
 
+pub use self::changes::BucketChange;
 pub use self::datastore::DatastoreInstance;
+pub use self::filter::EventFilter;
+pub use self::ndjson::export_ndjson;
+pub use self::ndjson::import_ndjson;
+pub use self::ndjson::NdjsonExport;
+pub use self::ndjson::NdjsonImport;
+pub use self::ndjson::NdjsonLine;
+pub use self::notifications::EventNotification;
 pub use self::worker::Datastore;
 
 #[derive(Debug, Clone)]
@@ -36,6 +49,8 @@ pub enum DatastoreError {
     NoSuchKey(String),
     MpscError,
     InternalError(String),
+    // Data provided to the datastore could not be parsed, such as a malformed import
+    InvalidData(String),
     // Errors specific to when migrate is disabled
     Uninitialized(String),
     OldDbVersion(String),
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use aw_models::Bucket;
+use aw_models::BucketImportResult;
+use aw_models::BucketsExport;
+use aw_models::Event;
+use aw_models::ImportConflictStrategy;
+use aw_models::ImportSummary;
+use aw_models::TryVec;
+
+use crate::Datastore;
+use crate::DatastoreError;
+
+/* Number of events to read from or write to the datastore at a time */
+static CHUNK_SIZE: usize = 1000;
+
+/*
+ * Newline delimited JSON (NDJSON) import/export format
+ *
+ * Every bucket starts with a header line containing the bucket (without events), followed by
+ * one line for each of the events in the bucket:
+ *
+ * {"bucket":{"id":"aw-watcher-window_host","type":"currentwindow",...}}
+ * {"event":{"id":1,"timestamp":"2020-01-01T00:00:00Z","duration":1.0,"data":{...}}}
+ * {"event":{"id":2,"timestamp":"2020-01-01T00:00:01Z","duration":1.0,"data":{...}}}
+ *
+ * Unlike the BucketsExport JSON format it can be written and read without having all the events
+ * in memory at once.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum NdjsonLine {
+    Bucket(Bucket),
+    Event(Event),
+}
+
+fn to_line(line: &NdjsonLine) -> String {
+    let mut line = serde_json::to_string(line).unwrap();
+    line.push('\n');
+    line
+}
+
+/// Iterator over chunks of NDJSON lines of an export of the given buckets, each chunk consists
+/// of one or more complete lines
+pub struct NdjsonExport {
+    ds: Datastore,
+    bucket_ids: VecDeque<String>,
+    // Bucket currently being exported and the id of the last exported event in it
+    current: Option<(String, Option<i64>)>,
+}
+
+impl NdjsonExport {
+    pub fn new(ds: Datastore, bucket_ids: Vec<String>) -> Self {
+        NdjsonExport {
+            ds,
+            bucket_ids: bucket_ids.into(),
+            current: None,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<String>, DatastoreError> {
+        let (bucket_id, after_id) = match self.current.take() {
+            Some(current) => current,
+            None => match self.bucket_ids.pop_front() {
+                Some(bucket_id) => {
+                    let mut bucket = self.ds.get_bucket(&bucket_id)?;
+                    bucket.events = None;
+                    self.current = Some((bucket_id, None));
+                    return Ok(Some(to_line(&NdjsonLine::Bucket(bucket))));
+                }
+                None => return Ok(None),
+            },
+        };
+        let events = self
+            .ds
+            .get_events_after(&bucket_id, after_id, CHUNK_SIZE as u64)?;
+        if events.is_empty() {
+            // Continue with the next bucket
+            return self.next_chunk();
+        }
+        let last_id = events.last().unwrap().id;
+        let mut chunk = String::new();
+        for event in events {
+            chunk.push_str(&to_line(&NdjsonLine::Event(event)));
+        }
+        self.current = Some((bucket_id, last_id));
+        Ok(Some(chunk))
+    }
+}
+
+impl Iterator for NdjsonExport {
+    type Item = Result<String, DatastoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_chunk() {
+            Ok(Some(chunk)) => Some(Ok(chunk)),
+            Ok(None) => None,
+            Err(err) => {
+                // Don't continue after an error
+                self.current = None;
+                self.bucket_ids.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Writes a NDJSON export of the given buckets
+pub fn export_ndjson<W: Write>(
+    ds: &Datastore,
+    bucket_ids: Vec<String>,
+    writer: &mut W,
+) -> Result<(), DatastoreError> {
+    for chunk in NdjsonExport::new(ds.clone(), bucket_ids) {
+        if let Err(err) = writer.write_all(chunk?.as_bytes()) {
+            return Err(DatastoreError::InternalError(format!(
+                "Failed to write NDJSON export: {}",
+                err
+            )));
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Imports NDJSON lines as they are pushed, inserting the events in chunks.
+ *
+ * Since the events are never all in memory at once, the import is not done in a single
+ * transaction like Datastore::import, so a failing import can leave buckets partially imported.
+ */
+pub struct NdjsonImport {
+    ds: Datastore,
+    strategy: ImportConflictStrategy,
+    // Bucket currently being imported
+    current: Option<NdjsonImportBucket>,
+    results: HashMap<String, BucketImportResult>,
+}
+
+struct NdjsonImportBucket {
+    // ID of the bucket in the import, the bucket itself has the ID it is imported as
+    exported_id: String,
+    bucket: Bucket,
+    // If events should be deduplicated against the existing events in the bucket
+    merge: bool,
+    // If all events of the bucket should be skipped
+    skip: bool,
+    events: Vec<Event>,
+    result: BucketImportResult,
+}
+
+impl NdjsonImport {
+    pub fn new(ds: Datastore, strategy: ImportConflictStrategy) -> Self {
+        NdjsonImport {
+            ds,
+            strategy,
+            current: None,
+            results: HashMap::new(),
+        }
+    }
+
+    /// Handles a single line of NDJSON, empty lines are ignored
+    pub fn push_line(&mut self, line: &str) -> Result<(), DatastoreError> {
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(err) => {
+                return Err(DatastoreError::InvalidData(format!(
+                    "Failed to parse NDJSON line: {}",
+                    err
+                )))
+            }
+        };
+        let is_event = value.get("event").is_some();
+        match serde_json::from_value::<NdjsonLine>(value) {
+            Ok(NdjsonLine::Bucket(bucket)) => {
+                self.finish_bucket()?;
+                self.start_bucket(bucket)
+            }
+            Ok(NdjsonLine::Event(event)) => match &mut self.current {
+                Some(current) => {
+                    current.events.push(event);
+                    if current.events.len() >= CHUNK_SIZE {
+                        self.flush_events()?;
+                    }
+                    Ok(())
+                }
+                None => Err(DatastoreError::InvalidData(
+                    "Event line found before any bucket line in NDJSON import".to_string(),
+                )),
+            },
+            Err(err) => {
+                // Skip events which fail to parse, like the TryVec in BucketsExport does
+                match &mut self.current {
+                    Some(current) if is_event => {
+                        warn!("Failed to parse event, the event will be skipped: {}", err);
+                        current.result.skipped += 1;
+                        Ok(())
+                    }
+                    _ => Err(DatastoreError::InvalidData(format!(
+                        "Failed to parse NDJSON line: {}",
+                        err
+                    ))),
+                }
+            }
+        }
+    }
+
+    fn start_bucket(&mut self, mut bucket: Bucket) -> Result<(), DatastoreError> {
+        bucket.events = None;
+        let exists = match self.ds.get_bucket(&bucket.id) {
+            Ok(_) => true,
+            Err(DatastoreError::NoSuchBucket(_)) => false,
+            Err(err) => return Err(err),
+        };
+        let merge = exists && self.strategy == ImportConflictStrategy::Merge;
+        let skip = exists && self.strategy == ImportConflictStrategy::Skip;
+        let mut imported_as = bucket.id.clone();
+        if !merge && !skip {
+            // Create the bucket without events according to the conflict strategy
+            let mut import = BucketsExport {
+                buckets: HashMap::new(),
+            };
+            import.buckets.insert(bucket.id.clone(), bucket.clone());
+            let summary = self.ds.import(import, self.strategy)?;
+            imported_as = summary.buckets[&bucket.id].imported_as.clone();
+        }
+        self.current = Some(NdjsonImportBucket {
+            exported_id: bucket.id.clone(),
+            bucket: Bucket {
+                id: imported_as.clone(),
+                ..bucket
+            },
+            merge,
+            skip,
+            events: Vec::new(),
+            result: BucketImportResult {
+                imported_as,
+                inserted: 0,
+                skipped: 0,
+            },
+        });
+        Ok(())
+    }
+
+    fn flush_events(&mut self) -> Result<(), DatastoreError> {
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let mut events: Vec<Event> = current.events.drain(..).collect();
+        if current.skip {
+            current.result.skipped += events.len();
+        } else if current.merge {
+            // Let the datastore deduplicate the events against the existing ones
+            let mut bucket = current.bucket.clone();
+            bucket.events = Some(TryVec::new(events));
+            let mut import = BucketsExport {
+                buckets: HashMap::new(),
+            };
+            import.buckets.insert(bucket.id.clone(), bucket);
+            let summary = self.ds.import(import, ImportConflictStrategy::Merge)?;
+            let result = &summary.buckets[&current.bucket.id];
+            current.result.inserted += result.inserted;
+            current.result.skipped += result.skipped;
+        } else {
+            for event in &mut events {
+                event.id = None;
+            }
+            let inserted = self.ds.insert_events(&current.bucket.id, &events)?;
+            current.result.inserted += inserted.len();
+        }
+        Ok(())
+    }
+
+    fn finish_bucket(&mut self) -> Result<(), DatastoreError> {
+        self.flush_events()?;
+        if let Some(current) = self.current.take() {
+            info!(
+                "Imported bucket {} with {} events ({} skipped)",
+                current.result.imported_as, current.result.inserted, current.result.skipped
+            );
+            self.results.insert(current.exported_id, current.result);
+        }
+        Ok(())
+    }
+
+    /// Inserts the remaining events and returns how many events were imported per bucket
+    pub fn finish(mut self) -> Result<ImportSummary, DatastoreError> {
+        self.finish_bucket()?;
+        Ok(ImportSummary {
+            buckets: self.results,
+        })
+    }
+}
+
+/// Imports buckets and events in the NDJSON format from a reader
+pub fn import_ndjson<R: BufRead>(
+    ds: &Datastore,
+    reader: R,
+    strategy: ImportConflictStrategy,
+) -> Result<ImportSummary, DatastoreError> {
+    let mut import = NdjsonImport::new(ds.clone(), strategy);
+    for line in reader.lines() {
+        match line {
+            Ok(line) => import.push_line(&line)?,
+            Err(err) => {
+                return Err(DatastoreError::InvalidData(format!(
+                    "Failed to read NDJSON import: {}",
+                    err
+                )))
+            }
+        }
+    }
+    import.finish()
+}
@@ -0,0 +1,90 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use aw_models::Event;
+
+/// A change to the events of a bucket, sent to subscribers as soon as the worker has committed it
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventNotification {
+    /// Events were inserted, events with an id replaced the existing event with that id
+    Inserted {
+        bucket_id: String,
+        events: Vec<Event>,
+    },
+    /// The last event of the bucket was replaced, as a heartbeat was merged into it
+    LastEventReplaced {
+        bucket_id: String,
+        event: Event,
+    },
+    /// Events with the ids were deleted
+    Deleted {
+        bucket_id: String,
+        event_ids: Vec<i64>,
+    },
+    BucketCreated {
+        bucket_id: String,
+    },
+    /// The bucket was deleted along with all of its events
+    BucketDeleted {
+        bucket_id: String,
+    },
+}
+
+impl EventNotification {
+    pub fn bucket_id(&self) -> &str {
+        match self {
+            EventNotification::Inserted { bucket_id, .. }
+            | EventNotification::LastEventReplaced { bucket_id, .. }
+            | EventNotification::Deleted { bucket_id, .. }
+            | EventNotification::BucketCreated { bucket_id }
+            | EventNotification::BucketDeleted { bucket_id } => bucket_id,
+        }
+    }
+
+    /// Name of the kind of notification, the same as its type when serialized
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EventNotification::Inserted { .. } => "inserted",
+            EventNotification::LastEventReplaced { .. } => "last_event_replaced",
+            EventNotification::Deleted { .. } => "deleted",
+            EventNotification::BucketCreated { .. } => "bucket_created",
+            EventNotification::BucketDeleted { .. } => "bucket_deleted",
+        }
+    }
+}
+
+pub type Subscriber = Box<dyn Fn(&EventNotification) + Send>;
+
+/// Subscribers to the notifications, shared between the Datastore and its worker
+#[derive(Clone, Default)]
+pub struct Subscribers {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Subscribers {
+    pub fn add(&self, subscriber: Subscriber) {
+        self.subscribers.lock().unwrap().push(subscriber);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.lock().unwrap().is_empty()
+    }
+
+    /// Calls all subscribers with the notification, which is only created if there are any
+    pub fn notify(&self, notification: impl FnOnce() -> EventNotification) {
+        let subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let notification = notification();
+        for subscriber in subscribers.iter() {
+            subscriber(&notification);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
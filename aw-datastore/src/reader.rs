@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+
+use aw_models::Bucket;
+use aw_models::Event;
+
+use crate::DatastoreError;
+use crate::DatastoreInstance;
+use crate::EventFilter;
+
+/* Max number of idle read-only connections to keep around */
+static MAX_IDLE_CONNECTIONS: usize = 4;
+
+/* Max number of pending events of a bucket to merge into reads, reads of buckets with more
+ * pending events go through the worker */
+static MAX_PENDING_EVENTS: usize = 100;
+
+/// Events changed in the open transaction of the worker by their id, None if the event was deleted
+pub type PendingEvents = HashMap<i64, Option<Event>>;
+
+/*
+ * The changes made by the worker which are not yet committed, and so can't be seen by the
+ * read-only connections.
+ */
+#[derive(Default)]
+pub struct PendingChanges {
+    // Incremented right before every commit
+    generation: u64,
+    // Buckets which were created, deleted or imported, which can only be read through the worker
+    buckets: HashSet<String>,
+    events: HashMap<String, PendingEvents>,
+}
+
+impl PendingChanges {
+    pub fn bucket_changed(&mut self, bucket_id: &str) {
+        self.events.remove(bucket_id);
+        self.buckets.insert(bucket_id.to_string());
+    }
+
+    /// The events have to have the ids they are stored with
+    pub fn events_changed(&mut self, bucket_id: &str, events: &[Event]) {
+        if events.iter().any(|e| e.id.is_none()) {
+            self.bucket_changed(bucket_id);
+            return;
+        }
+        self.add_events(
+            bucket_id,
+            events.iter().map(|e| (e.id.unwrap(), Some(e.clone()))),
+        );
+    }
+
+    pub fn events_deleted(&mut self, bucket_id: &str, event_ids: &[i64]) {
+        self.add_events(bucket_id, event_ids.iter().map(|id| (*id, None)));
+    }
+
+    fn add_events(&mut self, bucket_id: &str, events: impl Iterator<Item = (i64, Option<Event>)>) {
+        if self.buckets.contains(bucket_id) {
+            return;
+        }
+        let pending = self.events.entry(bucket_id.to_string()).or_default();
+        pending.extend(events);
+        if pending.len() > MAX_PENDING_EVENTS {
+            self.bucket_changed(bucket_id);
+        }
+    }
+
+    /// Has to be called before committing, so that reads which could have seen the commit can
+    /// tell that the pending changes they got might be out of date
+    pub fn start_commit(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Called once committed, when the changes can be seen by the read-only connections
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.events.clear();
+    }
+}
+
+/*
+ * Serves read requests from a pool of read-only SQLite connections so that they don't have to
+ * queue up behind the writes on the worker thread.
+ *
+ * The database is in WAL mode which allows readers to run concurrently with the writer, but
+ * readers only see what the worker has committed. The changes to events which the worker has
+ * not yet committed are merged into what is read, so reads of a bucket which a watcher is
+ * sending heartbeats to don't have to wait for the worker either. Only reads of buckets which
+ * were created, deleted or imported since the last commit go through the worker.
+ */
+pub struct DatastoreReader {
+    path: String,
+    ds: DatastoreInstance,
+    pending: Arc<Mutex<PendingChanges>>,
+    idle_connections: Mutex<Vec<Connection>>,
+}
+
+impl DatastoreReader {
+    pub fn new(path: String, ds: DatastoreInstance, pending: Arc<Mutex<PendingChanges>>) -> Self {
+        DatastoreReader {
+            path,
+            ds,
+            pending,
+            idle_connections: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn open_connection(&self) -> Result<Connection, DatastoreError> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = match Connection::open_with_flags(&self.path, flags) {
+            Ok(conn) => conn,
+            Err(err) => {
+                return Err(DatastoreError::InternalError(format!(
+                    "Failed to open read-only connection to datastore: {}",
+                    err
+                )))
+            }
+        };
+        // Only happens if the database is not in WAL mode, but better safe than sorry
+        if let Err(err) = conn.busy_timeout(std::time::Duration::from_secs(5)) {
+            warn!(
+                "Failed to set busy timeout on read-only connection: {}",
+                err
+            );
+        }
+        Ok(conn)
+    }
+
+    fn with_connection<T, F>(&self, f: F) -> Result<T, DatastoreError>
+    where
+        F: FnOnce(&Connection) -> Result<T, DatastoreError>,
+    {
+        let idle_conn = self.idle_connections.lock().unwrap().pop();
+        let conn = match idle_conn {
+            Some(conn) => conn,
+            None => self.open_connection()?,
+        };
+        let res = f(&conn);
+        let mut idle_connections = self.idle_connections.lock().unwrap();
+        if idle_connections.len() < MAX_IDLE_CONNECTIONS {
+            idle_connections.push(conn);
+        }
+        res
+    }
+
+    /// Runs the read with the pending events of the bucket, None if the read has to go through
+    /// the worker instead
+    fn with_pending<T, F>(&self, bucket_id: &str, f: F) -> Option<Result<T, DatastoreError>>
+    where
+        F: FnOnce(&Connection, &PendingEvents) -> Result<T, DatastoreError>,
+    {
+        let (generation, pending_events) = {
+            let pending = self.pending.lock().unwrap();
+            if pending.buckets.contains(bucket_id) {
+                return None;
+            }
+            let pending_events = pending.events.get(bucket_id).cloned();
+            (pending.generation, pending_events.unwrap_or_default())
+        };
+        let res = self.with_connection(|conn| f(conn, &pending_events));
+        // If a commit was started during the read, the read might have seen changes which
+        // weren't pending yet when we got the pending events, so the result can't be trusted
+        if self.pending.lock().unwrap().generation != generation {
+            return None;
+        }
+        Some(res)
+    }
+
+    pub fn get_buckets(&self) -> HashMap<String, Bucket> {
+        self.ds.get_buckets()
+    }
+
+    pub fn get_events(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        filter_opt: Option<&EventFilter>,
+    ) -> Option<Result<Vec<Event>, DatastoreError>> {
+        self.with_pending(bucket_id, |conn, pending| {
+            self.ds.get_events_with_pending(
+                conn,
+                bucket_id,
+                starttime_opt,
+                endtime_opt,
+                limit_opt,
+                filter_opt,
+                pending,
+            )
+        })
+    }
+
+    pub fn get_events_after(
+        &self,
+        bucket_id: &str,
+        after_id: Option<i64>,
+        limit: u64,
+    ) -> Option<Result<Vec<Event>, DatastoreError>> {
+        self.with_pending(bucket_id, |conn, pending| {
+            self.ds
+                .get_events_after_with_pending(conn, bucket_id, after_id, limit, pending)
+        })
+    }
+
+    pub fn get_event_count(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+    ) -> Option<Result<i64, DatastoreError>> {
+        self.with_pending(bucket_id, |conn, pending| {
+            self.ds.get_event_count_with_pending(
+                conn,
+                bucket_id,
+                starttime_opt,
+                endtime_opt,
+                pending,
+            )
+        })
+    }
+}
@@ -1,5 +1,8 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
 
 use chrono::DateTime;
@@ -12,21 +15,37 @@ use rusqlite::Transaction;
 use rusqlite::TransactionBehavior;
 
 use aw_models::Bucket;
+use aw_models::BucketsExport;
 use aw_models::Event;
+use aw_models::ImportConflictStrategy;
+use aw_models::ImportSummary;
 use aw_models::KeyValue;
 
+use crate::changes::BucketChange;
+use crate::changes::ChangeLog;
+use crate::notifications::{EventNotification, Subscribers};
+use crate::reader::DatastoreReader;
+use crate::reader::PendingChanges;
 use crate::DatastoreError;
 use crate::DatastoreInstance;
 use crate::DatastoreMethod;
+use crate::EventFilter;
 
 use mpsc_requests::ResponseReceiver;
+use mpsc_requests::ResponseSender;
 
 type RequestSender = mpsc_requests::RequestSender<Command, Result<Response, DatastoreError>>;
 type RequestReceiver = mpsc_requests::RequestReceiver<Command, Result<Response, DatastoreError>>;
+type Responder = ResponseSender<Result<Response, DatastoreError>>;
 
 #[derive(Clone)]
 pub struct Datastore {
     requester: RequestSender,
+    // Only available for file-backed datastores, in-memory datastores can't be shared between
+    // connections so all their reads go through the worker
+    reader: Option<Arc<DatastoreReader>>,
+    changes: Arc<Mutex<ChangeLog>>,
+    subscribers: Subscribers,
 }
 
 impl fmt::Debug for Datastore {
@@ -35,13 +54,6 @@ impl fmt::Debug for Datastore {
     }
 }
 
-/*
- * TODO:
- * - Allow read requests to go straight through a read-only db connection instead of requesting the
- * worker thread for better performance?
- * TODO: Add an separate "Import" request which does an import with an transaction
- */
-
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum Response {
@@ -53,6 +65,7 @@ pub enum Response {
     Count(i64),
     KeyValue(KeyValue),
     StringVec(Vec<String>),
+    ImportSummary(ImportSummary),
     // Used to indicate that no response should occur at all (not even an empty one)
     NoResponse(),
 }
@@ -72,7 +85,9 @@ pub enum Command {
         Option<DateTime<Utc>>,
         Option<DateTime<Utc>>,
         Option<u64>,
+        Option<EventFilter>,
     ),
+    GetEventsAfter(String, Option<i64>, u64),
     GetEventCount(String, Option<DateTime<Utc>>, Option<DateTime<Utc>>),
     DeleteEventsById(String, Vec<i64>),
     ForceCommit(),
@@ -80,6 +95,7 @@ pub enum Command {
     GetKeyValue(String),
     GetKeysStarting(String),
     DeleteKeyValue(String),
+    Import(BucketsExport, ImportConflictStrategy),
     Close(),
 }
 
@@ -101,13 +117,29 @@ struct DatastoreWorker {
     quit: bool,
     uncommitted_events: usize,
     commit: bool,
+    // Shared with the DatastoreReader, the changes which are not yet committed
+    pending: Arc<Mutex<PendingChanges>>,
     last_heartbeat: HashMap<String, Option<Event>>,
+    changes: Arc<Mutex<ChangeLog>>,
+    subscribers: Subscribers,
+    // Notifications of the changes in the open transaction, sent once it has been committed
+    notifications: Vec<EventNotification>,
+}
+
+/// The time range covered by the events, None if there are no events
+fn events_range(events: &[Event]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let start = events.iter().map(|e| e.timestamp).min()?;
+    let end = events.iter().map(|e| e.calculate_endtime()).max()?;
+    Some((start, end))
 }
 
 impl DatastoreWorker {
     pub fn new(
         responder: mpsc_requests::RequestReceiver<Command, Result<Response, DatastoreError>>,
         legacy_import: bool,
+        pending: Arc<Mutex<PendingChanges>>,
+        changes: Arc<Mutex<ChangeLog>>,
+        subscribers: Subscribers,
     ) -> Self {
         DatastoreWorker {
             responder,
@@ -115,21 +147,82 @@ impl DatastoreWorker {
             quit: false,
             uncommitted_events: 0,
             commit: false,
+            pending,
             last_heartbeat: HashMap::new(),
+            changes,
+            subscribers,
+            notifications: Vec::new(),
+        }
+    }
+
+    fn record_change(&self, bucket_id: &str, range: Option<(DateTime<Utc>, DateTime<Utc>)>) {
+        self.changes.lock().unwrap().push(bucket_id, range);
+    }
+
+    /// Queues the notification, which is only created if there are any subscribers
+    ///
+    /// Subscribers shouldn't learn of changes which aren't committed yet, so the notification is
+    /// sent with the next commit. That doesn't force a commit, as that would defeat the batching
+    /// of commits while a watcher is sending heartbeats.
+    fn notify(&mut self, notification: impl FnOnce() -> EventNotification) {
+        if self.subscribers.is_empty() {
+            return;
         }
+        self.notifications.push(notification());
+    }
+
+    /// Reads of the bucket go through the worker until the next commit, as the read-only
+    /// connections can't see the change yet
+    ///
+    /// Has to be called before the change is recorded, otherwise a query could see the new change
+    /// sequence number while still reading the old data from a read-only connection, and the
+    /// query cache would keep that stale result as up to date. The same goes for the other
+    /// pending changes.
+    fn mark_uncommitted(&self, bucket_id: &str) {
+        self.pending.lock().unwrap().bucket_changed(bucket_id);
     }
 
-    fn work_loop(&mut self, method: DatastoreMethod) {
+    /// Reads of the bucket from the read-only connections get the events merged in until the
+    /// next commit
+    fn mark_uncommitted_events(&self, bucket_id: &str, events: &[Event]) {
+        self.pending
+            .lock()
+            .unwrap()
+            .events_changed(bucket_id, events);
+    }
+
+    fn mark_deleted_events(&self, bucket_id: &str, event_ids: &[i64]) {
+        self.pending
+            .lock()
+            .unwrap()
+            .events_deleted(bucket_id, event_ids);
+    }
+
+    fn work_loop(
+        &mut self,
+        method: DatastoreMethod,
+        reader_sender: mpsc::Sender<DatastoreInstance>,
+    ) {
         // Open SQLite connection
         let mut conn = match &method {
             DatastoreMethod::Memory() => {
                 Connection::open_in_memory().expect("Failed to create in-memory datastore")
             }
             DatastoreMethod::File(path) => {
-                Connection::open(path).expect("Failed to create datastore")
+                let conn = Connection::open(path).expect("Failed to create datastore");
+                // WAL mode lets the read-only connections read while we are writing
+                match conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| {
+                    row.get::<_, String>(0)
+                }) {
+                    Ok(mode) => debug!("Using journal mode {}", mode),
+                    Err(err) => warn!("Failed to enable WAL journal mode: {}", err),
+                }
+                conn
             }
         };
         let mut ds = DatastoreInstance::new(&conn, true).unwrap();
+        // The requester might already be gone, in which case there is noone to read anyway
+        let _ = reader_sender.send(ds.reader_instance());
 
         // Ensure legacy import
         if self.legacy_import {
@@ -167,6 +260,9 @@ impl DatastoreWorker {
 
             self.uncommitted_events = 0;
             self.commit = false;
+            // Responses to ForceCommit requests are held back until the commit is done
+            let mut commit_responses: Vec<(Responder, Result<Response, DatastoreError>)> =
+                Vec::new();
             loop {
                 let (request, response_sender) = match self.responder.poll() {
                     Ok((req, res_sender)) => (req, res_sender),
@@ -177,11 +273,13 @@ impl DatastoreWorker {
                         break;
                     }
                 };
+                let wait_for_commit = matches!(request, Command::ForceCommit());
                 let response = self.handle_request(request, &mut ds, &tx);
                 match response {
                     // The NoResponse is used by commands like close(), which should
                     // not be responded to, as the requester might have disappeared.
                     Ok(Response::NoResponse()) => (),
+                    _ if wait_for_commit => commit_responses.push((response_sender, response)),
                     _ => response_sender.respond(response),
                 }
                 let now: DateTime<Utc> = Utc::now();
@@ -198,10 +296,18 @@ impl DatastoreWorker {
                 "Committing DB! Force commit {}, {} uncommitted events",
                 self.commit, self.uncommitted_events
             );
+            self.pending.lock().unwrap().start_commit();
             match tx.commit() {
                 Ok(_) => (),
                 Err(err) => panic!("Failed to commit datastore transaction! {}", err),
             }
+            self.pending.lock().unwrap().clear();
+            for notification in std::mem::take(&mut self.notifications) {
+                self.subscribers.notify(|| notification);
+            }
+            for (response_sender, response) in commit_responses {
+                response_sender.respond(response);
+            }
             if self.quit {
                 break;
             };
@@ -216,8 +322,13 @@ impl DatastoreWorker {
         tx: &Transaction,
     ) -> Result<Response, DatastoreError> {
         match request {
-            Command::CreateBucket(bucket) => match ds.create_bucket(tx, bucket) {
+            Command::CreateBucket(bucket) => match ds.create_bucket(tx, bucket.clone()) {
                 Ok(_) => {
+                    self.mark_uncommitted(&bucket.id);
+                    self.record_change(&bucket.id, None);
+                    self.notify(|| EventNotification::BucketCreated {
+                        bucket_id: bucket.id.clone(),
+                    });
                     self.commit = true;
                     Ok(Response::Empty())
                 }
@@ -225,6 +336,12 @@ impl DatastoreWorker {
             },
             Command::DeleteBucket(bucketname) => match ds.delete_bucket(tx, &bucketname) {
                 Ok(_) => {
+                    self.last_heartbeat.remove(&bucketname);
+                    self.mark_uncommitted(&bucketname);
+                    self.record_change(&bucketname, None);
+                    self.notify(|| EventNotification::BucketDeleted {
+                        bucket_id: bucketname.clone(),
+                    });
                     self.commit = true;
                     Ok(Response::Empty())
                 }
@@ -236,8 +353,21 @@ impl DatastoreWorker {
             },
             Command::GetBuckets() => Ok(Response::BucketMap(ds.get_buckets())),
             Command::InsertEvents(bucketname, events) => {
+                // Events with an id replace the existing event with that id, which could have
+                // been anywhere in the bucket
+                let replaces_events = events.iter().any(|e| e.id.is_some());
                 match ds.insert_events(tx, &bucketname, events) {
                     Ok(events) => {
+                        self.mark_uncommitted_events(&bucketname, &events);
+                        if replaces_events {
+                            self.record_change(&bucketname, None);
+                        } else if let Some(range) = events_range(&events) {
+                            self.record_change(&bucketname, Some(range));
+                        }
+                        self.notify(|| EventNotification::Inserted {
+                            bucket_id: bucketname.clone(),
+                            events: events.clone(),
+                        });
                         self.uncommitted_events += events.len();
                         self.last_heartbeat.insert(bucketname.to_string(), None); // invalidate last_heartbeat cache
                         Ok(Response::EventList(events))
@@ -247,7 +377,27 @@ impl DatastoreWorker {
             }
             Command::Heartbeat(bucketname, event, pulsetime) => {
                 match ds.heartbeat(tx, &bucketname, event, pulsetime, &mut self.last_heartbeat) {
-                    Ok(e) => {
+                    Ok((e, merged)) => {
+                        // The returned event has no id, unlike the cached one
+                        if let Some(Some(stored)) = self.last_heartbeat.get(&bucketname) {
+                            self.mark_uncommitted_events(&bucketname, std::slice::from_ref(stored));
+                        }
+                        // A merged heartbeat covers the event it was merged into
+                        self.record_change(&bucketname, Some((e.timestamp, e.calculate_endtime())));
+                        self.notify(|| {
+                            let bucket_id = bucketname.clone();
+                            if merged {
+                                EventNotification::LastEventReplaced {
+                                    bucket_id,
+                                    event: e.clone(),
+                                }
+                            } else {
+                                EventNotification::Inserted {
+                                    bucket_id,
+                                    events: vec![e.clone()],
+                                }
+                            }
+                        });
                         self.uncommitted_events += 1;
                         Ok(Response::Event(e))
                     }
@@ -260,8 +410,21 @@ impl DatastoreWorker {
                     Err(e) => Err(e),
                 }
             }
-            Command::GetEvents(bucketname, starttime_opt, endtime_opt, limit_opt) => {
-                match ds.get_events(tx, &bucketname, starttime_opt, endtime_opt, limit_opt) {
+            Command::GetEvents(bucketname, starttime_opt, endtime_opt, limit_opt, filter_opt) => {
+                match ds.get_events(
+                    tx,
+                    &bucketname,
+                    starttime_opt,
+                    endtime_opt,
+                    limit_opt,
+                    filter_opt.as_ref(),
+                ) {
+                    Ok(el) => Ok(Response::EventList(el)),
+                    Err(e) => Err(e),
+                }
+            }
+            Command::GetEventsAfter(bucketname, after_id, limit) => {
+                match ds.get_events_after(tx, &bucketname, after_id, limit) {
                     Ok(el) => Ok(Response::EventList(el)),
                     Err(e) => Err(e),
                 }
@@ -273,12 +436,23 @@ impl DatastoreWorker {
                 }
             }
             Command::DeleteEventsById(bucketname, event_ids) => {
-                match ds.delete_events_by_id(tx, &bucketname, event_ids) {
-                    Ok(()) => Ok(Response::Empty()),
+                match ds.delete_events_by_id(tx, &bucketname, event_ids.clone()) {
+                    Ok(()) => {
+                        // The last heartbeat could have been deleted
+                        self.last_heartbeat.remove(&bucketname);
+                        self.mark_deleted_events(&bucketname, &event_ids);
+                        self.record_change(&bucketname, None);
+                        self.notify(|| EventNotification::Deleted {
+                            bucket_id: bucketname.clone(),
+                            event_ids,
+                        });
+                        Ok(Response::Empty())
+                    }
                     Err(e) => Err(e),
                 }
             }
             Command::ForceCommit() => {
+                // The work_loop holds back the response until the commit is done
                 self.commit = true;
                 Ok(Response::Empty())
             }
@@ -298,7 +472,25 @@ impl DatastoreWorker {
                 Ok(()) => Ok(Response::Empty()),
                 Err(e) => Err(e),
             },
+            Command::Import(import, strategy) => match ds.import(tx, import, strategy) {
+                Ok((summary, notifications)) => {
+                    // Renamed buckets are imported under another id than the exported one
+                    for result in summary.buckets.values() {
+                        let bucket_id = &result.imported_as;
+                        self.last_heartbeat.insert(bucket_id.to_string(), None);
+                        self.mark_uncommitted(bucket_id);
+                        self.record_change(bucket_id, None);
+                    }
+                    for notification in notifications {
+                        self.notify(|| notification);
+                    }
+                    self.commit = true;
+                    Ok(Response::ImportSummary(summary))
+                }
+                Err(e) => Err(e),
+            },
             Command::Close() => {
+                self.subscribers.clear();
                 self.quit = true;
                 Ok(Response::NoResponse())
             }
@@ -320,11 +512,58 @@ impl Datastore {
     fn _new_internal(method: DatastoreMethod, legacy_import: bool) -> Self {
         let (requester, responder) =
             mpsc_requests::channel::<Command, Result<Response, DatastoreError>>();
+        let (reader_sender, reader_receiver) = mpsc::channel();
+        let pending = Arc::new(Mutex::new(PendingChanges::default()));
+        let worker_pending = Arc::clone(&pending);
+        let changes = Arc::new(Mutex::new(ChangeLog::new()));
+        let worker_changes = Arc::clone(&changes);
+        let subscribers = Subscribers::default();
+        let worker_subscribers = subscribers.clone();
+        let worker_method = method.clone();
         let _thread = thread::spawn(move || {
-            let mut di = DatastoreWorker::new(responder, legacy_import);
-            di.work_loop(method);
+            let mut di = DatastoreWorker::new(
+                responder,
+                legacy_import,
+                worker_pending,
+                worker_changes,
+                worker_subscribers,
+            );
+            di.work_loop(worker_method, reader_sender);
         });
-        Datastore { requester }
+        let reader = match method {
+            DatastoreMethod::Memory() => None,
+            // Fails if the worker failed to open the database, in which case all requests will
+            // fail anyway
+            DatastoreMethod::File(path) => match reader_receiver.recv() {
+                Ok(ds) => Some(Arc::new(DatastoreReader::new(path, ds, pending))),
+                Err(_) => None,
+            },
+        };
+        Datastore {
+            requester,
+            reader,
+            changes,
+            subscribers,
+        }
+    }
+
+    /// Sequence number of the latest change to any bucket
+    pub fn last_change(&self) -> u64 {
+        self.changes.lock().unwrap().last_seq()
+    }
+
+    /// Changes to buckets made after the change with the given sequence number, None if the
+    /// changes are too old to still be known
+    pub fn changes_since(&self, seq: u64) -> Option<Vec<BucketChange>> {
+        self.changes.lock().unwrap().changes_since(seq)
+    }
+
+    /// Calls the subscriber with every change to the events of any bucket, from the worker thread
+    /// right after the transaction with the change was committed. Transactions are committed in
+    /// batches, so this can be some time after the change was made, use force_commit to have the
+    /// notifications of all changes made so far sent.
+    pub fn subscribe(&self, subscriber: impl Fn(&EventNotification) + Send + 'static) {
+        self.subscribers.add(Box::new(subscriber));
     }
 
     pub fn create_bucket(&self, bucket: &Bucket) -> Result<(), DatastoreError> {
@@ -361,6 +600,10 @@ impl Datastore {
     }
 
     pub fn get_buckets(&self) -> Result<HashMap<String, Bucket>, DatastoreError> {
+        // The buckets cache is shared with the worker, so no need to commit first
+        if let Some(reader) = &self.reader {
+            return Ok(reader.get_buckets());
+        }
         let cmd = Command::GetBuckets();
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
@@ -427,7 +670,58 @@ impl Datastore {
         endtime_opt: Option<DateTime<Utc>>,
         limit_opt: Option<u64>,
     ) -> Result<Vec<Event>, DatastoreError> {
-        let cmd = Command::GetEvents(bucket_id.to_string(), starttime_opt, endtime_opt, limit_opt);
+        self.get_filtered_events(bucket_id, starttime_opt, endtime_opt, limit_opt, None)
+    }
+
+    /// Like get_events, but only returns the events matching the filter
+    pub fn get_filtered_events(
+        &self,
+        bucket_id: &str,
+        starttime_opt: Option<DateTime<Utc>>,
+        endtime_opt: Option<DateTime<Utc>>,
+        limit_opt: Option<u64>,
+        filter_opt: Option<&EventFilter>,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        // Reads never force a commit, so that they don't defeat the batching of commits while a
+        // watcher is sending heartbeats
+        if let Some(reader) = &self.reader {
+            if let Some(res) =
+                reader.get_events(bucket_id, starttime_opt, endtime_opt, limit_opt, filter_opt)
+            {
+                return res;
+            }
+        }
+        let cmd = Command::GetEvents(
+            bucket_id.to_string(),
+            starttime_opt,
+            endtime_opt,
+            limit_opt,
+            filter_opt.cloned(),
+        );
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::EventList(el) => Ok(el),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get up to `limit` events with an id larger than `after_id` ordered by id, without
+    /// cutting off the events at any time range
+    pub fn get_events_after(
+        &self,
+        bucket_id: &str,
+        after_id: Option<i64>,
+        limit: u64,
+    ) -> Result<Vec<Event>, DatastoreError> {
+        if let Some(reader) = &self.reader {
+            if let Some(res) = reader.get_events_after(bucket_id, after_id, limit) {
+                return res;
+            }
+        }
+        let cmd = Command::GetEventsAfter(bucket_id.to_string(), after_id, limit);
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
             Ok(r) => match r {
@@ -444,6 +738,11 @@ impl Datastore {
         starttime_opt: Option<DateTime<Utc>>,
         endtime_opt: Option<DateTime<Utc>>,
     ) -> Result<i64, DatastoreError> {
+        if let Some(reader) = &self.reader {
+            if let Some(res) = reader.get_event_count(bucket_id, starttime_opt, endtime_opt) {
+                return res;
+            }
+        }
         let cmd = Command::GetEventCount(bucket_id.to_string(), starttime_opt, endtime_opt);
         let receiver = self.requester.request(cmd).unwrap();
         match receiver.collect().unwrap() {
@@ -523,6 +822,24 @@ impl Datastore {
         }
     }
 
+    /// Imports the buckets with their events in a single transaction, if any bucket fails to be
+    /// imported nothing is imported
+    pub fn import(
+        &self,
+        import: BucketsExport,
+        strategy: ImportConflictStrategy,
+    ) -> Result<ImportSummary, DatastoreError> {
+        let cmd = Command::Import(import, strategy);
+        let receiver = self.requester.request(cmd).unwrap();
+        match receiver.collect().unwrap() {
+            Ok(r) => match r {
+                Response::ImportSummary(summary) => Ok(summary),
+                _ => panic!("Invalid response"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
     // TODO: Should this block until worker has stopped?
     pub fn close(&self) {
         info!("Sending close request to database");
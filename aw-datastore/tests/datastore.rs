@@ -9,15 +9,24 @@ extern crate appdirs;
 
 #[cfg(test)]
 mod datastore_tests {
+    use std::collections::HashMap;
+
     use chrono::Duration;
     use chrono::Utc;
     use serde_json::json;
 
     use aw_datastore::Datastore;
+    use aw_datastore::DatastoreError;
+    use aw_datastore::EventFilter;
+    use aw_datastore::EventNotification;
 
     use aw_models::Bucket;
+    use aw_models::BucketImportResult;
     use aw_models::BucketMetadata;
+    use aw_models::BucketsExport;
     use aw_models::Event;
+    use aw_models::ImportConflictStrategy;
+    use aw_models::TryVec;
 
     fn test_bucket() -> Bucket {
         Bucket {
@@ -248,6 +257,106 @@ mod datastore_tests {
         assert_eq!(event_count, 1);
     }
 
+    #[test]
+    fn test_get_filtered_events() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let values = [
+            json!("value"),
+            json!("other"),
+            json!(1),
+            json!(1.0),
+            json!(true),
+            json!(null),
+            json!({"nested": ["value"]}),
+        ];
+        let now = Utc::now();
+        let mut events: Vec<Event> = values
+            .iter()
+            .enumerate()
+            .map(|(i, val)| Event {
+                id: None,
+                timestamp: now + Duration::seconds(i as i64),
+                duration: Duration::seconds(1),
+                data: json_map! {"key": val.clone(), "i": i},
+            })
+            .collect();
+        events.push(Event {
+            id: None,
+            timestamp: now + Duration::seconds(values.len() as i64),
+            duration: Duration::seconds(1),
+            data: json_map! {"other_key": "value", "i": values.len()},
+        });
+        ds.insert_events(&bucket.id, &events).unwrap();
+
+        let get_filtered = |keyvals: Vec<(&str, Vec<serde_json::Value>)>| -> Vec<i64> {
+            let filter = EventFilter {
+                keyvals: keyvals
+                    .into_iter()
+                    .map(|(key, vals)| (key.to_string(), vals))
+                    .collect(),
+            };
+            let mut found: Vec<i64> = ds
+                .get_filtered_events(&bucket.id, None, None, None, Some(&filter))
+                .unwrap()
+                .iter()
+                .map(|e| e.data["i"].as_i64().unwrap())
+                .collect();
+            found.sort();
+            found
+        };
+
+        // Values only match if they are of the same type
+        assert_eq!(get_filtered(vec![("key", vec![json!("value")])]), vec![0]);
+        assert_eq!(
+            get_filtered(vec![("key", vec![json!("value"), json!("other")])]),
+            vec![0, 1]
+        );
+        assert_eq!(get_filtered(vec![("key", vec![json!(1)])]), vec![2]);
+        assert_eq!(get_filtered(vec![("key", vec![json!(1.0)])]), vec![3]);
+        assert_eq!(get_filtered(vec![("key", vec![json!(true)])]), vec![4]);
+        assert_eq!(get_filtered(vec![("key", vec![json!(null)])]), vec![5]);
+        assert_eq!(
+            get_filtered(vec![("key", vec![json!({"nested": ["value"]})])]),
+            vec![6]
+        );
+        assert_eq!(get_filtered(vec![("key", vec![])]), Vec::<i64>::new());
+        // All keys have to match
+        assert_eq!(
+            get_filtered(vec![("key", vec![json!("value")]), ("i", vec![json!(0)])]),
+            vec![0]
+        );
+        assert_eq!(
+            get_filtered(vec![("key", vec![json!("value")]), ("i", vec![json!(1)])]),
+            Vec::<i64>::new()
+        );
+        assert_eq!(
+            get_filtered(vec![("other_key", vec![json!("value")])]),
+            vec![7]
+        );
+
+        // The limit is applied to the matching events, also when the filter can't be checked
+        // exactly in SQL, such as for floats and objects, and newer events look like matches there
+        let newer = Event {
+            id: None,
+            timestamp: now + Duration::seconds(values.len() as i64 + 1),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": 2.5, "i": values.len() + 1},
+        };
+        ds.insert_events(&bucket.id, &[newer]).unwrap();
+        for (val, i) in [(json!(1.0), 3), (json!({"nested": ["value"]}), 6)] {
+            let filter = EventFilter {
+                keyvals: vec![("key".to_string(), vec![val])],
+            };
+            let found = ds
+                .get_filtered_events(&bucket.id, None, None, Some(1), Some(&filter))
+                .unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].data["i"], json!(i));
+        }
+    }
+
     #[test]
     fn test_events_delete() {
         // Setup datastore
@@ -375,6 +484,189 @@ mod datastore_tests {
         assert_ne!(fetched_events[0].id, e2.id);
     }
 
+    #[test]
+    fn test_changes() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+        let seq = ds.last_change();
+        assert_eq!(ds.changes_since(seq).unwrap().len(), 0);
+
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let mut e2 = e1.clone();
+        e2.timestamp = e1.timestamp + Duration::seconds(10);
+        let inserted = ds
+            .insert_events(&bucket.id, &[e2.clone(), e1.clone()])
+            .unwrap();
+        let changes = ds.changes_since(seq).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].bucket_id, bucket.id);
+        assert_eq!(
+            changes[0].range,
+            Some((e1.timestamp, e2.timestamp + Duration::seconds(1)))
+        );
+        assert!(changes[0].overlaps(&e1.timestamp, &e1.timestamp));
+        assert!(!changes[0].overlaps(
+            &(e1.timestamp - Duration::seconds(2)),
+            &(e1.timestamp - Duration::seconds(1))
+        ));
+
+        // A merged heartbeat changes the whole merged event
+        let seq = ds.last_change();
+        let mut e3 = e2.clone();
+        e3.timestamp = e2.timestamp + Duration::seconds(2);
+        ds.heartbeat(&bucket.id, e3.clone(), 10.0).unwrap();
+        let changes = ds.changes_since(seq).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            changes[0].range,
+            Some((e2.timestamp, e3.timestamp + Duration::seconds(1)))
+        );
+
+        // Deleting events could change anything in the bucket
+        let seq = ds.last_change();
+        ds.delete_events_by_id(&bucket.id, vec![inserted[0].id.unwrap()])
+            .unwrap();
+        ds.delete_bucket(&bucket.id).unwrap();
+        let changes = ds.changes_since(seq).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|change| change.range.is_none()));
+        assert_eq!(changes[1].seq, ds.last_change());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let ds = Datastore::new_in_memory(false);
+        let (tx, rx) = std::sync::mpsc::channel();
+        ds.subscribe(move |notification| tx.send(notification.clone()).unwrap());
+        let bucket = create_test_bucket(&ds);
+        ds.force_commit().unwrap();
+        let n = rx.try_recv().unwrap();
+        assert_eq!(n.kind(), "bucket_created");
+        assert_eq!(n.bucket_id(), bucket.id);
+
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let inserted = ds
+            .insert_events(&bucket.id, std::slice::from_ref(&e1))
+            .unwrap();
+        // Inserts don't force a commit, and the notification is only sent once committed
+        assert!(rx.try_recv().is_err());
+        ds.force_commit().unwrap();
+        match rx.try_recv().unwrap() {
+            EventNotification::Inserted { bucket_id, events } => {
+                assert_eq!(bucket_id, bucket.id);
+                assert_eq!(events, inserted);
+            }
+            n => panic!("unexpected notification {:?}", n),
+        }
+
+        // A merged heartbeat replaces the last event
+        let mut e2 = e1.clone();
+        e2.timestamp = e1.timestamp + Duration::seconds(2);
+        ds.heartbeat(&bucket.id, e2.clone(), 10.0).unwrap();
+        ds.force_commit().unwrap();
+        match rx.try_recv().unwrap() {
+            EventNotification::LastEventReplaced { event, .. } => {
+                assert_eq!(event.timestamp, e1.timestamp);
+                assert_eq!(event.duration, Duration::seconds(3));
+            }
+            n => panic!("unexpected notification {:?}", n),
+        }
+
+        // A heartbeat which is not merged is inserted as a new event
+        let mut e3 = e2.clone();
+        e3.data = json_map! {"key": json!("other value")};
+        ds.heartbeat(&bucket.id, e3, 10.0).unwrap();
+        ds.force_commit().unwrap();
+        let n = rx.try_recv().unwrap();
+        assert_eq!(n.kind(), "inserted");
+
+        ds.delete_events_by_id(&bucket.id, vec![inserted[0].id.unwrap()])
+            .unwrap();
+        ds.force_commit().unwrap();
+        match rx.try_recv().unwrap() {
+            EventNotification::Deleted { event_ids, .. } => {
+                assert_eq!(event_ids, vec![inserted[0].id.unwrap()])
+            }
+            n => panic!("unexpected notification {:?}", n),
+        }
+
+        ds.delete_bucket(&bucket.id).unwrap();
+        ds.force_commit().unwrap();
+        let n = rx.try_recv().unwrap();
+        assert_eq!(n.kind(), "bucket_deleted");
+        assert_eq!(n.bucket_id(), bucket.id);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_subscribe_import() {
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+        let (tx, rx) = std::sync::mpsc::channel();
+        ds.subscribe(move |notification| tx.send(notification.clone()).unwrap());
+
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let mut import_bucket = bucket.clone();
+        import_bucket.events = Some(TryVec::new(vec![e1.clone()]));
+        let import = || {
+            let mut import = BucketsExport {
+                buckets: HashMap::new(),
+            };
+            import
+                .buckets
+                .insert(bucket.id.clone(), import_bucket.clone());
+            import
+        };
+
+        // A failed import notifies nothing
+        assert!(ds.import(import(), ImportConflictStrategy::Fail).is_err());
+        ds.force_commit().unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // A renamed bucket is created and its events are inserted under the new id
+        let summary = ds.import(import(), ImportConflictStrategy::Rename).unwrap();
+        let imported_as = summary.buckets[&bucket.id].imported_as.clone();
+        ds.force_commit().unwrap();
+        let n = rx.try_recv().unwrap();
+        assert_eq!(n.kind(), "bucket_created");
+        assert_eq!(n.bucket_id(), imported_as);
+        match rx.try_recv().unwrap() {
+            EventNotification::Inserted { bucket_id, events } => {
+                assert_eq!(bucket_id, imported_as);
+                assert_eq!(events.len(), 1);
+                assert_eq!(events[0].data, e1.data);
+            }
+            n => panic!("unexpected notification {:?}", n),
+        }
+
+        // A replaced bucket is deleted and created again
+        ds.import(import(), ImportConflictStrategy::Replace)
+            .unwrap();
+        ds.force_commit().unwrap();
+        let kinds: Vec<&str> = rx.try_iter().map(|n| n.kind()).collect();
+        assert_eq!(kinds, vec!["bucket_deleted", "bucket_created", "inserted"]);
+
+        // Merged events which already exist are not inserted again
+        ds.import(import(), ImportConflictStrategy::Merge).unwrap();
+        ds.force_commit().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn test_event_replace() {
         // Setup datastore
@@ -435,6 +727,180 @@ mod datastore_tests {
         }
     }
 
+    #[test]
+    fn test_import() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let existing_bucket = create_test_bucket(&ds);
+
+        let e1 = Event {
+            id: Some(1),
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let mut e2 = e1.clone();
+        e2.id = Some(2);
+        e2.timestamp = e2.timestamp + Duration::seconds(1);
+
+        let mut new_bucket = test_bucket();
+        new_bucket.id = "testid2".to_string();
+        new_bucket.events = Some(TryVec::new(vec![e1.clone(), e2.clone()]));
+
+        // Importing an already existing bucket fails and rolls back the whole import
+        let mut conflicting_bucket = existing_bucket.clone();
+        conflicting_bucket.events = Some(TryVec::new(vec![e1.clone()]));
+        let mut import = BucketsExport {
+            buckets: HashMap::new(),
+        };
+        import
+            .buckets
+            .insert(new_bucket.id.clone(), new_bucket.clone());
+        import
+            .buckets
+            .insert(existing_bucket.id.clone(), conflicting_bucket);
+        match ds.import(import, ImportConflictStrategy::Fail) {
+            Err(DatastoreError::BucketAlreadyExists(bucket_id)) => {
+                assert_eq!(bucket_id, existing_bucket.id)
+            }
+            res => panic!("Expected BucketAlreadyExists, got {:?}", res),
+        }
+        match ds.get_bucket(&new_bucket.id) {
+            Err(DatastoreError::NoSuchBucket(_)) => (),
+            res => panic!("Expected NoSuchBucket, got {:?}", res),
+        }
+        let buckets = ds.get_buckets().unwrap();
+        assert_eq!(buckets.len(), 1);
+        let fetched_events = ds
+            .get_events(&existing_bucket.id, None, None, None)
+            .unwrap();
+        assert_eq!(fetched_events.len(), 0);
+
+        // Import new bucket only
+        let mut import = BucketsExport {
+            buckets: HashMap::new(),
+        };
+        import
+            .buckets
+            .insert(new_bucket.id.clone(), new_bucket.clone());
+        let summary = ds.import(import, ImportConflictStrategy::Fail).unwrap();
+        assert_eq!(
+            summary.buckets[&new_bucket.id],
+            BucketImportResult {
+                imported_as: new_bucket.id.clone(),
+                inserted: 2,
+                skipped: 0
+            }
+        );
+        let fetched_events = ds.get_events(&new_bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 2);
+        assert_eq!(fetched_events[0], e2);
+        assert_eq!(fetched_events[1], e1);
+        let bucket = ds.get_bucket(&new_bucket.id).unwrap();
+        assert_eq!(bucket.metadata.start, Some(e1.timestamp));
+        assert_eq!(bucket.metadata.end, Some(e2.calculate_endtime()));
+    }
+
+    #[test]
+    fn test_import_merge() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let mut e2 = e1.clone();
+        e2.data = json_map! {"key": json!("other value")};
+        let mut e3 = e1.clone();
+        e3.timestamp = e3.timestamp + Duration::seconds(1);
+        ds.insert_events(&bucket.id, &[e1.clone()]).unwrap();
+
+        // e1 already exists and e3 is in the import twice
+        let mut import_bucket = bucket.clone();
+        import_bucket.events = Some(TryVec::new(vec![
+            e1.clone(),
+            e2.clone(),
+            e3.clone(),
+            e3.clone(),
+        ]));
+        let mut import = BucketsExport {
+            buckets: HashMap::new(),
+        };
+        import.buckets.insert(bucket.id.clone(), import_bucket);
+        let summary = ds.import(import, ImportConflictStrategy::Merge).unwrap();
+        assert_eq!(
+            summary.buckets[&bucket.id],
+            BucketImportResult {
+                imported_as: bucket.id.clone(),
+                inserted: 2,
+                skipped: 2
+            }
+        );
+        let fetched_events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 3);
+        assert_eq!(fetched_events[0], e3);
+    }
+
+    #[test]
+    fn test_ndjson_export_import() {
+        // Setup datastore
+        let ds = Datastore::new_in_memory(false);
+        let bucket = create_test_bucket(&ds);
+
+        // Insert more events than fit in a single chunk
+        let now = Utc::now();
+        let events: Vec<Event> = (0..2500)
+            .map(|i| Event {
+                id: None,
+                timestamp: now + Duration::seconds(i),
+                duration: Duration::seconds(1),
+                data: json_map! {"i": json!(i)},
+            })
+            .collect();
+        ds.insert_events(&bucket.id, &events).unwrap();
+
+        let mut export = Vec::new();
+        aw_datastore::export_ndjson(&ds, vec![bucket.id.clone()], &mut export).unwrap();
+        let export = String::from_utf8(export).unwrap();
+        assert_eq!(export.lines().count(), 2501);
+
+        // Import into an empty datastore
+        let ds2 = Datastore::new_in_memory(false);
+        let summary =
+            aw_datastore::import_ndjson(&ds2, export.as_bytes(), ImportConflictStrategy::Fail)
+                .unwrap();
+        assert_eq!(
+            summary.buckets[&bucket.id],
+            BucketImportResult {
+                imported_as: bucket.id.clone(),
+                inserted: 2500,
+                skipped: 0
+            }
+        );
+        let fetched_events = ds2.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 2500);
+        assert_eq!(fetched_events[0].data, events[2499].data);
+
+        // Importing it again with merge only skips events
+        let summary =
+            aw_datastore::import_ndjson(&ds2, export.as_bytes(), ImportConflictStrategy::Merge)
+                .unwrap();
+        assert_eq!(summary.buckets[&bucket.id].inserted, 0);
+        assert_eq!(summary.buckets[&bucket.id].skipped, 2500);
+
+        // Events without a bucket header are invalid
+        let event_line = export.lines().nth(1).unwrap();
+        match aw_datastore::import_ndjson(&ds2, event_line.as_bytes(), ImportConflictStrategy::Fail)
+        {
+            Err(DatastoreError::InvalidData(_)) => (),
+            res => panic!("Expected InvalidData, got {:?}", res),
+        }
+    }
+
     #[test]
     fn test_datastore_reload() {
         // Create tmp datastore path
@@ -496,4 +962,203 @@ mod datastore_tests {
             );
         }
     }
+
+    #[test]
+    fn test_datastore_concurrent_reads() {
+        // Create tmp datastore path
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-reads.db");
+        let db_path_str = db_path.to_str().unwrap().to_string();
+
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-reads.db file");
+        }
+
+        let ds = Datastore::new(db_path_str, false);
+        let bucket = create_test_bucket(&ds);
+
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        let mut e2 = e1.clone();
+        e2.timestamp = e2.timestamp + Duration::seconds(1);
+        ds.insert_events(&bucket.id, &[e1.clone(), e2]).unwrap();
+
+        // Uncommitted events should be visible to reads right away
+        let fetched_events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 2);
+
+        // Read from several threads while heartbeats are being written
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let ds = ds.clone();
+                let bucket_id = bucket.id.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        let count = ds.get_event_count(&bucket_id, None, None).unwrap();
+                        assert!(count >= 2);
+                        let events = ds.get_events(&bucket_id, None, None, None).unwrap();
+                        assert!(events.len() >= 2);
+                    }
+                })
+            })
+            .collect();
+        let mut e3 = e1.clone();
+        e3.timestamp = e3.timestamp + Duration::seconds(2);
+        e3.duration = Duration::seconds(0);
+        e3.data = json_map! {"key": json!("other value")};
+        for i in 0..10 {
+            let mut heartbeat = e3.clone();
+            heartbeat.timestamp = heartbeat.timestamp + Duration::seconds(i);
+            ds.heartbeat(&bucket.id, heartbeat, 1.0).unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // The heartbeats are merged into a single event
+        let fetched_events = ds.get_events(&bucket.id, None, None, None).unwrap();
+        assert_eq!(fetched_events.len(), 3);
+        assert_eq!(fetched_events[0].duration, Duration::seconds(9));
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 3);
+        let buckets = ds.get_buckets().unwrap();
+        assert_eq!(
+            buckets[&bucket.id].metadata.end,
+            Some(fetched_events[0].calculate_endtime())
+        );
+    }
+
+    #[test]
+    fn test_datastore_reads_dont_commit() {
+        // Create tmp datastore path
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-uncommitted.db");
+        let db_path_str = db_path.to_str().unwrap().to_string();
+
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-uncommitted.db file");
+        }
+
+        let ds = Datastore::new(db_path_str.clone(), false);
+        let bucket = create_test_bucket(&ds);
+        let mut other_bucket = test_bucket();
+        other_bucket.id = "testid2".to_string();
+        ds.create_bucket(&other_bucket).unwrap();
+
+        let e1 = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value")},
+        };
+        ds.heartbeat(&bucket.id, e1, 1.0).unwrap();
+
+        // Reads of the bucket with the uncommitted heartbeat see it, and reads of any bucket
+        // leave it uncommitted
+        assert_eq!(
+            ds.get_events(&bucket.id, None, None, None).unwrap().len(),
+            1
+        );
+        assert_eq!(ds.get_event_count(&bucket.id, None, None).unwrap(), 1);
+        assert_eq!(ds.get_event_count(&other_bucket.id, None, None).unwrap(), 0);
+        let conn = rusqlite::Connection::open(&db_path_str).unwrap();
+        let committed_count = |conn: &rusqlite::Connection| -> i64 {
+            conn.query_row("SELECT count(*) FROM events", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(committed_count(&conn), 0);
+
+        ds.force_commit().unwrap();
+        assert_eq!(committed_count(&conn), 1);
+        assert_eq!(
+            ds.get_events(&bucket.id, None, None, None).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_datastore_reads_merge_pending() {
+        // Create tmp datastore path
+        let mut db_path = get_cache_dir().unwrap();
+        db_path.push("datastore-unittest-pending.db");
+        let db_path_str = db_path.to_str().unwrap().to_string();
+
+        if db_path.exists() {
+            std::fs::remove_file(db_path.clone())
+                .expect("Failed to remove datastore-unittest-pending.db file");
+        }
+
+        let ds = Datastore::new(db_path_str.clone(), false);
+        let bucket = create_test_bucket(&ds);
+        let now = Utc::now();
+        let events: Vec<Event> = (0..3)
+            .map(|i| Event {
+                id: None,
+                timestamp: now + Duration::seconds(i * 10),
+                duration: Duration::seconds(1),
+                data: json_map! {"key": json!(i)},
+            })
+            .collect();
+        let inserted = ds.insert_events(&bucket.id, &events).unwrap();
+        ds.force_commit().unwrap();
+
+        // Merge a heartbeat into the last event, delete the first one and insert a new one
+        let mut heartbeat = events[2].clone();
+        heartbeat.timestamp = heartbeat.timestamp + Duration::seconds(1);
+        ds.heartbeat(&bucket.id, heartbeat, 5.0).unwrap();
+        ds.delete_events_by_id(&bucket.id, vec![inserted[0].id.unwrap()])
+            .unwrap();
+        let mut new_event = events[0].clone();
+        new_event.timestamp = now + Duration::seconds(30);
+        new_event.data = json_map! {"key": json!(3)};
+        ds.insert_events(&bucket.id, &[new_event]).unwrap();
+
+        let conn = rusqlite::Connection::open(&db_path_str).unwrap();
+        let committed_count: i64 = conn
+            .query_row("SELECT count(*) FROM events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(committed_count, 3);
+
+        let filter = EventFilter {
+            keyvals: vec![("key".to_string(), vec![json!(1), json!(2)])],
+        };
+        let read = || {
+            (
+                ds.get_events(&bucket.id, None, None, None).unwrap(),
+                ds.get_events(&bucket.id, None, None, Some(2)).unwrap(),
+                ds.get_events(&bucket.id, Some(now + Duration::seconds(21)), None, None)
+                    .unwrap(),
+                ds.get_filtered_events(&bucket.id, None, None, Some(1), Some(&filter))
+                    .unwrap(),
+                ds.get_event_count(&bucket.id, Some(now + Duration::seconds(5)), None)
+                    .unwrap(),
+                ds.get_events_after(&bucket.id, inserted[0].id, 10)
+                    .unwrap()
+                    .iter()
+                    .map(|e| e.id.unwrap())
+                    .collect::<Vec<i64>>(),
+            )
+        };
+        let (all, limited, clipped, filtered, count, ids) = read();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].data, json_map! {"key": json!(3)});
+        assert_eq!(all[1].duration, Duration::seconds(2));
+        assert_eq!(&limited[..], &all[..2]);
+        assert_eq!(clipped.len(), 2);
+        assert_eq!(clipped[1].timestamp, now + Duration::seconds(21));
+        assert_eq!(clipped[1].duration, Duration::seconds(1));
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].data, json_map! {"key": json!(2)});
+        assert_eq!(count, 3);
+        assert_eq!(ids.len(), 3);
+
+        // The reads give the same results once the changes are committed
+        ds.force_commit().unwrap();
+        assert_eq!(read(), (all, limited, clipped, filtered, count, ids));
+    }
 }
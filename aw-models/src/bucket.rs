@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::map::Map;
 use serde_json::value::Value;
 use std::collections::HashMap;
+use std::str::FromStr;
 
 use crate::Event;
 use crate::TryVec;
@@ -46,11 +47,61 @@ impl Default for BucketMetadata {
     }
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct BucketsExport {
     pub buckets: HashMap<String, Bucket>,
 }
 
+/// What to do when importing a bucket with an ID which already exists
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportConflictStrategy {
+    /// Fail the whole import
+    #[default]
+    Fail,
+    /// Keep the existing bucket and don't import the bucket
+    Skip,
+    /// Delete the existing bucket and its events before importing the bucket
+    Replace,
+    /// Import the events into the existing bucket, skipping events which already exist with the
+    /// same timestamp, duration and data
+    Merge,
+    /// Import the bucket under a new ID with an "-imported" suffix
+    Rename,
+}
+
+impl FromStr for ImportConflictStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ImportConflictStrategy, Self::Err> {
+        match s {
+            "fail" => Ok(ImportConflictStrategy::Fail),
+            "skip" => Ok(ImportConflictStrategy::Skip),
+            "replace" | "overwrite" => Ok(ImportConflictStrategy::Replace),
+            "merge" => Ok(ImportConflictStrategy::Merge),
+            "rename" => Ok(ImportConflictStrategy::Rename),
+            _ => Err(format!("Invalid import conflict strategy '{}'", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct BucketImportResult {
+    // ID of the bucket the events were imported into, differs from the exported bucket ID when
+    // the bucket was renamed due to a conflict
+    pub imported_as: String,
+    // Number of events inserted into the bucket
+    pub inserted: usize,
+    // Number of events which were not inserted, such as events which failed to be parsed or
+    // already existed
+    pub skipped: usize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct ImportSummary {
+    pub buckets: HashMap<String, BucketImportResult>,
+}
+
 #[test]
 fn test_bucket() {
     let b = Bucket {
@@ -26,8 +26,11 @@ mod timeinterval;
 mod tryvec;
 
 pub use self::bucket::Bucket;
+pub use self::bucket::BucketImportResult;
 pub use self::bucket::BucketMetadata;
 pub use self::bucket::BucketsExport;
+pub use self::bucket::ImportConflictStrategy;
+pub use self::bucket::ImportSummary;
 pub use self::event::Event;
 pub use self::info::Info;
 pub use self::key_value::Key;
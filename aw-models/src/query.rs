@@ -8,4 +8,13 @@ pub struct Query {
     //#[serde(with = "DurationSerialization")]
     pub timeperiods: Vec<TimeInterval>,
     pub query: Vec<String>,
+    /// Return timings of the statements and function calls of each timeperiod with the results
+    #[serde(default)]
+    pub profile: bool,
+    /// Timezone the query runs in, such as "Europe/Stockholm" or "+02:00", UTC if not set
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Local time at which days start, such as "04:00", midnight if not set
+    #[serde(default)]
+    pub day_start: Option<String>,
 }
@@ -55,6 +55,15 @@ impl<T: JsonSchema> TryVec<T> {
         TryVec { inner: Vec::new() }
     }
 
+    /// Number of items, including the ones which failed to be parsed
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
     pub fn take_inner(self) -> Vec<T> {
         let mut vec: Vec<T> = Vec::new();
         for item in self.inner {
@@ -1,6 +1,7 @@
 use crate::lexer::Span;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Program {
@@ -22,16 +23,38 @@ pub enum Expr_ {
     Mod(Box<Expr>, Box<Expr>),
 
     Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+    Less(Box<Expr>, Box<Expr>),
+    LessEqual(Box<Expr>, Box<Expr>),
+    Greater(Box<Expr>, Box<Expr>),
+    GreaterEqual(Box<Expr>, Box<Expr>),
+
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
 
     Var(String),
+    Attribute(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
     Assign(String, Box<Expr>),
     Function(String, Box<Expr>),
+    FunctionDef(Arc<FunctionDef>),
     If(Vec<(Box<Expr>, Vec<Expr>)>),
+    For(String, Box<Expr>, Vec<Expr>),
     Return(Box<Expr>),
 
     Bool(bool),
     Number(f64),
     String(String),
     List(Vec<Expr>),
+    // [item for var in list if cond]
+    ListComprehension(Box<Expr>, String, Box<Expr>, Option<Box<Expr>>),
     Dict(HashMap<String, Expr>),
 }
+
+#[derive(Debug)]
+pub struct FunctionDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Expr>,
+}
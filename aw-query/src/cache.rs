@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use aw_datastore::BucketChange;
+use aw_datastore::Datastore;
+use aw_models::TimeInterval;
+use aw_transform::Calendar;
+
+use crate::DataType;
+use crate::QueryDependencies;
+use crate::QueryError;
+use crate::QueryLimits;
+use crate::QueryOptions;
+
+/*
+ * Caches query results per query, timeinterval and calendar
+ *
+ * A result stays cached until one of the buckets the query read gets changes overlapping its
+ * timeinterval, as recorded by the datastore. Results of queries which depend on which buckets
+ * exist are also dropped whenever a bucket is created, deleted or imported.
+ *
+ * A cache must only be used with a single datastore.
+ */
+pub struct QueryCache {
+    inner: Mutex<QueryCacheInner>,
+    max_entries: usize,
+    limits: QueryLimits,
+}
+
+type CacheKey = (String, String, String);
+
+struct QueryCacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Keys of the entries by when they were last used, least recently used first
+    lru: BTreeMap<u64, CacheKey>,
+    // Sequence number of the last datastore change which has been applied to the entries
+    last_change: Option<u64>,
+    uses: u64,
+}
+
+struct CacheEntry {
+    result: DataType,
+    deps: QueryDependencies,
+    interval: TimeInterval,
+    last_used: u64,
+}
+
+impl CacheEntry {
+    fn affected_by(&self, change: &BucketChange) -> bool {
+        if change.range.is_none() && self.deps.bucket_list {
+            return true;
+        }
+        self.deps.buckets.contains(&change.bucket_id)
+            && change.overlaps(self.interval.start(), self.interval.end())
+    }
+}
+
+impl QueryCacheInner {
+    fn apply_changes(&mut self, ds: &Datastore) {
+        let last_change = match self.last_change {
+            Some(last_change) => last_change,
+            None => {
+                self.last_change = Some(ds.last_change());
+                return;
+            }
+        };
+        match ds.changes_since(last_change) {
+            Some(changes) => {
+                let lru = &mut self.lru;
+                self.entries.retain(|_, entry| {
+                    let affected = changes.iter().any(|change| entry.affected_by(change));
+                    if affected {
+                        lru.remove(&entry.last_used);
+                    }
+                    !affected
+                });
+                if let Some(change) = changes.last() {
+                    self.last_change = Some(change.seq);
+                }
+            }
+            // Too many changes to know what changed
+            None => {
+                self.entries.clear();
+                self.lru.clear();
+                self.last_change = Some(ds.last_change());
+            }
+        }
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<DataType> {
+        let uses = self.next_use();
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.last_used);
+        entry.last_used = uses;
+        self.lru.insert(uses, key.clone());
+        Some(entry.result.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, mut entry: CacheEntry, max_entries: usize) {
+        if let Some(old_entry) = self.entries.remove(&key) {
+            self.lru.remove(&old_entry.last_used);
+        }
+        while self.entries.len() >= max_entries {
+            match self.lru.pop_first() {
+                Some((_, least_recently_used)) => {
+                    self.entries.remove(&least_recently_used);
+                }
+                None => break,
+            }
+        }
+        entry.last_used = self.next_use();
+        self.lru.insert(entry.last_used, key.clone());
+        self.entries.insert(key, entry);
+    }
+}
+
+impl QueryCache {
+    /// Creates a cache keeping at most max_entries results, queries run with the given limits
+    pub fn new(max_entries: usize, limits: QueryLimits) -> QueryCache {
+        QueryCache {
+            inner: Mutex::new(QueryCacheInner {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                last_change: None,
+                uses: 0,
+            }),
+            max_entries,
+            limits,
+        }
+    }
+
+    /// Runs the query, or returns the cached result if none of the data it read has changed
+    pub fn query(
+        &self,
+        code: &str,
+        ti: &TimeInterval,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        self.query_in_calendar(code, ti, &Calendar::default(), ds)
+    }
+
+    /// Like query, with the calendar deciding where days begin
+    pub fn query_in_calendar(
+        &self,
+        code: &str,
+        ti: &TimeInterval,
+        calendar: &Calendar,
+        ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        let key = (code.to_string(), ti.to_string(), calendar.to_string());
+        let seq = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.apply_changes(ds);
+            if let Some(result) = inner.get(&key) {
+                return Ok(result);
+            }
+            inner.last_change.unwrap()
+        };
+
+        // The lock isn't held while querying, so that other queries can run meanwhile
+        let options = QueryOptions {
+            calendar: *calendar,
+            limits: self.limits.clone(),
+            profile: false,
+        };
+        let output = crate::query_with_options(code, ti, ds, &options)?;
+        let result = output.result;
+        if self.max_entries == 0 {
+            return Ok(result);
+        }
+        let entry = CacheEntry {
+            result: result.clone(),
+            deps: output.dependencies,
+            interval: ti.clone(),
+            last_used: 0,
+        };
+
+        // Changes made while the query ran might be missing from the result. Those up to
+        // last_change have already been applied to the other entries and won't be applied to this
+        // one, so it is only cached if none of them affect it. Later changes are applied to it by
+        // the next apply_changes, as the lock is held until it is inserted.
+        let mut inner = self.inner.lock().unwrap();
+        inner.apply_changes(ds);
+        let last_change = inner.last_change.unwrap();
+        if last_change > seq {
+            match ds.changes_since(seq) {
+                Some(changes)
+                    if !changes
+                        .iter()
+                        .filter(|change| change.seq <= last_change)
+                        .any(|change| entry.affected_by(change)) => {}
+                _ => return Ok(result),
+            }
+        }
+        inner.insert(key, entry, self.max_entries);
+        Ok(result)
+    }
+
+    /// Number of cached results
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -1,18 +1,21 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
+use std::sync::Arc;
 
+use super::ast::FunctionDef;
 use super::functions;
 use super::QueryError;
 use aw_models::Event;
-use aw_transform::classify::{RegexRule, Rule};
+use aw_transform::classify::{ExactRule, GlobRule, RegexRule, Rule};
+use aw_transform::{Aggregation, TimeUnit};
 
+use serde::ser::Error;
 use serde::{Serialize, Serializer};
 use serde_json::value::Value;
 use serde_json::Number;
 
-// TODO: greater/less comparisons
-
 #[derive(Clone, Serialize)]
 #[serde(untagged)]
 pub enum DataType {
@@ -25,6 +28,8 @@ pub enum DataType {
     Dict(HashMap<String, DataType>),
     #[serde(serialize_with = "serialize_function")]
     Function(String, functions::QueryFn),
+    #[serde(serialize_with = "serialize_user_function")]
+    UserFunction(Arc<FunctionDef>),
 }
 
 #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -40,6 +45,16 @@ where
     //element.id.serialize(serializer)
 }
 
+fn serialize_user_function<S>(fun: &Arc<FunctionDef>, _serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    Err(S::Error::custom(format!(
+        "Cannot return the function {} from a query",
+        fun.name
+    )))
+}
+
 // Needed because of a limitation in rust where you cannot derive(Debug) on a
 // enum which has a fn with reference parameters which our QueryFn has
 // https://stackoverflow.com/questions/53380040/function-pointer-with-a-reference-argument-cannot-derive-debug
@@ -54,6 +69,7 @@ impl fmt::Debug for DataType {
             DataType::List(l) => write!(f, "List({:?})", l),
             DataType::Dict(d) => write!(f, "Dict({:?})", d),
             DataType::Function(name, _fun) => write!(f, "Function({})", name),
+            DataType::UserFunction(fun) => write!(f, "UserFunction({})", fun.name),
         }
     }
 }
@@ -78,6 +94,95 @@ impl DataType {
             ))),
         }
     }
+
+    /* Ordering used by <, >, <= and >=, only numbers (which includes durations, as they are
+     * always represented as seconds) and strings (compared lexicographically) can be ordered */
+    pub fn query_cmp(&self, other: &DataType) -> Result<Ordering, QueryError> {
+        match (self, other) {
+            (DataType::Number(n1), DataType::Number(n2)) => match n1.partial_cmp(n2) {
+                Some(ordering) => Ok(ordering),
+                None => Err(QueryError::MathError(format!(
+                    "Cannot compare numbers {} and {}",
+                    n1, n2
+                ))),
+            },
+            (DataType::String(s1), DataType::String(s2)) => Ok(s1.cmp(s2)),
+            (DataType::Number(_), _) | (DataType::String(_), _) => {
+                Err(QueryError::InvalidType(format!(
+                    "Cannot compare values of different types {:?} and {:?}",
+                    self, other
+                )))
+            }
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot order values of type {:?}, only numbers and strings can be ordered",
+                self
+            ))),
+        }
+    }
+}
+
+/* Attribute and index access (e.data.app, list[0] and dict["key"]) */
+impl DataType {
+    pub fn query_attr(&self, attr: &str) -> Result<DataType, QueryError> {
+        match self {
+            DataType::Event(e) => match attr {
+                "id" => Ok(match e.id {
+                    Some(id) => DataType::Number(id as f64),
+                    None => DataType::None(),
+                }),
+                "timestamp" => Ok(DataType::String(e.timestamp.to_rfc3339())),
+                // Durations are in seconds, like in sum_durations
+                "duration" => Ok(DataType::Number(
+                    (e.duration.num_milliseconds() as f64) / 1000.0,
+                )),
+                "data" => Ok(DataType::Dict(
+                    e.data.iter().map(|(k, v)| (k.clone(), v.into())).collect(),
+                )),
+                _ => Err(QueryError::KeyNotFound(format!(
+                    "Events have no attribute '{}'",
+                    attr
+                ))),
+            },
+            DataType::Dict(d) => match d.get(attr) {
+                Some(val) => Ok(val.clone()),
+                None => Err(QueryError::KeyNotFound(format!(
+                    "Dict has no key '{}'",
+                    attr
+                ))),
+            },
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot get attribute '{}' of {:?}, only events and dicts have attributes",
+                attr, self
+            ))),
+        }
+    }
+
+    pub fn query_index(&self, index: &DataType) -> Result<DataType, QueryError> {
+        match (self, index) {
+            (DataType::List(l), DataType::Number(n)) => {
+                if n.fract() != 0.0 || *n < 0.0 {
+                    return Err(QueryError::InvalidType(format!(
+                        "List indices must be non-negative integers, got {}",
+                        n
+                    )));
+                }
+                match l.get(*n as usize) {
+                    Some(val) => Ok(val.clone()),
+                    None => Err(QueryError::IndexOutOfRange(format!(
+                        "Index {} is out of range for a list of length {}",
+                        n,
+                        l.len()
+                    ))),
+                }
+            }
+            (DataType::Dict(_), DataType::String(key))
+            | (DataType::Event(_), DataType::String(key)) => self.query_attr(key),
+            _ => Err(QueryError::InvalidType(format!(
+                "Cannot index {:?} with {:?}",
+                self, index
+            ))),
+        }
+    }
 }
 
 /* Required for query_eq when comparing two dicts */
@@ -278,6 +383,97 @@ impl TryFrom<&DataType> for Vec<Value> {
     }
 }
 
+impl From<&Value> for DataType {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => DataType::None(),
+            Value::Bool(b) => DataType::Bool(*b),
+            Value::Number(n) => DataType::Number(n.as_f64().unwrap()),
+            Value::String(s) => DataType::String(s.clone()),
+            Value::Array(a) => DataType::List(a.iter().map(|v| v.into()).collect()),
+            Value::Object(o) => {
+                DataType::Dict(o.iter().map(|(k, v)| (k.clone(), v.into())).collect())
+            }
+        }
+    }
+}
+
+impl TryFrom<&DataType> for Aggregation {
+    type Error = QueryError;
+
+    fn try_from(data: &DataType) -> Result<Self, Self::Error> {
+        match data {
+            DataType::String(s) => match s.as_str() {
+                "sum_duration" => Ok(Aggregation::SumDuration),
+                "count" => Ok(Aggregation::Count),
+                "first_timestamp" => Ok(Aggregation::FirstTimestamp),
+                "last_timestamp" => Ok(Aggregation::LastTimestamp),
+                _ => Err(QueryError::InvalidFunctionParameters(format!(
+                    "Unknown aggregation {}",
+                    s
+                ))),
+            },
+            DataType::List(l) => match l.as_slice() {
+                [DataType::String(s), DataType::String(key)] if s == "distinct_count" => {
+                    Ok(Aggregation::DistinctCount(key.clone()))
+                }
+                _ => Err(QueryError::InvalidFunctionParameters(format!(
+                    "Unknown aggregation {:?}",
+                    l
+                ))),
+            },
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected aggregation, got {:?}",
+                data
+            ))),
+        }
+    }
+}
+
+impl TryFrom<&DataType> for TimeUnit {
+    type Error = QueryError;
+
+    fn try_from(data: &DataType) -> Result<Self, Self::Error> {
+        match data {
+            DataType::String(s) => match s.as_str() {
+                "hour" => Ok(TimeUnit::Hour),
+                "day" => Ok(TimeUnit::Day),
+                "week" => Ok(TimeUnit::Week),
+                _ => Err(QueryError::InvalidFunctionParameters(format!(
+                    "Unknown time unit {}, expected hour, day or week",
+                    s
+                ))),
+            },
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected time unit, got {:?}",
+                data
+            ))),
+        }
+    }
+}
+
+impl TryFrom<&DataType> for Vec<(String, Aggregation)> {
+    type Error = QueryError;
+
+    fn try_from(data: &DataType) -> Result<Self, Self::Error> {
+        match data {
+            DataType::Dict(dict) => {
+                let mut aggregations = Vec::new();
+                for (name, aggregation) in dict.iter() {
+                    aggregations.push((name.clone(), aggregation.try_into()?));
+                }
+                // Dicts have no order, but the results should always be the same
+                aggregations.sort_by(|a, b| a.0.cmp(&b.0));
+                Ok(aggregations)
+            }
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected dict of aggregations, got {:?}",
+                data
+            ))),
+        }
+    }
+}
+
 impl TryFrom<&DataType> for Rule {
     type Error = QueryError;
 
@@ -307,52 +503,159 @@ impl TryFrom<&DataType> for Rule {
                 ))
             }
         };
-        if rtype == "none" {
-            Ok(Self::None)
-        } else if rtype == "regex" {
-            let regex_val = match obj.get("regex") {
-                Some(regex_val) => regex_val,
-                None => {
-                    return Err(QueryError::InvalidFunctionParameters(
-                        "regex rule is missing the 'regex' field".to_string(),
-                    ))
-                }
-            };
-            let regex_str = match regex_val {
-                DataType::String(s) => s,
-                _ => {
-                    return Err(QueryError::InvalidFunctionParameters(
-                        "the regex field of the regex rule is not a string".to_string(),
-                    ))
-                }
-            };
-            let ignore_case_val = match obj.get("ignore_case") {
-                Some(case_val) => case_val,
-                None => &DataType::Bool(false),
-            };
-            let ignore_case = match ignore_case_val {
-                DataType::Bool(b) => b,
-                _ => {
-                    return Err(QueryError::InvalidFunctionParameters(
-                        "the ignore_case field of the regex rule is not a bool".to_string(),
-                    ))
-                }
-            };
-            let regex_rule = match RegexRule::new(regex_str, *ignore_case) {
-                Ok(regex_rule) => regex_rule,
-                Err(err) => {
-                    return Err(QueryError::RegexCompileError(format!(
-                        "Failed to compile regex string '{}': '{:?}",
-                        regex_str, err
-                    )))
+        match rtype.as_str() {
+            "none" => Ok(Self::None),
+            "regex" => {
+                let regex_str = rule_string_field(obj, rtype, "regex")?;
+                let ignore_case = rule_ignore_case(obj, rtype)?;
+                let regex_rule = match RegexRule::new(regex_str, ignore_case) {
+                    Ok(regex_rule) => regex_rule,
+                    Err(err) => {
+                        return Err(QueryError::RegexCompileError(format!(
+                            "Failed to compile regex string '{}': '{:?}",
+                            regex_str, err
+                        )))
+                    }
+                };
+                Ok(Self::Regex(match rule_select_keys(obj, rtype)? {
+                    Some(keys) => regex_rule.select_keys(keys),
+                    None => regex_rule,
+                }))
+            }
+            "glob" => {
+                let pattern = rule_string_field(obj, rtype, "pattern")?;
+                let ignore_case = rule_ignore_case(obj, rtype)?;
+                let glob_rule = match GlobRule::new(pattern, ignore_case) {
+                    Ok(glob_rule) => glob_rule,
+                    Err(err) => {
+                        return Err(QueryError::RegexCompileError(format!(
+                            "Failed to compile glob pattern '{}': '{:?}",
+                            pattern, err
+                        )))
+                    }
+                };
+                Ok(Self::Glob(match rule_select_keys(obj, rtype)? {
+                    Some(keys) => glob_rule.select_keys(keys),
+                    None => glob_rule,
+                }))
+            }
+            "exact" => {
+                let value = rule_string_field(obj, rtype, "value")?;
+                let ignore_case = rule_ignore_case(obj, rtype)?;
+                let exact_rule = ExactRule::new(value, ignore_case);
+                Ok(Self::Exact(match rule_select_keys(obj, rtype)? {
+                    Some(keys) => exact_rule.select_keys(keys),
+                    None => exact_rule,
+                }))
+            }
+            "all" | "any" => {
+                let rules = match rule_field(obj, rtype, "rules")? {
+                    DataType::List(rules) => rules,
+                    _ => {
+                        return Err(QueryError::InvalidFunctionParameters(format!(
+                            "the rules field of the {} rule is not a list",
+                            rtype
+                        )))
+                    }
+                };
+                let rules = rules
+                    .iter()
+                    .map(Rule::try_from)
+                    .collect::<Result<Vec<Rule>, QueryError>>()?;
+                if rtype == "all" {
+                    Ok(Self::All(rules))
+                } else {
+                    Ok(Self::Any(rules))
                 }
-            };
-            Ok(Self::Regex(regex_rule))
-        } else {
-            Err(QueryError::InvalidFunctionParameters(format!(
+            }
+            "not" => {
+                let rule = rule_field(obj, rtype, "rule")?.try_into()?;
+                Ok(Self::Not(Box::new(rule)))
+            }
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
                 "Unknown rule type '{}'",
                 rtype
-            )))
+            ))),
+        }
+    }
+}
+
+fn rule_field<'a>(
+    obj: &'a HashMap<String, DataType>,
+    rtype: &str,
+    field: &str,
+) -> Result<&'a DataType, QueryError> {
+    match obj.get(field) {
+        Some(val) => Ok(val),
+        None => Err(QueryError::InvalidFunctionParameters(format!(
+            "{} rule is missing the '{}' field",
+            rtype, field
+        ))),
+    }
+}
+
+fn rule_string_field<'a>(
+    obj: &'a HashMap<String, DataType>,
+    rtype: &str,
+    field: &str,
+) -> Result<&'a str, QueryError> {
+    match rule_field(obj, rtype, field)? {
+        DataType::String(s) => Ok(s),
+        _ => Err(QueryError::InvalidFunctionParameters(format!(
+            "the {} field of the {} rule is not a string",
+            field, rtype
+        ))),
+    }
+}
+
+/// The keys a rule only matches the values of, all string values are matched if not set
+///
+/// A single key can also be given in the key field, which rules had before select_keys.
+fn rule_select_keys(
+    obj: &HashMap<String, DataType>,
+    rtype: &str,
+) -> Result<Option<Vec<String>>, QueryError> {
+    if obj.contains_key("key") {
+        if obj.contains_key("select_keys") {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "the {} rule has both a key and a select_keys field",
+                rtype
+            )));
         }
+        let key = rule_string_field(obj, rtype, "key")?;
+        return Ok(Some(vec![key.to_string()]));
+    }
+    match obj.get("select_keys") {
+        Some(DataType::List(keys)) => {
+            let mut select_keys = Vec::new();
+            for key in keys {
+                match key {
+                    DataType::String(key) => select_keys.push(key.clone()),
+                    _ => {
+                        return Err(QueryError::InvalidFunctionParameters(format!(
+                            "the select_keys field of the {} rule is not a list of strings",
+                            rtype
+                        )))
+                    }
+                }
+            }
+            Ok(Some(select_keys))
+        }
+        Some(_) => Err(QueryError::InvalidFunctionParameters(format!(
+            "the select_keys field of the {} rule is not a list of strings",
+            rtype
+        ))),
+        None => Ok(None),
+    }
+}
+
+fn rule_ignore_case(obj: &HashMap<String, DataType>, rtype: &str) -> Result<bool, QueryError> {
+    match obj.get("ignore_case") {
+        Some(DataType::Bool(b)) => Ok(*b),
+        Some(_) => Err(QueryError::InvalidFunctionParameters(format!(
+            "the ignore_case field of the {} rule is not a bool",
+            rtype
+        ))),
+        None => Ok(false),
     }
 }
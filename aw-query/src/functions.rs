@@ -1,7 +1,10 @@
+use crate::BucketAccess;
 use crate::DataType;
+use crate::QueryDependencies;
 use crate::QueryError;
 use crate::VarEnv;
 use aw_datastore::Datastore;
+use aw_datastore::DatastoreError;
 
 pub type QueryFn =
     fn(args: Vec<DataType>, env: &VarEnv, ds: &Datastore) -> Result<DataType, QueryError>;
@@ -60,6 +63,14 @@ pub fn fill_env(env: &mut VarEnv) {
             qfunctions::merge_events_by_keys,
         ),
     );
+    env.insert(
+        "group_by".to_string(),
+        DataType::Function("group_by".to_string(), qfunctions::group_by),
+    );
+    env.insert(
+        "histogram".to_string(),
+        DataType::Function("histogram".to_string(), qfunctions::histogram),
+    );
     env.insert(
         "chunk_events_by_key".to_string(),
         DataType::Function(
@@ -115,15 +126,87 @@ pub fn fill_env(env: &mut VarEnv) {
     );
 }
 
+/// Records which buckets a call to a builtin function reads
+pub fn record_dependencies(fname: &str, args: &[DataType], deps: &mut QueryDependencies) {
+    match fname {
+        "query_bucket" => {
+            if let Some(DataType::String(bucket_id)) = args.first() {
+                deps.buckets.insert(bucket_id.clone());
+            }
+        }
+        "query_bucket_names" | "find_bucket" => deps.bucket_list = true,
+        _ => (),
+    }
+}
+
+/// Errors if a call to a builtin function would read a bucket the query isn't allowed to read
+pub fn check_access(
+    fname: &str,
+    args: &[DataType],
+    access: &BucketAccess,
+) -> Result<(), QueryError> {
+    match fname {
+        "query_bucket" => match args.first() {
+            Some(DataType::String(bucket_id)) if !access.allows(bucket_id) => Err(
+                QueryError::AccessDenied(format!("Not allowed to query bucket '{}'", bucket_id)),
+            ),
+            _ => Ok(()),
+        },
+        "query_bucket_names" | "find_bucket" => Err(QueryError::AccessDenied(format!(
+            "Not allowed to call {}, it needs access to all buckets",
+            fname
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Number of events loaded from the datastore by a call to a builtin function
+pub fn loaded_events(fname: &str, result: &DataType) -> usize {
+    match (fname, result) {
+        ("query_bucket", DataType::List(events)) => events.len(),
+        _ => 0,
+    }
+}
+
+/// Calls the builtin function, letting it load at most max_events events from the datastore
+pub fn call_with_max_events(
+    fname: &str,
+    fun: QueryFn,
+    args: Vec<DataType>,
+    env: &VarEnv,
+    ds: &Datastore,
+    max_events: Option<usize>,
+) -> Result<DataType, QueryError> {
+    match (fname, max_events) {
+        ("query_bucket", Some(max_events)) => {
+            qfunctions::query_bucket_with_limit(args, env, ds, Some(max_events as u64))
+        }
+        _ => fun(args, env, ds),
+    }
+}
+
+/// A missing bucket is the fault of the query, any other datastore error is not
+fn datastore_error(msg: &str, err: DatastoreError) -> QueryError {
+    match err {
+        DatastoreError::NoSuchBucket(_) => {
+            QueryError::BucketQueryError(format!("{}: {:?}", msg, err))
+        }
+        err => QueryError::DatastoreError(format!("{}: {:?}", msg, err)),
+    }
+}
+
 mod qfunctions {
+    use std::collections::HashMap;
     use std::convert::TryFrom;
     use std::convert::TryInto;
 
     use aw_datastore::Datastore;
     use aw_models::Event;
     use aw_transform::classify::Rule;
+    use aw_transform::{Aggregation, TimeUnit};
+    use serde_json::Value;
 
-    use super::validate;
+    use super::{datastore_error, validate};
     use crate::DataType;
     use crate::QueryError;
     use crate::VarEnv;
@@ -143,26 +226,41 @@ mod qfunctions {
         args: Vec<DataType>,
         env: &VarEnv,
         ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        query_bucket_with_limit(args, env, ds, None)
+    }
+
+    /// query_bucket which loads at most limit events, the most recent ones
+    pub fn query_bucket_with_limit(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        ds: &Datastore,
+        limit: Option<u64>,
     ) -> Result<DataType, QueryError> {
         // Typecheck
-        validate::args_length(&args, 1)?;
+        if args.is_empty() || args.len() > 2 {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected 1 or 2 parameters in function, got {}",
+                args.len()
+            )));
+        }
 
         let bucket_id: String = (&args[0]).try_into()?;
+        let filter = match args.get(1) {
+            Some(options) => Some(validate::query_bucket_filter(options)?),
+            None => None,
+        };
         let interval = validate::get_timeinterval(env)?;
 
-        let events = match ds.get_events(
+        let events = match ds.get_filtered_events(
             bucket_id.as_str(),
             Some(*interval.start()),
             Some(*interval.end()),
-            None,
+            limit,
+            filter.as_ref(),
         ) {
             Ok(events) => events,
-            Err(e) => {
-                return Err(QueryError::BucketQueryError(format!(
-                    "Failed to query bucket: {:?}",
-                    e
-                )))
-            }
+            Err(e) => return Err(datastore_error("Failed to query bucket", e)),
         };
         let mut ret = Vec::new();
         for event in events {
@@ -180,12 +278,7 @@ mod qfunctions {
         let mut bucketnames: Vec<DataType> = Vec::new();
         let buckets = match ds.get_buckets() {
             Ok(buckets) => buckets,
-            Err(e) => {
-                return Err(QueryError::BucketQueryError(format!(
-                    "Failed to query bucket names: {:?}",
-                    e
-                )))
-            }
+            Err(e) => return Err(datastore_error("Failed to query bucket names", e)),
         };
         for bucketname in buckets.keys() {
             bucketnames.push(DataType::String(bucketname.to_string()));
@@ -208,12 +301,7 @@ mod qfunctions {
 
         let buckets = match ds.get_buckets() {
             Ok(buckets) => buckets,
-            Err(e) => {
-                return Err(QueryError::BucketQueryError(format!(
-                    "Failed to query bucket names: {:?}",
-                    e
-                )))
-            }
+            Err(e) => return Err(datastore_error("Failed to query bucket names", e)),
         };
         let bucketname =
             match aw_transform::find_bucket(&bucket_filter, &hostname_filter, buckets.values()) {
@@ -407,6 +495,77 @@ mod qfunctions {
         Ok(DataType::List(merged_tagged_events))
     }
 
+    pub fn group_by(
+        args: Vec<DataType>,
+        _env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        validate::args_length(&args, 3)?;
+        let events: Vec<Event> = (&args[0]).try_into()?;
+        let keys: Vec<String> = (&args[1]).try_into()?;
+        let aggregations: Vec<(String, Aggregation)> = (&args[2]).try_into()?;
+        for (name, _) in aggregations.iter() {
+            if keys.contains(name) {
+                return Err(QueryError::InvalidFunctionParameters(format!(
+                    "Aggregation {} has the same name as a key",
+                    name
+                )));
+            }
+        }
+
+        let groups = aw_transform::group_by(events, &keys, &aggregations);
+        Ok(DataType::List(
+            groups
+                .into_iter()
+                .map(|group| DataType::from(&Value::Object(group)))
+                .collect(),
+        ))
+    }
+
+    pub fn histogram(
+        args: Vec<DataType>,
+        env: &VarEnv,
+        _ds: &Datastore,
+    ) -> Result<DataType, QueryError> {
+        // typecheck
+        if args.len() < 2 || args.len() > 4 {
+            return Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected 2 to 4 parameters in function, got {}",
+                args.len()
+            )));
+        }
+        let events: Vec<Event> = (&args[0]).try_into()?;
+        let unit: TimeUnit = (&args[1]).try_into()?;
+        let mut calendar = validate::get_calendar(env)?;
+        if let Some(timezone) = args.get(2) {
+            calendar.timezone = validate::timezone(timezone)?;
+        }
+        let key: Option<String> = match args.get(3) {
+            Some(key) => Some(key.try_into()?),
+            None => None,
+        };
+
+        let bins = aw_transform::histogram(events, unit, &calendar, key.as_deref());
+        let mut histogram = Vec::new();
+        for bin in bins {
+            let mut dict = HashMap::new();
+            dict.insert(
+                "timestamp".to_string(),
+                DataType::String(bin.start.to_rfc3339()),
+            );
+            dict.insert(
+                "duration".to_string(),
+                DataType::Number((bin.duration.num_milliseconds() as f64) / 1000.0),
+            );
+            if let (Some(key), Some(value)) = (&key, &bin.value) {
+                dict.insert(key.clone(), value.into());
+            }
+            histogram.push(DataType::Dict(dict));
+        }
+        Ok(DataType::List(histogram))
+    }
+
     pub fn chunk_events_by_key(
         args: Vec<DataType>,
         _env: &VarEnv,
@@ -581,8 +740,12 @@ mod qfunctions {
 }
 
 mod validate {
+    use std::convert::TryInto;
+
     use crate::{DataType, QueryError, VarEnv};
+    use aw_datastore::EventFilter;
     use aw_models::TimeInterval;
+    use aw_transform::{Calendar, Timezone};
 
     pub fn args_length(args: &[DataType], len: usize) -> Result<(), QueryError> {
         if args.len() != len {
@@ -619,4 +782,59 @@ mod validate {
             ))),
         }
     }
+
+    /// Reads the TIMEZONE and DAY_START variables, which decide where days begin
+    pub fn get_calendar(env: &VarEnv) -> Result<Calendar, QueryError> {
+        let get_string = |name: &str| match env.get(name) {
+            Some(DataType::String(s)) => Ok(s),
+            Some(_) => Err(QueryError::InvalidType(format!(
+                "{} is not of type string!",
+                name
+            ))),
+            None => Err(QueryError::VariableNotDefined(name.to_string())),
+        };
+        let timezone = get_string("TIMEZONE")?;
+        let day_start = get_string("DAY_START")?;
+        Calendar::new(timezone, day_start).map_err(QueryError::InvalidType)
+    }
+
+    /// Parses a timezone such as "Europe/Stockholm" or an offset from UTC such as "+02:00"
+    pub fn timezone(timezone: &DataType) -> Result<Timezone, QueryError> {
+        match timezone {
+            DataType::String(s) => s.parse().map_err(QueryError::InvalidFunctionParameters),
+            _ => Err(QueryError::InvalidFunctionParameters(format!(
+                "Expected timezone, got {:?}",
+                timezone
+            ))),
+        }
+    }
+
+    /// Parses the options of query_bucket, such as {"filter_keyvals": {"app": ["Firefox"]}}
+    pub fn query_bucket_filter(options: &DataType) -> Result<EventFilter, QueryError> {
+        let options = match options {
+            DataType::Dict(options) => options,
+            _ => {
+                return Err(QueryError::InvalidFunctionParameters(
+                    "The options of query_bucket need to be a dict".to_string(),
+                ))
+            }
+        };
+        let mut filter = EventFilter::default();
+        for (name, value) in options.iter() {
+            match (name.as_str(), value) {
+                ("filter_keyvals", DataType::Dict(keyvals)) => {
+                    for (key, vals) in keyvals.iter() {
+                        filter.keyvals.push((key.clone(), vals.try_into()?));
+                    }
+                }
+                _ => {
+                    return Err(QueryError::InvalidFunctionParameters(format!(
+                        "Invalid query_bucket option {}: {:?}",
+                        name, value
+                    )))
+                }
+            }
+        }
+        Ok(filter)
+    }
 }
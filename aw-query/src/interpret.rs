@@ -1,19 +1,176 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Instant;
 
 use crate::functions;
+use crate::profile;
 
 use aw_datastore::Datastore;
 use aw_models::TimeInterval;
+use aw_transform::Calendar;
 
+use crate::ast;
 use crate::ast::*;
 use crate::DataType;
+use crate::QueryDependencies;
 use crate::QueryError;
+use crate::QueryLimit;
+use crate::QueryLimits;
+use crate::QueryProfile;
+use crate::Span;
 
 pub type VarEnv = HashMap<String, DataType>;
 
-fn init_env(ti: &TimeInterval) -> VarEnv {
+/* Max depth of nested calls to user-defined functions, to stop runaway recursion */
+static MAX_CALL_DEPTH: usize = 32;
+
+/* State of a query shared by all its scopes */
+struct QueryContext {
+    deps: RefCell<QueryDependencies>,
+    limits: QueryLimits,
+    started: Instant,
+    events_loaded: Cell<usize>,
+    // Only set when profiling
+    profile: Option<RefCell<QueryProfile>>,
+}
+
+impl QueryContext {
+    fn new(limits: &QueryLimits, profile: bool) -> QueryContext {
+        QueryContext {
+            deps: RefCell::new(QueryDependencies::default()),
+            limits: limits.clone(),
+            started: Instant::now(),
+            events_loaded: Cell::new(0),
+            profile: if profile {
+                Some(RefCell::new(QueryProfile::default()))
+            } else {
+                None
+            },
+        }
+    }
+
+    fn check_timeout(&self) -> Result<(), QueryError> {
+        match self.limits.timeout {
+            Some(timeout) if self.started.elapsed() >= timeout => Err(QueryError::LimitExceeded(
+                QueryLimit::Timeout,
+                format!("Query ran for longer than the limit of {:?}", timeout),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of events the query can still load, None if it is not limited
+    fn remaining_events(&self) -> Option<usize> {
+        self.limits
+            .max_events
+            .map(|max_events| max_events.saturating_sub(self.events_loaded.get()))
+    }
+
+    fn add_loaded_events(&self, count: usize) -> Result<(), QueryError> {
+        let events_loaded = self.events_loaded.get() + count;
+        self.events_loaded.set(events_loaded);
+        match self.limits.max_events {
+            Some(max_events) if events_loaded > max_events => Err(QueryError::LimitExceeded(
+                QueryLimit::MaxEvents,
+                format!(
+                    "Query loaded {} events, more than the limit of {}",
+                    events_loaded, max_events
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_result_size(&self, result: &DataType) -> Result<(), QueryError> {
+        if let Some(max_result_size) = self.limits.max_result_size {
+            let size = result_size(result);
+            if size > max_result_size {
+                return Err(QueryError::LimitExceeded(
+                    QueryLimit::MaxResultSize,
+                    format!(
+                        "Query result has {} values, more than the limit of {}",
+                        size, max_result_size
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/* Number of values in a result, not counting the lists and dicts which contain them */
+fn result_size(data: &DataType) -> usize {
+    match data {
+        DataType::List(l) => l.iter().map(result_size).sum(),
+        DataType::Dict(d) => d.values().map(result_size).sum(),
+        _ => 1,
+    }
+}
+
+/*
+ * Variables visible to the code being interpreted
+ *
+ * The program itself runs in the global scope, calls to user-defined functions get a new scope
+ * with only their parameters. Functions can read global variables (which includes all builtin
+ * and user-defined functions, so functions can call themselves) but not the variables of their
+ * caller, and all assignments in a function are local to that call.
+ */
+pub struct Scope<'a> {
+    vars: VarEnv,
+    globals: Option<&'a VarEnv>,
+    ctx: &'a QueryContext,
+    depth: usize,
+    // Set when a return statement has been executed in a function
+    returned: bool,
+}
+
+impl<'a> Scope<'a> {
+    fn new_global(vars: VarEnv, ctx: &'a QueryContext) -> Scope<'a> {
+        Scope {
+            vars,
+            globals: None,
+            ctx,
+            depth: 0,
+            returned: false,
+        }
+    }
+
+    fn get(&self, var: &str) -> Option<&DataType> {
+        match self.vars.get(var) {
+            Some(val) => Some(val),
+            None => self.globals.and_then(|globals| globals.get(var)),
+        }
+    }
+
+    fn insert(&mut self, var: String, val: DataType) {
+        self.vars.insert(var, val);
+    }
+
+    fn globals(&self) -> &VarEnv {
+        self.globals.unwrap_or(&self.vars)
+    }
+
+    fn is_function(&self) -> bool {
+        self.globals.is_some()
+    }
+}
+
+fn init_env(ti: &TimeInterval, calendar: &Calendar) -> VarEnv {
     let mut env = HashMap::new();
     env.insert("TIMEINTERVAL".to_string(), DataType::String(ti.to_string()));
+    env.insert(
+        "TIMEZONE".to_string(),
+        DataType::String(calendar.timezone.to_string()),
+    );
+    let day_start = calendar.day_start.num_minutes();
+    env.insert(
+        "DAY_START".to_string(),
+        DataType::String(format!("{:02}:{:02}", day_start / 60, day_start % 60)),
+    );
     functions::fill_env(&mut env);
     env
 }
@@ -21,225 +178,525 @@ fn init_env(ti: &TimeInterval) -> VarEnv {
 pub fn interpret_prog(
     p: Program,
     ti: &TimeInterval,
+    calendar: &Calendar,
     ds: &Datastore,
-) -> Result<DataType, QueryError> {
-    let mut env = init_env(ti);
+    limits: &QueryLimits,
+    profile: bool,
+) -> Result<(DataType, QueryDependencies, Option<QueryProfile>), QueryError> {
+    let ctx = QueryContext::new(limits, profile);
+    let mut env = Scope::new_global(init_env(ti, calendar), &ctx);
     for expr in p.stmts {
+        ctx.check_timeout()?;
+        let span = expr.span;
+        let started = Instant::now();
         interpret_expr(&mut env, ds, expr)?;
+        if let Some(profile) = &ctx.profile {
+            profile
+                .borrow_mut()
+                .record_statement(span, started.elapsed());
+        }
     }
-    match env.remove("RETURN") {
-        Some(ret) => Ok(ret),
-        None => Err(QueryError::EmptyQuery()),
-    }
+    let ret = match env.vars.remove("RETURN") {
+        Some(ret) => ret,
+        None => return Err(QueryError::EmptyQuery()),
+    };
+    ctx.check_result_size(&ret)?;
+    Ok((
+        ret,
+        ctx.deps.into_inner(),
+        ctx.profile.map(RefCell::into_inner),
+    ))
 }
 
-fn interpret_expr(
-    env: &mut HashMap<String, DataType>,
-    ds: &Datastore,
-    expr: Expr,
-) -> Result<DataType, QueryError> {
+// Larger cases are kept in separate functions, as interpret_expr recurses for every level of
+// the AST and its stack frame would otherwise limit how deep user-defined functions can recurse
+fn interpret_expr(env: &mut Scope, ds: &Datastore, expr: Expr) -> Result<DataType, QueryError> {
     use crate::ast::Expr_::*;
-    match expr.node {
-        Add(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let res = match a_res {
-                DataType::Number(n1) => match b_res {
-                    DataType::Number(n2) => DataType::Number(n1 + n2),
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a number with a number!"
-                                .to_string(),
-                        ))
-                    }
-                },
-                DataType::List(mut l1) => match b_res {
-                    DataType::List(mut l2) => {
-                        l1.append(&mut l2);
-                        DataType::List(l1)
-                    }
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a list with a list!".to_string(),
-                        ))
-                    }
-                },
-                DataType::String(s1) => match b_res {
-                    DataType::String(s2) => {
-                        let mut new_string = s1;
-                        new_string.push_str(&s2);
-                        DataType::String(new_string)
-                    }
-                    _ => {
-                        return Err(QueryError::InvalidType(
-                            "Cannot use + on something that is not a list with a list!".to_string(),
-                        ))
-                    }
-                },
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot use + on something that is not a number, list or string!"
-                            .to_string(),
-                    ))
-                }
-            };
-            Ok(res)
-        }
-        Sub(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num - b_num))
-        }
-        Mul(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num * b_num))
-        }
-        Div(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            if b_num == 0.0 {
+    let span = expr.span;
+    let res = match expr.node {
+        Add(a, b) => interpret_add(env, ds, *a, *b),
+        Sub(a, b) => interpret_numbers(env, ds, *a, *b).map(|(a, b)| DataType::Number(a - b)),
+        Mul(a, b) => interpret_numbers(env, ds, *a, *b).map(|(a, b)| DataType::Number(a * b)),
+        Div(a, b) => interpret_numbers(env, ds, *a, *b).and_then(|(a, b)| {
+            if b == 0.0 {
                 return Err(QueryError::MathError(
                     "Tried to divide by zero!".to_string(),
                 ));
             }
-            Ok(DataType::Number(a_num / b_num))
-        }
-        Mod(a, b) => {
-            let a_res = interpret_expr(env, ds, *a)?;
-            let b_res = interpret_expr(env, ds, *b)?;
-            let a_num = match a_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            let b_num = match b_res {
-                DataType::Number(n) => n,
-                _ => {
-                    return Err(QueryError::InvalidType(
-                        "Cannot sub something that is not a number!".to_string(),
-                    ))
-                }
-            };
-            Ok(DataType::Number(a_num % b_num))
-        }
-        Equal(lhs, rhs) => {
-            let lhs_res = interpret_expr(env, ds, *lhs)?;
-            let rhs_res = interpret_expr(env, ds, *rhs)?;
-            Ok(DataType::Bool(lhs_res.query_eq(&rhs_res)?))
-        }
-        Assign(var, b) => {
-            let val = interpret_expr(env, ds, *b)?;
-            env.insert(var, val);
-            Ok(DataType::None())
-        }
+            Ok(DataType::Number(a / b))
+        }),
+        Mod(a, b) => interpret_numbers(env, ds, *a, *b).map(|(a, b)| DataType::Number(a % b)),
+        Equal(lhs, rhs) => interpret_eq(env, ds, *lhs, *rhs).map(DataType::Bool),
+        NotEqual(lhs, rhs) => interpret_eq(env, ds, *lhs, *rhs).map(|eq| DataType::Bool(!eq)),
+        Less(lhs, rhs) => interpret_cmp(env, ds, *lhs, *rhs)
+            .map(|ordering| DataType::Bool(ordering == Ordering::Less)),
+        LessEqual(lhs, rhs) => interpret_cmp(env, ds, *lhs, *rhs)
+            .map(|ordering| DataType::Bool(ordering != Ordering::Greater)),
+        Greater(lhs, rhs) => interpret_cmp(env, ds, *lhs, *rhs)
+            .map(|ordering| DataType::Bool(ordering == Ordering::Greater)),
+        GreaterEqual(lhs, rhs) => interpret_cmp(env, ds, *lhs, *rhs)
+            .map(|ordering| DataType::Bool(ordering != Ordering::Less)),
+        And(lhs, rhs) => interpret_and(env, ds, *lhs, *rhs),
+        Or(lhs, rhs) => interpret_or(env, ds, *lhs, *rhs),
+        Not(e) => interpret_bool(env, ds, *e, "not").map(|b| DataType::Bool(!b)),
+        Assign(var, b) => interpret_assign(env, ds, var, *b),
         // FIXME: avoid clone, it's slow
         Var(var) => match env.get(&var) {
             Some(v) => Ok(v.clone()),
             None => Err(QueryError::VariableNotDefined(var.to_string())),
         },
+        Attribute(e, attr) => interpret_expr(env, ds, *e).and_then(|val| val.query_attr(&attr)),
+        Index(e, index) => interpret_index(env, ds, *e, *index),
         Bool(lit) => Ok(DataType::Bool(lit)),
         Number(lit) => Ok(DataType::Number(lit)),
         String(litstr) => Ok(DataType::String(litstr)),
         Return(e) => {
-            let val = interpret_expr(env, ds, *e)?;
             // TODO: Once RETURN is deprecated we can fix this
-            env.insert("RETURN".to_string(), val);
+            let res = interpret_assign(env, ds, "RETURN".to_string(), *e);
+            if env.is_function() {
+                env.returned = true;
+            }
+            res
+        }
+        If(ifs) => interpret_if(env, ds, ifs),
+        For(var, list, body) => interpret_for(env, ds, var, *list, body),
+        Function(fname, e) => interpret_function_call(env, ds, fname, *e, span),
+        FunctionDef(fun) => {
+            env.insert(fun.name.clone(), DataType::UserFunction(fun));
             Ok(DataType::None())
         }
-        If(ifs) => {
-            for (cond, block) in ifs {
-                let c = interpret_expr(env, ds, *cond)?;
-                if c.query_eq(&DataType::Bool(true))? {
-                    for expr in block {
-                        interpret_expr(env, ds, expr)?;
-                    }
+        List(list) => list
+            .into_iter()
+            .map(|entry| interpret_expr(env, ds, entry))
+            .collect::<Result<Vec<_>, _>>()
+            .map(DataType::List),
+        ListComprehension(item, var, list, cond) => {
+            interpret_list_comprehension(env, ds, *item, var, *list, cond.map(|cond| *cond))
+        }
+        Dict(d) => d
+            .into_iter()
+            .map(|(key, val)| Ok((key, interpret_expr(env, ds, val)?)))
+            .collect::<Result<HashMap<_, _>, _>>()
+            .map(DataType::Dict),
+    };
+    // Errors point at the innermost expression which failed
+    res.map_err(|err| err.with_span(span))
+}
+
+fn interpret_assign(
+    env: &mut Scope,
+    ds: &Datastore,
+    var: String,
+    e: Expr,
+) -> Result<DataType, QueryError> {
+    let val = interpret_expr(env, ds, e)?;
+    env.insert(var, val);
+    Ok(DataType::None())
+}
+
+fn interpret_index(
+    env: &mut Scope,
+    ds: &Datastore,
+    e: Expr,
+    index: Expr,
+) -> Result<DataType, QueryError> {
+    let val = interpret_expr(env, ds, e)?;
+    let index = interpret_expr(env, ds, index)?;
+    val.query_index(&index)
+}
+
+fn interpret_eq(env: &mut Scope, ds: &Datastore, lhs: Expr, rhs: Expr) -> Result<bool, QueryError> {
+    let lhs_res = interpret_expr(env, ds, lhs)?;
+    let rhs_res = interpret_expr(env, ds, rhs)?;
+    lhs_res.query_eq(&rhs_res)
+}
+
+// and/or short-circuit, so the right hand side is only evaluated if needed
+fn interpret_and(
+    env: &mut Scope,
+    ds: &Datastore,
+    lhs: Expr,
+    rhs: Expr,
+) -> Result<DataType, QueryError> {
+    if !interpret_bool(env, ds, lhs, "and")? {
+        return Ok(DataType::Bool(false));
+    }
+    Ok(DataType::Bool(interpret_bool(env, ds, rhs, "and")?))
+}
+
+fn interpret_or(
+    env: &mut Scope,
+    ds: &Datastore,
+    lhs: Expr,
+    rhs: Expr,
+) -> Result<DataType, QueryError> {
+    if interpret_bool(env, ds, lhs, "or")? {
+        return Ok(DataType::Bool(true));
+    }
+    Ok(DataType::Bool(interpret_bool(env, ds, rhs, "or")?))
+}
+
+fn interpret_add(
+    env: &mut Scope,
+    ds: &Datastore,
+    a: Expr,
+    b: Expr,
+) -> Result<DataType, QueryError> {
+    let a_res = interpret_expr(env, ds, a)?;
+    let b_res = interpret_expr(env, ds, b)?;
+    let res = match a_res {
+        DataType::Number(n1) => match b_res {
+            DataType::Number(n2) => DataType::Number(n1 + n2),
+            _ => {
+                return Err(QueryError::InvalidType(
+                    "Cannot use + on something that is not a number with a number!".to_string(),
+                ))
+            }
+        },
+        DataType::List(mut l1) => match b_res {
+            DataType::List(mut l2) => {
+                l1.append(&mut l2);
+                DataType::List(l1)
+            }
+            _ => {
+                return Err(QueryError::InvalidType(
+                    "Cannot use + on something that is not a list with a list!".to_string(),
+                ))
+            }
+        },
+        DataType::String(s1) => match b_res {
+            DataType::String(s2) => {
+                let mut new_string = s1;
+                new_string.push_str(&s2);
+                DataType::String(new_string)
+            }
+            _ => {
+                return Err(QueryError::InvalidType(
+                    "Cannot use + on something that is not a list with a list!".to_string(),
+                ))
+            }
+        },
+        _ => {
+            return Err(QueryError::InvalidType(
+                "Cannot use + on something that is not a number, list or string!".to_string(),
+            ))
+        }
+    };
+    Ok(res)
+}
+
+fn interpret_numbers(
+    env: &mut Scope,
+    ds: &Datastore,
+    a: Expr,
+    b: Expr,
+) -> Result<(f64, f64), QueryError> {
+    let a_res = interpret_expr(env, ds, a)?;
+    let b_res = interpret_expr(env, ds, b)?;
+    match (a_res, b_res) {
+        (DataType::Number(a_num), DataType::Number(b_num)) => Ok((a_num, b_num)),
+        _ => Err(QueryError::InvalidType(
+            "Cannot sub something that is not a number!".to_string(),
+        )),
+    }
+}
+
+fn interpret_if(
+    env: &mut Scope,
+    ds: &Datastore,
+    ifs: Vec<(Box<Expr>, Vec<Expr>)>,
+) -> Result<DataType, QueryError> {
+    for (cond, block) in ifs {
+        let c = interpret_expr(env, ds, *cond)?;
+        if c.query_eq(&DataType::Bool(true))? {
+            for expr in block {
+                interpret_expr(env, ds, expr)?;
+                if env.returned {
                     break;
                 }
             }
-            Ok(DataType::None())
+            break;
         }
-        Function(fname, e) => {
-            let args = match interpret_expr(env, ds, *e)? {
-                DataType::List(l) => l,
-                _ => unreachable!(),
-            };
-            let var = match env.get(&fname[..]) {
-                Some(v) => v,
-                None => return Err(QueryError::VariableNotDefined(fname.clone())),
-            };
-            let (_name, fun) = match var {
-                DataType::Function(name, fun) => (name, fun),
-                _data => return Err(QueryError::InvalidType(fname.to_string())),
-            };
-            fun(args, env, ds)
-        }
-        List(list) => {
-            let mut l = Vec::new();
-            for entry in list {
-                let res = interpret_expr(env, ds, entry)?;
-                l.push(res);
+    }
+    Ok(DataType::None())
+}
+
+fn interpret_iterable(
+    env: &mut Scope,
+    ds: &Datastore,
+    e: Expr,
+) -> Result<Vec<DataType>, QueryError> {
+    match interpret_expr(env, ds, e)? {
+        DataType::List(l) => Ok(l),
+        _ => Err(QueryError::InvalidType(
+            "Cannot iterate over something that is not a list!".to_string(),
+        )),
+    }
+}
+
+fn interpret_for(
+    env: &mut Scope,
+    ds: &Datastore,
+    var: String,
+    list: Expr,
+    body: Vec<Expr>,
+) -> Result<DataType, QueryError> {
+    // Like assignments, the loop variable is still set after the loop
+    for val in interpret_iterable(env, ds, list)? {
+        env.ctx.check_timeout()?;
+        env.insert(var.clone(), val);
+        for expr in body.iter() {
+            // FIXME: avoid clone of the loop body for every iteration
+            interpret_expr(env, ds, expr.clone())?;
+            if env.returned {
+                return Ok(DataType::None());
             }
-            Ok(DataType::List(l))
         }
-        Dict(d) => {
-            let mut dict = HashMap::new();
-            for (key, val_uninterpreted) in d {
-                let val = interpret_expr(env, ds, val_uninterpreted)?;
-                dict.insert(key.clone(), val);
+    }
+    Ok(DataType::None())
+}
+
+fn interpret_list_comprehension(
+    env: &mut Scope,
+    ds: &Datastore,
+    item: Expr,
+    var: String,
+    list: Expr,
+    cond: Option<Expr>,
+) -> Result<DataType, QueryError> {
+    let list = interpret_iterable(env, ds, list)?;
+    // Unlike in for loops the variable is only visible inside the comprehension
+    let shadowed = env.vars.remove(&var);
+    let res = comprehend(env, ds, &item, &var, list, cond.as_ref());
+    match shadowed {
+        Some(val) => env.insert(var, val),
+        None => {
+            env.vars.remove(&var);
+        }
+    }
+    res.map(DataType::List)
+}
+
+fn comprehend(
+    env: &mut Scope,
+    ds: &Datastore,
+    item: &Expr,
+    var: &str,
+    list: Vec<DataType>,
+    cond: Option<&Expr>,
+) -> Result<Vec<DataType>, QueryError> {
+    let mut res = Vec::new();
+    for val in list {
+        env.ctx.check_timeout()?;
+        env.insert(var.to_string(), val);
+        if let Some(cond) = cond {
+            let c = interpret_expr(env, ds, cond.clone())?;
+            if !c.query_eq(&DataType::Bool(true))? {
+                continue;
             }
-            Ok(DataType::Dict(dict))
         }
+        res.push(interpret_expr(env, ds, item.clone())?);
+    }
+    Ok(res)
+}
+
+fn interpret_function_call(
+    env: &mut Scope,
+    ds: &Datastore,
+    fname: String,
+    e: Expr,
+    span: Span,
+) -> Result<DataType, QueryError> {
+    if is_filtered_query_bucket(env, &fname, &e) {
+        return interpret_filtered_query_bucket(env, ds, e);
+    }
+    let args = match interpret_expr(env, ds, e)? {
+        DataType::List(l) => l,
+        _ => unreachable!(),
+    };
+    let var = match env.get(&fname[..]) {
+        Some(v) => v,
+        None => return Err(QueryError::VariableNotDefined(fname.clone())),
+    };
+    env.ctx.check_timeout()?;
+    match var {
+        DataType::Function(name, fun) => call_builtin(env, ds, name, *fun, args, span),
+        DataType::UserFunction(fun) => {
+            let fun = fun.clone();
+            match call_user_function(env, ds, &fun, args) {
+                Ok(val) => Ok(val),
+                // Point at the innermost call which failed
+                Err(err @ QueryError::FunctionCallError(..)) => Err(err),
+                Err(err) => Err(QueryError::FunctionCallError(fname, span, Box::new(err))),
+            }
+        }
+        _data => Err(QueryError::InvalidType(fname.to_string())),
+    }
+}
+
+fn is_builtin(env: &Scope, fname: &str) -> bool {
+    matches!(env.get(fname), Some(DataType::Function(name, _)) if name == fname)
+}
+
+/*
+ * Whether the call is filter_keyvals(query_bucket(bucket_id), key, values), which is run as
+ * query_bucket(bucket_id, {"filter_keyvals": {key: values}}) instead so that the events get
+ * filtered by the datastore rather than all of them being loaded.
+ */
+fn is_filtered_query_bucket(env: &Scope, fname: &str, args: &Expr) -> bool {
+    let args = match &args.node {
+        Expr_::List(args) if fname == "filter_keyvals" && args.len() == 3 => args,
+        _ => return false,
+    };
+    let is_query_bucket_call = match &args[0].node {
+        Expr_::Function(inner, inner_args) if inner == "query_bucket" => {
+            matches!(&inner_args.node, Expr_::List(inner_args) if inner_args.len() == 1)
+        }
+        _ => false,
+    };
+    // The functions could have been redefined
+    is_query_bucket_call && is_builtin(env, "filter_keyvals") && is_builtin(env, "query_bucket")
+}
+
+fn interpret_filtered_query_bucket(
+    env: &mut Scope,
+    ds: &Datastore,
+    args: Expr,
+) -> Result<DataType, QueryError> {
+    let mut args = match args.node {
+        Expr_::List(args) => args,
+        _ => unreachable!(),
+    };
+    let vals = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    let query_bucket = args.pop().unwrap();
+    let (query_bucket_args, query_bucket_span) = match query_bucket.node {
+        Expr_::Function(_, inner_args) => (*inner_args, query_bucket.span),
+        _ => unreachable!(),
+    };
+    let mut query_bucket_args = match interpret_expr(env, ds, query_bucket_args)? {
+        DataType::List(l) => l,
+        _ => unreachable!(),
+    };
+    let key: String = (&interpret_expr(env, ds, key)?).try_into()?;
+    let vals = interpret_expr(env, ds, vals)?;
+    let mut keyvals = HashMap::new();
+    keyvals.insert(key, vals);
+    let mut options = HashMap::new();
+    options.insert("filter_keyvals".to_string(), DataType::Dict(keyvals));
+    query_bucket_args.push(DataType::Dict(options));
+    let fun = match env.get("query_bucket") {
+        Some(DataType::Function(_, fun)) => *fun,
+        _ => unreachable!(),
+    };
+    call_builtin(
+        env,
+        ds,
+        "query_bucket",
+        fun,
+        query_bucket_args,
+        query_bucket_span,
+    )
+    .map_err(|err| err.with_span(query_bucket_span))
+}
+
+fn call_builtin(
+    env: &Scope,
+    ds: &Datastore,
+    name: &str,
+    fun: functions::QueryFn,
+    args: Vec<DataType>,
+    span: Span,
+) -> Result<DataType, QueryError> {
+    functions::record_dependencies(name, &args, &mut env.ctx.deps.borrow_mut());
+    if let Some(access) = &env.ctx.limits.buckets {
+        functions::check_access(name, &args, access)?;
+    }
+    let events_in = match env.ctx.profile {
+        Some(_) => args.iter().map(profile::count_events).sum(),
+        None => 0,
+    };
+    let started = Instant::now();
+    // Loading one event more than the limit allows is enough to notice that it is exceeded,
+    // without loading all the events of the bucket
+    let max_events = env.ctx.remaining_events().map(|remaining| remaining + 1);
+    let res = functions::call_with_max_events(name, fun, args, env.globals(), ds, max_events)?;
+    env.ctx
+        .add_loaded_events(functions::loaded_events(name, &res))?;
+    if let Some(profile) = &env.ctx.profile {
+        profile.borrow_mut().record_call(
+            span,
+            name,
+            started.elapsed(),
+            events_in,
+            profile::count_events(&res),
+        );
+    }
+    Ok(res)
+}
+
+fn call_user_function(
+    env: &Scope,
+    ds: &Datastore,
+    fun: &Arc<ast::FunctionDef>,
+    args: Vec<DataType>,
+) -> Result<DataType, QueryError> {
+    if args.len() != fun.params.len() {
+        return Err(QueryError::InvalidFunctionParameters(format!(
+            "Function {} takes {} parameters, {} given",
+            fun.name,
+            fun.params.len(),
+            args.len()
+        )));
+    }
+    if env.depth >= MAX_CALL_DEPTH {
+        return Err(QueryError::RecursionLimitExceeded(format!(
+            "Calls to {} are nested deeper than the limit of {}",
+            fun.name, MAX_CALL_DEPTH
+        )));
+    }
+    let mut scope = Scope {
+        vars: fun.params.iter().cloned().zip(args).collect(),
+        globals: Some(env.globals()),
+        ctx: env.ctx,
+        depth: env.depth + 1,
+        returned: false,
+    };
+    for expr in fun.body.iter() {
+        // FIXME: avoid clone of the function body for every call
+        interpret_expr(&mut scope, ds, expr.clone())?;
+        if scope.returned {
+            break;
+        }
+    }
+    match scope.vars.remove("RETURN") {
+        Some(ret) => Ok(ret),
+        None => Ok(DataType::None()),
+    }
+}
+
+fn interpret_cmp(
+    env: &mut Scope,
+    ds: &Datastore,
+    lhs: Expr,
+    rhs: Expr,
+) -> Result<Ordering, QueryError> {
+    let lhs_res = interpret_expr(env, ds, lhs)?;
+    let rhs_res = interpret_expr(env, ds, rhs)?;
+    lhs_res.query_cmp(&rhs_res)
+}
+
+fn interpret_bool(
+    env: &mut Scope,
+    ds: &Datastore,
+    expr: Expr,
+    op: &str,
+) -> Result<bool, QueryError> {
+    match interpret_expr(env, ds, expr)? {
+        DataType::Bool(b) => Ok(b),
+        _ => Err(QueryError::InvalidType(format!(
+            "Cannot use {} on something that is not a bool!",
+            op
+        ))),
     }
 }
@@ -1,4 +1,5 @@
 use plex::lexer;
+use serde::Serialize;
 
 #[derive(Debug, Clone)]
 pub enum Token {
@@ -8,6 +9,12 @@ pub enum Token {
     ElseIf,
     Else,
     Return,
+    Def,
+    For,
+    In,
+    And,
+    Or,
+    Not,
 
     Bool(bool),
     Number(f64),
@@ -18,6 +25,11 @@ pub enum Token {
     Slash,
     Percent,
     Equals,
+    NotEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
     Assign,
     LParen,
     RParen,
@@ -27,6 +39,7 @@ pub enum Token {
     RBrace,
     Comma,
     Colon,
+    Dot,
     Semi,
 
     Whitespace,
@@ -46,6 +59,12 @@ lexer! {
     r#"elif"# => (Token::ElseIf, text),
     r#"else"# => (Token::Else, text),
     r#"return"# => (Token::Return, text),
+    r#"def"# => (Token::Def, text),
+    r#"for"# => (Token::For, text),
+    r#"in"# => (Token::In, text),
+    r#"and"# => (Token::And, text),
+    r#"or"# => (Token::Or, text),
+    r#"not"# => (Token::Not, text),
 
     r#"true"# => (Token::Bool(true), text),
     r#"false"# => (Token::Bool(false), text),
@@ -68,6 +87,11 @@ lexer! {
     r#"[a-zA-Z_][a-zA-Z0-9_]*"# => (Token::Ident(text.to_owned()), text),
 
     r#"=="# => (Token::Equals, text),
+    r#"!="# => (Token::NotEquals, text),
+    r#"<="# => (Token::LessEquals, text),
+    r#">="# => (Token::GreaterEquals, text),
+    r#"<"# => (Token::Less, text),
+    r#">"# => (Token::Greater, text),
     r#"="# => (Token::Assign, text),
     r#"\+"# => (Token::Plus, text),
     r#"-"# => (Token::Minus, text),
@@ -82,6 +106,7 @@ lexer! {
     r#"\}"# => (Token::RBrace, text),
     r#","# => (Token::Comma, text),
     r#":"# => (Token::Colon, text),
+    r#"\."# => (Token::Dot, text),
     r#";"# => (Token::Semi, text),
 }
 
@@ -101,22 +126,41 @@ impl<'a> Lexer<'a> {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct Span {
     pub lo: usize,
     pub hi: usize,
     pub line: usize,
+    // Counted in characters from the start of the line, starting at 1
+    pub column: usize,
+}
+
+impl Span {
+    /// The code the span covers
+    pub fn snippet<'a>(&self, code: &'a str) -> &'a str {
+        code.get(self.lo..self.hi).unwrap_or("")
+    }
 }
 
 fn span_in(s: &str, t: &str, l: usize) -> Span {
     let lo = s.as_ptr() as usize - t.as_ptr() as usize;
+    let line_start = match t[..lo].rfind('\n') {
+        Some(i) => i + 1,
+        None => 0,
+    };
     Span {
         lo,
         hi: lo + s.len(),
         line: l,
+        column: t[line_start..lo].chars().count() + 1,
     }
 }
 
+/// An empty span at the end of the code, where the parser ran out of tokens
+pub fn end_span(code: &str) -> Span {
+    span_in(&code[code.len()..], code, code.matches('\n').count() + 1)
+}
+
 impl<'a> Iterator for Lexer<'a> {
     type Item = (Token, Span);
     fn next(&mut self) -> Option<(Token, Span)> {
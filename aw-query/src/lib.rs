@@ -3,15 +3,20 @@ extern crate log;
 extern crate serde;
 extern crate serde_json;
 
+use std::collections::HashSet;
 use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 
 use aw_models::TimeInterval;
+use aw_transform::Calendar;
 
 use aw_datastore::Datastore;
 
 pub mod datatype;
 
 mod ast;
+mod cache;
 mod functions;
 mod interpret;
 mod lexer;
@@ -21,12 +26,15 @@ mod lexer;
     unused_braces
 )]
 mod parser;
+mod profile;
 
+pub use crate::cache::QueryCache;
 pub use crate::datatype::DataType;
 pub use crate::interpret::VarEnv;
-
-// TODO: add line numbers to errors
-// (works during lexing, but not during parsing I believe)
+pub use crate::lexer::Span;
+pub use crate::profile::CallProfile;
+pub use crate::profile::QueryProfile;
+pub use crate::profile::StatementProfile;
 
 #[derive(Debug)]
 pub enum QueryError {
@@ -39,26 +47,192 @@ pub enum QueryError {
     MathError(String),
     InvalidType(String),
     InvalidFunctionParameters(String),
+    KeyNotFound(String),
+    IndexOutOfRange(String),
     TimeIntervalError(String),
     BucketQueryError(String),
+    // The datastore failed to read the buckets or events, which isn't the fault of the query
+    DatastoreError(String),
     RegexCompileError(String),
+    RecursionLimitExceeded(String),
+    LimitExceeded(QueryLimit, String),
+    AccessDenied(String),
+    // Error raised by a call to a user-defined function, with the span of the call
+    FunctionCallError(String, Span, Box<QueryError>),
+    // Error with the span of the code which caused it
+    Located(Span, Box<QueryError>),
+}
+
+impl QueryError {
+    /// Adds the span to the error, unless it already points at more specific code
+    pub fn with_span(self, span: Span) -> QueryError {
+        match self {
+            QueryError::Located(..) | QueryError::FunctionCallError(..) => self,
+            err => QueryError::Located(span, Box::new(err)),
+        }
+    }
+
+    /// Span of the code which caused the error, if known
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            QueryError::Located(span, err) | QueryError::FunctionCallError(_, span, err) => {
+                err.span().or(Some(*span))
+            }
+            _ => None,
+        }
+    }
+
+    /// Name of the kind of error, for the error which caused all the others
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QueryError::ParsingError(_) => "ParsingError",
+            QueryError::EmptyQuery() => "EmptyQuery",
+            QueryError::VariableNotDefined(_) => "VariableNotDefined",
+            QueryError::MathError(_) => "MathError",
+            QueryError::InvalidType(_) => "InvalidType",
+            QueryError::InvalidFunctionParameters(_) => "InvalidFunctionParameters",
+            QueryError::KeyNotFound(_) => "KeyNotFound",
+            QueryError::IndexOutOfRange(_) => "IndexOutOfRange",
+            QueryError::TimeIntervalError(_) => "TimeIntervalError",
+            QueryError::BucketQueryError(_) => "BucketQueryError",
+            QueryError::DatastoreError(_) => "DatastoreError",
+            QueryError::RegexCompileError(_) => "RegexCompileError",
+            QueryError::RecursionLimitExceeded(_) => "RecursionLimitExceeded",
+            QueryError::LimitExceeded(..) => "LimitExceeded",
+            QueryError::AccessDenied(_) => "AccessDenied",
+            QueryError::FunctionCallError(_, _, err) | QueryError::Located(_, err) => err.kind(),
+        }
+    }
 }
 
 impl fmt::Display for QueryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            QueryError::Located(span, err) => {
+                write!(f, "{} at line {}, column {}", err, span.line, span.column)
+            }
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Limits on the resources a single query can use, None means unlimited
+#[derive(Debug, Default, Clone)]
+pub struct QueryLimits {
+    /// Max wall clock time a query can run for. It is checked before every function call and
+    /// loop iteration, so a single call to a builtin function can take longer.
+    pub timeout: Option<Duration>,
+    /// Max number of events a query can load with query_bucket, summed over all calls. A call
+    /// loads at most one event more than the query has left, so it fails before all the events
+    /// of a large bucket are loaded.
+    pub max_events: Option<usize>,
+    /// Max size of the result, as the number of values (events, numbers, strings...) in it. It is
+    /// only checked once the query has finished, so it limits the size of what is returned but
+    /// not of the values built while running, the events those are built from are limited by
+    /// max_events.
+    pub max_result_size: Option<usize>,
+    /// Buckets the query is allowed to read. When set, functions which list the buckets can't be
+    /// used, as they would reveal the other buckets.
+    pub buckets: Option<BucketAccess>,
+}
+
+/// Decides which buckets a query is allowed to read, by their ids
+#[derive(Clone)]
+pub struct BucketAccess(Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl BucketAccess {
+    pub fn new(allowed: impl Fn(&str) -> bool + Send + Sync + 'static) -> BucketAccess {
+        BucketAccess(Arc::new(allowed))
+    }
+
+    pub fn allows(&self, bucket_id: &str) -> bool {
+        (self.0)(bucket_id)
     }
 }
 
+impl fmt::Debug for BucketAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BucketAccess")
+    }
+}
+
+/// The limit which was exceeded by a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryLimit {
+    Timeout,
+    MaxEvents,
+    MaxResultSize,
+}
+
+/// The data read by a query, used to know which changes can affect its result
+#[derive(Debug, Default, Clone)]
+pub struct QueryDependencies {
+    /// Buckets whose events were queried
+    pub buckets: HashSet<String>,
+    /// Set if the result depends on which buckets exist
+    pub bucket_list: bool,
+}
+
+/// How a query is run
+#[derive(Debug, Default, Clone)]
+pub struct QueryOptions {
+    /// Decides where days begin, available to the query as the TIMEZONE and DAY_START variables
+    pub calendar: Calendar,
+    pub limits: QueryLimits,
+    /// Time the statements of the query and the builtin functions it calls
+    pub profile: bool,
+}
+
+/// The result of a query along with what was recorded while running it
+#[derive(Debug)]
+pub struct QueryOutput {
+    pub result: DataType,
+    pub dependencies: QueryDependencies,
+    /// Only set when the query was profiled
+    pub profile: Option<QueryProfile>,
+}
+
+/// Runs the query with days starting at midnight UTC, without limits
 pub fn query(code: &str, ti: &TimeInterval, ds: &Datastore) -> Result<DataType, QueryError> {
+    let output = query_with_options(code, ti, ds, &QueryOptions::default())?;
+    Ok(output.result)
+}
+
+pub fn query_with_options(
+    code: &str,
+    ti: &TimeInterval,
+    ds: &Datastore,
+    options: &QueryOptions,
+) -> Result<QueryOutput, QueryError> {
+    let program = parse(code)?;
+    let (result, dependencies, profile) = interpret::interpret_prog(
+        program,
+        ti,
+        &options.calendar,
+        ds,
+        &options.limits,
+        options.profile,
+    )?;
+    Ok(QueryOutput {
+        result,
+        dependencies,
+        profile,
+    })
+}
+
+fn parse(code: &str) -> Result<ast::Program, QueryError> {
     let lexer = lexer::Lexer::new(code);
-    let program = match parser::parse(lexer) {
-        Ok(p) => p,
-        Err(e) => {
-            // TODO: Improve parsing error message
-            warn!("ParsingError: {:?}", e);
-            return Err(QueryError::ParsingError(format!("{:?}", e)));
+    match parser::parse(lexer) {
+        Ok(p) => Ok(p),
+        Err((Some((token, span)), msg)) => {
+            warn!("ParsingError: {} at {:?}", msg, token);
+            let err = QueryError::ParsingError(format!("{}, unexpected {:?}", msg, token));
+            Err(err.with_span(span))
         }
-    };
-    interpret::interpret_prog(program, ti, ds)
+        Err((None, msg)) => {
+            warn!("ParsingError: {} at end of query", msg);
+            let err = QueryError::ParsingError(format!("{}, unexpected end of query", msg));
+            Err(err.with_span(lexer::end_span(code)))
+        }
+    }
 }
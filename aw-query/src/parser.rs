@@ -4,6 +4,7 @@ use crate::lexer::*;
 use plex::parser;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 fn merge_if_vecs(lhs: Expr_, rhs: Expr_) -> Expr_ {
     let mut ifs = match lhs {
@@ -26,6 +27,7 @@ parser! {
             lo: a.lo,
             hi: b.hi,
             line: a.line,
+            column: a.column,
         }
     }
 
@@ -44,9 +46,38 @@ parser! {
 
     statement: Expr {
         ifs[x] => x,
+        def[x] => x,
+        _for[x] => x,
         ret[x] Semi => x,
     }
 
+    def: Expr {
+        Def Ident(name) LParen _params[params] RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::FunctionDef(Arc::new(FunctionDef { name, params, body })),
+        },
+        Def Ident(name) LParen RParen LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::FunctionDef(Arc::new(FunctionDef { name, params: Vec::new(), body })),
+        },
+    }
+
+    _for: Expr {
+        For Ident(var) In expr[list] LBrace statements[body] RBrace => Expr {
+            span: span!(),
+            node: Expr_::For(var, Box::new(list), body),
+        },
+    }
+
+    // String is shadowed by the String token here
+    _params: Vec<std::string::String> {
+        Ident(param) => vec![param],
+        _params[mut params] Comma Ident(param) => {
+            params.push(param);
+            params
+        },
+    }
+
     ifs: Expr {
         _if[l_ifs] => l_ifs,
         _elif[l_ifs] => l_ifs,
@@ -54,7 +85,7 @@ parser! {
     }
 
     _cond_block: Expr {
-        binop[cond] LBrace statements[block] RBrace => Expr {
+        expr[cond] LBrace statements[block] RBrace => Expr {
             span: span!(),
             node: {
                 let mut ifs = Vec::new();
@@ -119,37 +150,98 @@ parser! {
     }
 
     assign: Expr {
-        Ident(var) Assign binop[rhs] => Expr {
+        Ident(var) Assign expr[rhs] => Expr {
             span: span!(),
             node: Expr_::Assign(var, Box::new(rhs)),
         },
+        expr[x] => x
+    }
+
+    expr: Expr {
+        expr[lhs] Or _and[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Or(Box::new(lhs), Box::new(rhs)),
+        },
+        _and[x] => x
+    }
+
+    _and: Expr {
+        _and[lhs] And _not[rhs] => Expr {
+            span: span!(),
+            node: Expr_::And(Box::new(lhs), Box::new(rhs)),
+        },
+        _not[x] => x
+    }
+
+    _not: Expr {
+        Not _not[x] => Expr {
+            span: span!(),
+            node: Expr_::Not(Box::new(x)),
+        },
+        comparison[x] => x
+    }
+
+    comparison: Expr {
+        comparison[lhs] Equals binop[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+        },
+        comparison[lhs] NotEquals binop[rhs] => Expr {
+            span: span!(),
+            node: Expr_::NotEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        comparison[lhs] Less binop[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Less(Box::new(lhs), Box::new(rhs)),
+        },
+        comparison[lhs] LessEquals binop[rhs] => Expr {
+            span: span!(),
+            node: Expr_::LessEqual(Box::new(lhs), Box::new(rhs)),
+        },
+        comparison[lhs] Greater binop[rhs] => Expr {
+            span: span!(),
+            node: Expr_::Greater(Box::new(lhs), Box::new(rhs)),
+        },
+        comparison[lhs] GreaterEquals binop[rhs] => Expr {
+            span: span!(),
+            node: Expr_::GreaterEqual(Box::new(lhs), Box::new(rhs)),
+        },
         binop[x] => x
     }
 
     binop: Expr {
-        binop[lhs] Plus func[rhs] => Expr {
+        binop[lhs] Plus access[rhs] => Expr {
             span: span!(),
             node: Expr_::Add(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Minus func[rhs] => Expr {
+        binop[lhs] Minus access[rhs] => Expr {
             span: span!(),
             node: Expr_::Sub(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Star func[rhs] => Expr {
+        binop[lhs] Star access[rhs] => Expr {
             span: span!(),
             node: Expr_::Mul(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Slash func[rhs] => Expr {
+        binop[lhs] Slash access[rhs] => Expr {
             span: span!(),
             node: Expr_::Div(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Percent func[rhs] => Expr {
+        binop[lhs] Percent access[rhs] => Expr {
             span: span!(),
             node: Expr_::Mod(Box::new(lhs), Box::new(rhs)),
         },
-        binop[lhs] Equals func[rhs] => Expr {
+        access[x] => x
+    }
+
+    // e.data.app, list[0] and dict["key"]
+    access: Expr {
+        access[e] Dot Ident(attr) => Expr {
             span: span!(),
-            node: Expr_::Equal(Box::new(lhs), Box::new(rhs)),
+            node: Expr_::Attribute(Box::new(e), attr),
+        },
+        access[e] LBracket expr[index] RBracket => Expr {
+            span: span!(),
+            node: Expr_::Index(Box::new(e), Box::new(index)),
         },
         func[x] => x
     }
@@ -185,6 +277,19 @@ parser! {
 
     list: Expr {
         LBracket _inner_list[l] RBracket => l,
+        LBracket expr[item] For Ident(var) In expr[list] RBracket => Expr {
+            span: span!(),
+            node: Expr_::ListComprehension(Box::new(item), var, Box::new(list), None),
+        },
+        LBracket expr[item] For Ident(var) In expr[list] If expr[cond] RBracket => Expr {
+            span: span!(),
+            node: Expr_::ListComprehension(
+                Box::new(item),
+                var,
+                Box::new(list),
+                Some(Box::new(cond)),
+            ),
+        },
         LBracket RBracket => Expr {
             span: span!(),
             node: {
@@ -195,7 +300,7 @@ parser! {
     }
 
     _inner_list: Expr {
-        binop[o] => Expr {
+        expr[o] => Expr {
             span: span!(),
             node: {
                 let mut list = Vec::new();
@@ -203,7 +308,7 @@ parser! {
                 Expr_::List(list)
             }
         },
-        _inner_list[l] Comma binop[o] => Expr {
+        _inner_list[l] Comma expr[o] => Expr {
             span: span!(),
             node: {
                 match l.node {
@@ -218,7 +323,7 @@ parser! {
     }
 
     dict: Expr {
-        String(k) Colon binop[v] => Expr {
+        String(k) Colon expr[v] => Expr {
             span: span!(),
             node: {
                 let mut dict = HashMap::new();
@@ -226,7 +331,7 @@ parser! {
                 Expr_::Dict(dict)
             }
         },
-        dict[d] Comma String(k) Colon binop[v] => Expr {
+        dict[d] Comma String(k) Colon expr[v] => Expr {
             span: span!(),
             node: {
                 match d.node {
@@ -258,7 +363,7 @@ parser! {
             span: span!(),
             node: Expr_::String(s),
         },
-        LParen binop[x] RParen => x
+        LParen expr[x] RParen => x
     }
 }
 
@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::DataType;
+use crate::Span;
+
+/// Where the time went while running a query, collected when profiling is enabled
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct QueryProfile {
+    /// Top-level statements, in the order they ran
+    pub statements: Vec<StatementProfile>,
+    /// Calls to builtin functions, one per call in the code. A call which runs multiple times
+    /// (in a loop or a user-defined function) is summed up.
+    pub calls: Vec<CallProfile>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementProfile {
+    pub span: Span,
+    /// In seconds
+    pub duration: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallProfile {
+    pub span: Span,
+    pub function: String,
+    /// Number of times the call ran
+    pub count: usize,
+    /// In seconds
+    pub duration: f64,
+    /// Number of events passed as arguments
+    pub events_in: usize,
+    /// Number of events returned
+    pub events_out: usize,
+}
+
+impl QueryProfile {
+    pub(crate) fn record_statement(&mut self, span: Span, duration: Duration) {
+        self.statements.push(StatementProfile {
+            span,
+            duration: duration.as_secs_f64(),
+        });
+    }
+
+    pub(crate) fn record_call(
+        &mut self,
+        span: Span,
+        function: &str,
+        duration: Duration,
+        events_in: usize,
+        events_out: usize,
+    ) {
+        let same_call =
+            |call: &&mut CallProfile| call.span.lo == span.lo && call.span.hi == span.hi;
+        match self.calls.iter_mut().find(same_call) {
+            Some(call) => {
+                call.count += 1;
+                call.duration += duration.as_secs_f64();
+                call.events_in += events_in;
+                call.events_out += events_out;
+            }
+            None => self.calls.push(CallProfile {
+                span,
+                function: function.to_string(),
+                count: 1,
+                duration: duration.as_secs_f64(),
+                events_in,
+                events_out,
+            }),
+        }
+    }
+}
+
+/// Number of events in a value, including the events in lists and dicts within it
+pub(crate) fn count_events(data: &DataType) -> usize {
+    match data {
+        DataType::Event(_) => 1,
+        DataType::List(l) => l.iter().map(count_events).sum(),
+        DataType::Dict(d) => d.values().map(count_events).sum(),
+        _ => 0,
+    }
+}
@@ -21,12 +21,21 @@ macro_rules! json_map {
 #[cfg(test)]
 mod query_tests {
     use chrono;
+    use chrono::DateTime;
     use chrono::Duration;
     use serde_json::json;
     use std::convert::TryFrom;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
 
+    use aw_query::BucketAccess;
     use aw_query::DataType;
+    use aw_query::QueryCache;
     use aw_query::QueryError;
+    use aw_query::QueryLimit;
+    use aw_query::QueryLimits;
+    use aw_query::QueryOptions;
 
     use aw_datastore::Datastore;
 
@@ -34,6 +43,7 @@ mod query_tests {
     use aw_models::BucketMetadata;
     use aw_models::Event;
     use aw_models::TimeInterval;
+    use aw_transform::Calendar;
 
     static TIME_INTERVAL: &str = "1980-01-01T00:00:00Z/2080-01-02T00:00:00Z";
     static BUCKET_ID: &str = "testid";
@@ -44,7 +54,11 @@ mod query_tests {
 
     fn setup_datastore_with_bucket() -> Datastore {
         let ds = setup_datastore_empty();
-        // Create bucket
+        create_bucket(&ds);
+        return ds;
+    }
+
+    fn create_bucket(ds: &Datastore) {
         let bucket = Bucket {
             bid: None,
             id: BUCKET_ID.to_string(),
@@ -58,7 +72,6 @@ mod query_tests {
             last_updated: None,
         };
         ds.create_bucket(&bucket).unwrap();
-        return ds;
     }
 
     fn setup_datastore_populated() -> Datastore {
@@ -89,6 +102,11 @@ mod query_tests {
         ($v:expr, $p:pat) => {
             match $v {
                 Ok(_) => panic!("Expected an error, got {:?}", $v),
+                // The error type is the same no matter where in the code it happened
+                Err(QueryError::Located(_, e)) => match *e {
+                    $p => (),
+                    _ => panic!("Expected an error of another type, got {:?}", e),
+                },
                 Err(e) => match e {
                     $p => (),
                     _ => panic!("Expected an error of another type, got {:?}", e),
@@ -174,6 +192,771 @@ mod query_tests {
         assert_err_type!(res, QueryError::InvalidType(_));
     }
 
+    #[test]
+    fn test_comparisons() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return 1 != 2;", true),
+            ("return 1 != 1;", false),
+            ("return 1 < 2;", true),
+            ("return 2 < 1;", false),
+            ("return 1 <= 1;", true),
+            ("return 2 <= 1;", false),
+            ("return 2 > 1;", true),
+            ("return 1 > 1;", false),
+            ("return 1 >= 1;", true),
+            ("return 1 >= 2;", false),
+            // Arithmetic binds tighter than comparisons
+            ("return 1 + 1 == 2;", true),
+            ("return 2 == 1 + 1;", true),
+            (r#"return "a" < "b";"#, true),
+            (r#"return "b" <= "a";"#, false),
+            (r#"return "a" != "b";"#, true),
+            // Durations are numbers of seconds
+            ("return sum_durations([]) < 60;", true),
+        ];
+        for (code, expected) in cases.iter() {
+            match aw_query::query(code, &interval, &ds).unwrap() {
+                aw_query::DataType::Bool(b) => assert_eq!(b, *expected, "{}", code),
+                ref data => panic!("Wrong datatype, {:?}", data),
+            };
+        }
+
+        // different types comparison (should raise an error)
+        let code = String::from(r#"return 1 < "a";"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+
+        // types without an ordering (should raise an error)
+        let code = String::from("return [1] < [2];");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_boolean_operators() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let cases = [
+            ("return true and true;", true),
+            ("return true and false;", false),
+            ("return false or true;", true),
+            ("return false or false;", false),
+            ("return not false;", true),
+            ("return not not false;", false),
+            // not binds tighter than and, which binds tighter than or
+            ("return not false and false;", false),
+            ("return true or true and false;", true),
+            ("return (true or true) and false;", false),
+            ("return not 1 == 2;", true),
+            ("return 1 < 2 and 2 < 3;", true),
+            ("a = 5; return a > 1 and a < 10 or a == 100;", true),
+        ];
+        for (code, expected) in cases.iter() {
+            match aw_query::query(code, &interval, &ds).unwrap() {
+                aw_query::DataType::Bool(b) => assert_eq!(b, *expected, "{}", code),
+                ref data => panic!("Wrong datatype, {:?}", data),
+            };
+        }
+
+        // Short-circuiting, the undefined variable is never evaluated
+        let code = String::from("return false and undefined or true or undefined;");
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            aw_query::DataType::Bool(b) => assert_eq!(b, true),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        // Conditions in if statements
+        let code = String::from(
+            "
+            n = 1;
+            if n >= 1 and not n == 2 { n = 3; }
+            return n;",
+        );
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            aw_query::DataType::Number(n) => assert_eq!(n, 3.0),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        // non-bool operands (should raise an error)
+        let code = String::from("return 1 and true;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_user_functions() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from(
+            "
+            def add(a, b) { return a + b; }
+            def one() { return 1; }
+            return add(one(), 2);",
+        );
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            aw_query::DataType::Number(n) => assert_eq!(n, 3.0),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        // Recursion, return stops the execution of the function
+        let code = String::from(
+            "
+            def factorial(n) {
+                if n <= 1 { return 1; }
+                return n * factorial(n - 1);
+            }
+            return factorial(5);",
+        );
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            aw_query::DataType::Number(n) => assert_eq!(n, 120.0),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        // Globals can be read, assignments are local to the function
+        let code = String::from(
+            "
+            x = 1; n = 10;
+            def f(a) { x = a + n; return x; }
+            return [f(1), x, sum_durations([])];",
+        );
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            aw_query::DataType::List(l) => assert_eq!(
+                l,
+                vec![
+                    DataType::Number(11.0),
+                    DataType::Number(1.0),
+                    DataType::Number(0.0)
+                ]
+            ),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        // Variables of the caller are not visible
+        let code = String::from(
+            "
+            def g() { return secret; }
+            def f() { secret = 1; return g(); }
+            return f();",
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        match res {
+            Err(QueryError::FunctionCallError(fname, _, err)) => {
+                assert_eq!(fname, "g");
+                match *err {
+                    QueryError::Located(span, err) => {
+                        assert_eq!(span.line, 2);
+                        assert_eq!(&code[span.lo..span.hi], "secret");
+                        assert!(
+                            matches!(*err, QueryError::VariableNotDefined(_)),
+                            "{:?}",
+                            err
+                        );
+                    }
+                    err => panic!("Expected Located, got {:?}", err),
+                }
+            }
+            _ => panic!("Expected FunctionCallError, got {:?}", res),
+        }
+
+        // Wrong number of parameters, the error points at the call
+        let code = String::from(
+            "def f(a) { return a; }
+            return f(1, 2);",
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        match res {
+            Err(QueryError::FunctionCallError(fname, span, err)) => {
+                assert_eq!(fname, "f");
+                assert_eq!(span.line, 2);
+                assert_eq!(&code[span.lo..span.hi], "f(1, 2)");
+                assert!(
+                    matches!(*err, QueryError::InvalidFunctionParameters(_)),
+                    "{:?}",
+                    err
+                );
+            }
+            _ => panic!("Expected FunctionCallError, got {:?}", res),
+        }
+
+        // Runaway recursion
+        let code = String::from(
+            "
+            def f(n) {
+                if n >= 0 {
+                    if n % 2 == 0 { return [{\"n\": 1 + f(n + 1)}]; }
+                    return [f(n + 1) * 2];
+                }
+            }
+            return f(0);",
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        match res {
+            Err(QueryError::FunctionCallError(fname, _, err)) => {
+                assert_eq!(fname, "f");
+                assert!(
+                    matches!(*err, QueryError::RecursionLimitExceeded(_)),
+                    "{:?}",
+                    err
+                );
+            }
+            _ => panic!("Expected FunctionCallError, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_loops() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from(
+            "
+            sum = 0;
+            for n in [1, 2, 3] { sum = sum + n; }
+            return [sum, n];",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(6.0), DataType::Number(3.0)])
+        );
+
+        // Per-bucket processing
+        let code = String::from(
+            "
+            events = [];
+            for bucket in query_bucket_names() {
+                events = events + query_bucket(bucket);
+            }
+            return events;",
+        );
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), 2),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        // Return inside a loop stops the function
+        let code = String::from(
+            "
+            def first_over(list, limit) {
+                for n in list { if n > limit { return n; } }
+                return 0;
+            }
+            return first_over([1, 5, 2, 7], 4);",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(5.0));
+
+        let code = String::from("for n in 1 { }");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_list_comprehension() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = String::from("return [n * 2 for n in [1, 2, 3]];");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::Number(2.0),
+                DataType::Number(4.0),
+                DataType::Number(6.0)
+            ])
+        );
+
+        let code = String::from("return [[n] for n in [1, 2, 3] if n != 2];");
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::List(vec![DataType::Number(1.0)]),
+                DataType::List(vec![DataType::Number(3.0)])
+            ])
+        );
+
+        // The variable doesn't leak out of the comprehension
+        let code = String::from(
+            "
+            n = 1;
+            l = [n for n in [2, 3]];
+            return [n for x in l];",
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![DataType::Number(1.0), DataType::Number(1.0)])
+        );
+        let code = String::from("l = [n for n in [2, 3]]; return n;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::VariableNotDefined(_));
+    }
+
+    #[test]
+    fn test_attribute_access() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let code = format!(
+            r#"
+            events = query_bucket("{}");
+            e = events[0];
+            return [e.data.key, e.data["key"], e.duration, query_bucket("{}")[1]["data"].key];"#,
+            BUCKET_ID, BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            res,
+            DataType::List(vec![
+                DataType::String("value".to_string()),
+                DataType::String("value".to_string()),
+                DataType::Number(0.0),
+                DataType::String("value".to_string())
+            ])
+        );
+
+        // Fields can be compared and used to build new dicts
+        let code = format!(
+            r#"
+            events = query_bucket("{}");
+            return [{{"key": e.data.key}} for e in events if e.data.key == "value"];"#,
+            BUCKET_ID
+        );
+        match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => {
+                assert_eq!(l.len(), 2);
+                assert_eq!(
+                    l[0].query_attr("key").unwrap(),
+                    DataType::String("value".to_string())
+                );
+            }
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        let code = String::from(r#"d = {"a": {"b": [1, 2]}}; return d.a.b[1] + d["a"]["b"][0];"#);
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(res, DataType::Number(3.0));
+
+        let code = String::from("return [1, 2][2];");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::IndexOutOfRange(_));
+
+        let code = String::from(r#"return {"a": 1}.b;"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::KeyNotFound(_));
+
+        let code = String::from("n = 1; return n.a;");
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_calendar() {
+        let ds = setup_datastore_with_bucket();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let calendar = Calendar::new("Europe/Stockholm", "04:00").unwrap();
+        // 02:00 and 05:00 in Stockholm, on either side of the day start
+        let event = |timestamp: &str| Event {
+            id: None,
+            timestamp: DateTime::from_str(timestamp).unwrap(),
+            duration: Duration::minutes(30),
+            data: json_map! {},
+        };
+        let events = [event("2000-01-02T01:00:00Z"), event("2000-01-02T04:00:00Z")];
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+
+        let code = format!(
+            r#"return [TIMEZONE, DAY_START, histogram(query_bucket("{}"), "day")];"#,
+            BUCKET_ID
+        );
+        let options = QueryOptions {
+            calendar,
+            ..QueryOptions::default()
+        };
+        let res = aw_query::query_with_options(&code, &interval, &ds, &options)
+            .unwrap()
+            .result;
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            json!([
+                "Europe/Stockholm",
+                "04:00",
+                [
+                    {"timestamp": "2000-01-01T04:00:00+01:00", "duration": 1800.0},
+                    {"timestamp": "2000-01-02T04:00:00+01:00", "duration": 1800.0},
+                ]
+            ])
+        );
+
+        // The timezone can be changed for a single call, but days still start at 04:00
+        let code = format!(
+            r#"return histogram(query_bucket("{}"), "day", "+05:00");"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query_with_options(&code, &interval, &ds, &options)
+            .unwrap()
+            .result;
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            json!([{"timestamp": "2000-01-02T04:00:00+05:00", "duration": 3600.0}])
+        );
+
+        let code = format!(
+            r#"TIMEZONE = "Europe/Atlantis"; return histogram(query_bucket("{}"), "day");"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query_with_options(&code, &interval, &ds, &options);
+        assert_err_type!(res, QueryError::InvalidType(_));
+    }
+
+    #[test]
+    fn test_query_cache_concurrent_writes() {
+        // A file-backed datastore, so that queries read from the read-only connections with the
+        // uncommitted changes merged in
+        let db_path = std::env::temp_dir().join("aw-query-unittest-cache.db");
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", db_path.display(), suffix));
+        }
+        let ds = Datastore::new(db_path.to_str().unwrap().to_string(), false);
+        create_bucket(&ds);
+        ds.force_commit().unwrap();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let cache = QueryCache::new(10, QueryLimits::default());
+        let code = format!(r#"return query_bucket("{}");"#, BUCKET_ID);
+
+        // Query through the cache while events are being inserted and committed, a result read
+        // from before a change must never be cached as up to date with it
+        let inserts = 200;
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let ds = ds.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for i in 0..inserts {
+                    let e = Event {
+                        id: None,
+                        timestamp: chrono::Utc::now() + Duration::seconds(i),
+                        duration: Duration::seconds(0),
+                        data: json_map! {},
+                    };
+                    ds.insert_events(BUCKET_ID, &[e]).unwrap();
+                    if i % 10 == 0 {
+                        ds.force_commit().unwrap();
+                    }
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        while !done.load(Ordering::SeqCst) {
+            cache.query(&code, &interval, &ds).unwrap();
+        }
+        writer.join().unwrap();
+        match cache.query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), inserts as usize),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+    }
+
+    #[test]
+    fn test_query_bucket_missing() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        // A missing bucket is the fault of the query and not a DatastoreError
+        let code = String::from(r#"return query_bucket("missing");"#);
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::BucketQueryError(_));
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let ds = setup_datastore_populated();
+        let past_interval =
+            TimeInterval::new_from_string("1980-01-01T00:00:00Z/1980-01-02T00:00:00Z").unwrap();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let cache = QueryCache::new(10, QueryLimits::default());
+
+        let code = format!(r#"return query_bucket("{}");"#, BUCKET_ID);
+        let res = cache.query(&code, &past_interval, &ds).unwrap();
+        assert_eq!(res, DataType::List(vec![]));
+        match cache.query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), 2),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+        assert_eq!(cache.len(), 2);
+
+        // Only the result for the timeinterval overlapping the new event is invalidated
+        let e = Event {
+            id: None,
+            timestamp: chrono::Utc::now(),
+            duration: Duration::seconds(0),
+            data: json_map! {"key": json!("value3")},
+        };
+        ds.insert_events(BUCKET_ID, &[e]).unwrap();
+        let res = cache.query(&code, &past_interval, &ds).unwrap();
+        assert_eq!(res, DataType::List(vec![]));
+        assert_eq!(cache.len(), 1);
+        match cache.query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), 3),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+        assert_eq!(cache.len(), 2);
+
+        // Queries listing the buckets are invalidated when buckets are created
+        let code = String::from("return query_bucket_names();");
+        match cache.query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), 1),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+        let mut bucket = ds.get_bucket(BUCKET_ID).unwrap();
+        bucket.bid = None;
+        bucket.id = "testid2".to_string();
+        ds.create_bucket(&bucket).unwrap();
+        match cache.query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => assert_eq!(l.len(), 2),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+        assert_eq!(cache.len(), 3);
+
+        // The least recently used result is dropped when the cache is full
+        let cache = QueryCache::new(1, QueryLimits::default());
+        cache.query("return 1;", &interval, &ds).unwrap();
+        cache.query("return 2;", &interval, &ds).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.query("return undefined;", &interval, &ds).is_err());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let code = format!(r#"return query_bucket("{}");"#, BUCKET_ID);
+        let query_with_limits = |code: &str, limits: &QueryLimits| {
+            let options = QueryOptions {
+                limits: limits.clone(),
+                ..QueryOptions::default()
+            };
+            aw_query::query_with_options(code, &interval, &ds, &options).map(|output| output.result)
+        };
+
+        let limits = QueryLimits {
+            max_events: Some(2),
+            ..QueryLimits::default()
+        };
+        query_with_limits(&code, &limits).unwrap();
+        let code2 = format!(
+            r#"events = query_bucket("{}"); return query_bucket("{}");"#,
+            BUCKET_ID, BUCKET_ID
+        );
+        let res = query_with_limits(&code2, &limits);
+        assert_err_type!(res, QueryError::LimitExceeded(QueryLimit::MaxEvents, _));
+        // Only one event more than the limit is loaded, rather than all events of the bucket
+        let limits = QueryLimits {
+            max_events: Some(0),
+            ..QueryLimits::default()
+        };
+        match query_with_limits(&code, &limits) {
+            Err(QueryError::Located(_, err)) => match *err {
+                QueryError::LimitExceeded(QueryLimit::MaxEvents, msg) => {
+                    assert_eq!(msg, "Query loaded 1 events, more than the limit of 0")
+                }
+                err => panic!("Expected MaxEvents error, got {:?}", err),
+            },
+            res => panic!("Expected MaxEvents error, got {:?}", res),
+        }
+
+        let limits = QueryLimits {
+            max_result_size: Some(3),
+            ..QueryLimits::default()
+        };
+        query_with_limits(&code, &limits).unwrap();
+        let res = query_with_limits("return [1, [2, 3], 4];", &limits);
+        assert_err_type!(res, QueryError::LimitExceeded(QueryLimit::MaxResultSize, _));
+
+        let limits = QueryLimits {
+            timeout: Some(std::time::Duration::from_millis(0)),
+            ..QueryLimits::default()
+        };
+        let code = String::from("n = 0; for i in [1, 2, 3] { n = n + i; } return n;");
+        let res = query_with_limits(&code, &limits);
+        assert_err_type!(res, QueryError::LimitExceeded(QueryLimit::Timeout, _));
+
+        let limits = QueryLimits {
+            buckets: Some(BucketAccess::new(|bucket_id| bucket_id == BUCKET_ID)),
+            ..QueryLimits::default()
+        };
+        let code = format!(r#"return query_bucket("{}");"#, BUCKET_ID);
+        query_with_limits(&code, &limits).unwrap();
+        let code = r#"return query_bucket("other");"#;
+        let res = query_with_limits(code, &limits);
+        assert_err_type!(res, QueryError::AccessDenied(_));
+        // Listing the buckets would reveal the ones the query can't read
+        let code = r#"return find_bucket("test");"#;
+        let res = query_with_limits(code, &limits);
+        assert_err_type!(res, QueryError::AccessDenied(_));
+    }
+
+    #[test]
+    fn test_query_profile() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let code = format!(
+            r#"events = query_bucket("{}");
+            for i in [1, 2, 3] {{ events = limit_events(events, i); }}
+            return events;"#,
+            BUCKET_ID
+        );
+        let options = QueryOptions {
+            profile: true,
+            ..QueryOptions::default()
+        };
+        let output = aw_query::query_with_options(&code, &interval, &ds, &options).unwrap();
+        let (res, profile) = (output.result, output.profile.unwrap());
+        match res {
+            DataType::List(l) => assert_eq!(l.len(), 1),
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+
+        assert_eq!(profile.statements.len(), 3);
+        assert_eq!(profile.statements[1].span.line, 2);
+        assert_eq!(profile.calls.len(), 2);
+        let query_bucket = &profile.calls[0];
+        assert_eq!(query_bucket.function, "query_bucket");
+        assert_eq!(query_bucket.count, 1);
+        assert_eq!(query_bucket.events_in, 0);
+        assert_eq!(query_bucket.events_out, 2);
+        assert_eq!(
+            &code[query_bucket.span.lo..query_bucket.span.hi],
+            format!(r#"query_bucket("{}")"#, BUCKET_ID)
+        );
+        // Calls in loops are summed up
+        let limit_events = &profile.calls[1];
+        assert_eq!(limit_events.function, "limit_events");
+        assert_eq!(limit_events.count, 3);
+        assert_eq!(limit_events.events_in, 2 + 1 + 1);
+        assert_eq!(limit_events.events_out, 1 + 1 + 1);
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let ds = setup_datastore_empty();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        // Runtime errors point at the innermost expression which failed
+        let code = String::from("a = 1;\nb = [1, a / 0];\nreturn b;");
+        let err = aw_query::query(&code, &interval, &ds).unwrap_err();
+        assert_eq!(err.kind(), "MathError");
+        let span = err.span().unwrap();
+        assert_eq!(span.line, 2);
+        assert_eq!(span.column, 9);
+        assert_eq!(span.snippet(&code), "a / 0");
+
+        // Parse errors point at the unexpected token
+        let code = String::from("a = 1;\nb = (a;\nreturn b;");
+        let err = aw_query::query(&code, &interval, &ds).unwrap_err();
+        assert_eq!(err.kind(), "ParsingError");
+        let span = err.span().unwrap();
+        assert_eq!(span.line, 2);
+        assert_eq!(span.column, 7);
+        assert_eq!(span.snippet(&code), ";");
+
+        // or at the end of the query if it ended too early
+        let code = String::from("a = 1;\nreturn [a");
+        let err = aw_query::query(&code, &interval, &ds).unwrap_err();
+        assert_eq!(err.kind(), "ParsingError");
+        let span = err.span().unwrap();
+        assert_eq!((span.line, span.column), (2, 10));
+
+        // Errors in user-defined functions point into the function
+        let code = String::from("def f(x) {\n  return x.missing;\n}\nreturn f({});");
+        let err = aw_query::query(&code, &interval, &ds).unwrap_err();
+        assert_eq!(err.kind(), "KeyNotFound");
+        let span = err.span().unwrap();
+        assert_eq!(span.line, 2);
+        assert_eq!(span.snippet(&code), "x.missing");
+
+        // Errors which aren't caused by any specific code have no span
+        let err = aw_query::query("a = 1;", &interval, &ds).unwrap_err();
+        assert_eq!(err.kind(), "EmptyQuery");
+        assert!(err.span().is_none());
+    }
+
+    #[test]
+    fn test_filter_pushdown() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let e = Event {
+            id: None,
+            timestamp: chrono::Utc::now(),
+            duration: Duration::seconds(1),
+            data: json_map! {"key": json!("value2")},
+        };
+        ds.insert_events(BUCKET_ID, &[e]).unwrap();
+
+        let filtered_events = |code: &str| {
+            let options = QueryOptions {
+                profile: true,
+                ..QueryOptions::default()
+            };
+            let output = aw_query::query_with_options(code, &interval, &ds, &options).unwrap();
+            let (res, profile) = (output.result, output.profile.unwrap());
+            let events = match res {
+                DataType::List(l) => l.len(),
+                ref data => panic!("Wrong datatype, {:?}", data),
+            };
+            let calls: Vec<(String, usize)> = profile
+                .calls
+                .iter()
+                .map(|call| (call.function.clone(), call.events_out))
+                .collect();
+            (events, calls)
+        };
+
+        // Only the matching events are loaded from the bucket
+        let code = format!(
+            r#"return filter_keyvals(query_bucket("{}"), "key", ["value2"]);"#,
+            BUCKET_ID
+        );
+        assert_eq!(
+            filtered_events(&code),
+            (1, vec![("query_bucket".to_string(), 1)])
+        );
+        let code = format!(
+            r#"return query_bucket("{}", {{"filter_keyvals": {{"key": ["value", "value2"]}}}});"#,
+            BUCKET_ID
+        );
+        assert_eq!(
+            filtered_events(&code),
+            (3, vec![("query_bucket".to_string(), 3)])
+        );
+
+        // Unless filter_keyvals has been redefined
+        let code = format!(
+            r#"def filter_keyvals(events, key, vals) {{ return events; }}
+            return filter_keyvals(query_bucket("{}"), "key", ["value2"]);"#,
+            BUCKET_ID
+        );
+        assert_eq!(
+            filtered_events(&code),
+            (3, vec![("query_bucket".to_string(), 3)])
+        );
+
+        let code = format!(
+            r#"return query_bucket("{}", {{"no_such_option": 1}});"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
     #[test]
     fn test_return() {
         let ds = setup_datastore_empty();
@@ -319,19 +1102,21 @@ mod query_tests {
         let code = String::from("return no_such_function(1);");
         match aw_query::query(&code, &interval, &ds) {
             Ok(ok) => panic!("Expected QueryError, got {:?}", ok),
-            Err(e) => match e {
+            Err(QueryError::Located(_, e)) => match *e {
                 QueryError::VariableNotDefined(qe) => assert_eq!(qe, "no_such_function"),
                 qe => panic!("Expected QueryError::VariableNotDefined, got {:?}", qe),
             },
+            Err(e) => panic!("Expected QueryError::Located, got {:?}", e),
         }
 
         let code = String::from("invalid_type=1; return invalid_type(1);");
         match aw_query::query(&code, &interval, &ds) {
             Ok(ok) => panic!("Expected QueryError, got {:?}", ok),
-            Err(e) => match e {
+            Err(QueryError::Located(_, e)) => match *e {
                 QueryError::InvalidType(qe) => assert_eq!(qe, "invalid_type"),
                 qe => panic!("Expected QueryError::VariableNotDefined, got {:?}", qe),
             },
+            Err(e) => panic!("Expected QueryError::Located, got {:?}", e),
         }
     }
 
@@ -362,6 +1147,8 @@ mod query_tests {
             filtered_events = filter_keyvals_regex(events, "key", "regex");
             chunked_events = chunk_events_by_key(events, "key");
             merged_events = merge_events_by_keys(events, ["key"]);
+            groups = group_by(events, ["key"], {{"duration": "sum_duration"}});
+            hours = histogram(events, "hour", "+02:00", "key");
             return  merged_events;"#,
             "testid", "testid"
         );
@@ -372,6 +1159,117 @@ mod query_tests {
         // TODO: assert_eq result
     }
 
+    #[test]
+    fn test_group_by() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let e = Event {
+            id: None,
+            timestamp: chrono::Utc::now(),
+            duration: Duration::seconds(3),
+            data: json_map! {"key": json!("value2"), "title": json!("a")},
+        };
+        ds.insert_events(BUCKET_ID, &[e]).unwrap();
+
+        let code = format!(
+            r#"
+            events = sort_by_timestamp(query_bucket("{}"));
+            return group_by(events, ["key"], {{
+                "duration": "sum_duration",
+                "count": "count",
+                "first": "first_timestamp",
+                "last": "last_timestamp",
+                "titles": ["distinct_count", "title"]
+            }});"#,
+            BUCKET_ID
+        );
+        let groups = match aw_query::query(&code, &interval, &ds).unwrap() {
+            DataType::List(l) => l,
+            ref data => panic!("Wrong datatype, {:?}", data),
+        };
+        assert_eq!(groups.len(), 2);
+        let groups = serde_json::to_value(&groups).unwrap();
+        assert_eq!(groups[0]["key"], "value");
+        assert_eq!(groups[0]["count"], 2.0);
+        assert_eq!(groups[0]["titles"], 0.0);
+        assert!(groups[0]["first"].as_str().unwrap() <= groups[0]["last"].as_str().unwrap());
+        assert_eq!(groups[1]["key"], "value2");
+        assert_eq!(groups[1]["duration"], 3.0);
+        assert_eq!(groups[1]["count"], 1.0);
+        assert_eq!(groups[1]["titles"], 1.0);
+
+        let code = format!(
+            r#"return group_by(query_bucket("{}"), ["key"], {{"key": "count"}});"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let code = format!(
+            r#"return group_by(query_bucket("{}"), ["key"], {{"n": "median"}});"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let ds = setup_datastore_with_bucket();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+        let event = |timestamp: &str, minutes: i64, app: &str| Event {
+            id: None,
+            timestamp: DateTime::from_str(timestamp).unwrap(),
+            duration: Duration::minutes(minutes),
+            data: json_map! {"app": json!(app)},
+        };
+        let events = [
+            event("2000-01-01T22:30:00Z", 60, "a"),
+            event("2000-01-01T23:00:00Z", 15, "b"),
+        ];
+        ds.insert_events(BUCKET_ID, &events).unwrap();
+
+        let code = format!(
+            r#"return histogram(query_bucket("{}"), "hour");"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            json!([
+                {"timestamp": "2000-01-01T22:00:00+00:00", "duration": 1800.0},
+                {"timestamp": "2000-01-01T23:00:00+00:00", "duration": 2700.0},
+            ])
+        );
+
+        // Days start at midnight in the timezone of the offset
+        let code = format!(
+            r#"return histogram(query_bucket("{}"), "day", "+01:00", "app");"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds).unwrap();
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            json!([
+                {"timestamp": "2000-01-01T00:00:00+01:00", "duration": 1800.0, "app": "a"},
+                {"timestamp": "2000-01-02T00:00:00+01:00", "duration": 1800.0, "app": "a"},
+                {"timestamp": "2000-01-02T00:00:00+01:00", "duration": 900.0, "app": "b"},
+            ])
+        );
+
+        let code = format!(
+            r#"return histogram(query_bucket("{}"), "month");"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let code = format!(
+            r#"return histogram(query_bucket("{}"), "day", "2");"#,
+            BUCKET_ID
+        );
+        let res = aw_query::query(&code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
     #[test]
     fn test_categorize() {
         let ds = setup_datastore_populated();
@@ -494,6 +1392,110 @@ mod query_tests {
             return  events;"#;
         let res = aw_query::query(&code, &interval, &ds);
         assert_err_type!(res, QueryError::RegexCompileError(_));
+
+        // Test combined rule where rules field is not a list
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "any", "rules": { "type": "none" } }]]);
+            return  events;"#;
+        let res = aw_query::query(code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        // Test rule where select_keys is not a list of strings
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "regex", "regex": "test", "select_keys": "title" }]]);
+            return  events;"#;
+        let res = aw_query::query(code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "exact", "value": "test", "select_keys": [1] }]]);
+            return  events;"#;
+        let res = aw_query::query(code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        // Test rule with both a key and select_keys
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "regex", "regex": "test", "key": "title", "select_keys": ["app"] }]]);
+            return  events;"#;
+        let res = aw_query::query(code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+
+        // Test not rule with an invalid rule inside it
+        let code = r#"
+            events = [];
+            events = tag(events, [["testtag", { "type": "not", "rule": { "type": "glob" } }]]);
+            return  events;"#;
+        let res = aw_query::query(code, &interval, &ds);
+        assert_err_type!(res, QueryError::InvalidFunctionParameters(_));
+    }
+
+    #[test]
+    fn test_rule_types() {
+        let ds = setup_datastore_populated();
+        let interval = TimeInterval::new_from_string(TIME_INTERVAL).unwrap();
+
+        let tags = |rule: &str| {
+            let code = format!(
+                r#"return tag(query_bucket("{}"), [["tag", {}]]);"#,
+                BUCKET_ID, rule
+            );
+            let result = aw_query::query(&code, &interval, &ds).unwrap();
+            let events: Vec<Event> = Vec::try_from(&result).unwrap();
+            events[0].data["$tags"].as_array().unwrap().len()
+        };
+        assert_eq!(tags(r#"{"type": "glob", "pattern": "va*e"}"#), 1);
+        assert_eq!(
+            tags(r#"{"type": "glob", "pattern": "VA?UE", "ignore_case": true}"#),
+            1
+        );
+        assert_eq!(tags(r#"{"type": "glob", "pattern": "va"}"#), 0);
+        assert_eq!(tags(r#"{"type": "exact", "value": "value"}"#), 1);
+        assert_eq!(tags(r#"{"type": "exact", "value": "valu"}"#), 0);
+        assert_eq!(
+            tags(r#"{"type": "regex", "regex": "val", "key": "key"}"#),
+            1
+        );
+        assert_eq!(
+            tags(r#"{"type": "regex", "regex": "val", "key": "title"}"#),
+            0
+        );
+        assert_eq!(
+            tags(r#"{"type": "regex", "regex": "val", "select_keys": ["key"]}"#),
+            1
+        );
+        assert_eq!(
+            tags(r#"{"type": "regex", "regex": "val", "select_keys": ["title"]}"#),
+            0
+        );
+        assert_eq!(
+            tags(r#"{"type": "glob", "pattern": "v*", "select_keys": ["title", "key"]}"#),
+            1
+        );
+        assert_eq!(
+            tags(r#"{"type": "exact", "value": "value", "select_keys": []}"#),
+            0
+        );
+        assert_eq!(
+            tags(
+                r#"{"type": "all", "rules": [
+                    {"type": "exact", "value": "value"},
+                    {"type": "not", "rule": {"type": "glob", "pattern": "*2"}}
+                ]}"#
+            ),
+            1
+        );
+        assert_eq!(
+            tags(
+                r#"{"type": "any", "rules": [
+                    {"type": "none"},
+                    {"type": "exact", "value": "value2"}
+                ]}"#
+            ),
+            0
+        );
     }
 
     #[test]
@@ -1,10 +1,13 @@
 use std::fs::File;
 use std::io::{Read, Write};
+use std::time::Duration;
 
-use rocket::config::Config;
+use rocket::config::{Config, TlsConfig};
 use rocket::data::{Limits, ToByteUnit};
 use serde::{Deserialize, Serialize};
 
+use aw_query::QueryLimits;
+
 use crate::dirs;
 
 /* Far from an optimal way to solve it, but works and is simple */
@@ -28,6 +31,44 @@ pub struct AWConfig {
     pub testing: bool, // This is not written to the config file (serde(skip))
     #[serde(default = "default_cors")]
     pub cors: Vec<String>,
+    // Limits for each timeperiod of a query, 0 means unlimited
+    #[serde(default = "default_query_timeout")]
+    pub query_timeout: u64, // In seconds
+    #[serde(default = "default_query_max_events")]
+    pub query_max_events: usize,
+    #[serde(default = "default_query_max_result_size")]
+    pub query_max_result_size: usize,
+    // Require an API token for requests to the API which do not come from localhost
+    #[serde(default)]
+    pub token_auth: bool,
+    // Paths to the PEM encoded certificate chain and private key to serve HTTPS with, both need
+    // to be set to enable TLS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<String>,
+    // URLs to POST changes to the buckets to
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    // Ids of the buckets to send the changes of, a * matches any characters
+    #[serde(default = "default_webhook_buckets")]
+    pub buckets: Vec<String>,
+    // Changes are collected for this long before they are sent together, 0 sends them one by one
+    #[serde(default = "default_webhook_batch_interval")]
+    pub batch_interval: u64, // In seconds
+    #[serde(default = "default_webhook_max_batch_size")]
+    pub max_batch_size: usize,
+    // Time until the first retry of a failed delivery, doubled for every following retry
+    #[serde(default = "default_webhook_retry_interval")]
+    pub retry_interval: u64, // In seconds
+    // Attempts to deliver a batch before it is dropped
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
 }
 
 impl Default for AWConfig {
@@ -37,6 +78,13 @@ impl Default for AWConfig {
             port: default_port(),
             testing: default_testing(),
             cors: default_cors(),
+            query_timeout: default_query_timeout(),
+            query_max_events: default_query_max_events(),
+            query_max_result_size: default_query_max_result_size(),
+            token_auth: false,
+            tls_cert: None,
+            tls_key: None,
+            webhooks: Vec::new(),
         }
     }
 }
@@ -52,15 +100,48 @@ impl AWConfig {
         // Needed for bucket imports
         let limits = Limits::default()
             .limit("json", 1000u64.megabytes())
-            .limit("data-form", 1000u64.megabytes());
+            .limit("data-form", 1000u64.megabytes())
+            .limit("ndjson", 1000u64.megabytes());
 
         config.address = self.address.parse().unwrap();
         config.port = self.port;
         config.keep_alive = 0;
         config.limits = limits;
+        // Only one of them being set is rejected by validate
+        config.tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig::from_paths(cert, key)),
+            _ => None,
+        };
 
         config
     }
+
+    /// Checks the settings which can't be checked when parsing the config file, as they depend
+    /// on each other
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("Both tls_cert and tls_key need to be set to enable TLS".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn query_limits(&self) -> QueryLimits {
+        QueryLimits {
+            timeout: match self.query_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_events: match self.query_max_events {
+                0 => None,
+                max_events => Some(max_events),
+            },
+            max_result_size: match self.query_max_result_size {
+                0 => None,
+                max_result_size => Some(max_result_size),
+            },
+            buckets: None,
+        }
+    }
 }
 
 fn default_address() -> String {
@@ -71,6 +152,38 @@ fn default_cors() -> Vec<String> {
     Vec::<String>::new()
 }
 
+fn default_query_timeout() -> u64 {
+    120
+}
+
+fn default_query_max_events() -> usize {
+    5_000_000
+}
+
+fn default_query_max_result_size() -> usize {
+    5_000_000
+}
+
+fn default_webhook_buckets() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_webhook_batch_interval() -> u64 {
+    10
+}
+
+fn default_webhook_max_batch_size() -> usize {
+    1000
+}
+
+fn default_webhook_retry_interval() -> u64 {
+    30
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    10
+}
+
 fn default_testing() -> bool {
     is_testing()
 }
@@ -0,0 +1,536 @@
+//! Optional API token authentication, so the server can be exposed to other machines than
+//! localhost.
+//!
+//! Uses a Request Fairing like the host header check, requests to the API from other addresses
+//! than localhost without a valid token in an `Authorization: Bearer <token>` header are
+//! rerouted to an Unauthorized response. Only the SHA-256 hashes of the tokens are stored.
+//!
+//! Tokens are limited to the operations of their scopes and to the buckets matching their bucket
+//! patterns, which the endpoints check through the `Access` request guard. The guard does not
+//! rely on the fairing, it rejects requests from other addresses than localhost without a valid
+//! token by itself, and it enforces the scopes of tokens sent from localhost as well.
+//!
+//! Note that requests from a reverse proxy on the same machine count as coming from localhost.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+use rocket::fairing::Fairing;
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest};
+use rocket::route::Outcome;
+use rocket::{Data, Request, Rocket, Route};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use aw_datastore::{Datastore, DatastoreError};
+use aw_query::BucketAccess;
+
+use crate::config::AWConfig;
+use crate::endpoints::HttpErrorJson;
+use crate::webhooks::bucket_matches;
+
+static FAIRING_ROUTE_BASE: &str = "/tokenauth_fairing";
+
+const TOKEN_KEY_PREFIX: &str = "tokens.";
+
+/// An operation a token can be allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Get buckets, events and settings, and run queries and exports
+    Read,
+    /// Create buckets, and insert, heartbeat and delete events
+    WriteEvents,
+    /// Create buckets and send heartbeats, all a watcher needs
+    Heartbeat,
+    /// Everything, including deleting buckets, imports, changing settings and managing tokens
+    Admin,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::WriteEvents => "write_events",
+            Scope::Heartbeat => "heartbeat",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn includes(self, scope: Scope) -> bool {
+        self == scope
+            || self == Scope::Admin
+            || (self == Scope::WriteEvents && scope == Scope::Heartbeat)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub created: DateTime<Utc>,
+    // Tokens created before there were scopes can do everything
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<Scope>,
+    // Ids of the buckets the token can access, a * matches any characters
+    #[serde(default = "default_buckets")]
+    pub buckets: Vec<String>,
+}
+
+pub fn default_scopes() -> Vec<Scope> {
+    vec![Scope::Admin]
+}
+
+pub fn default_buckets() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+impl ApiToken {
+    fn allows_bucket(&self, bucket_id: &str) -> bool {
+        self.buckets
+            .iter()
+            .any(|pattern| bucket_matches(pattern, bucket_id))
+    }
+
+    fn allows_all_buckets(&self) -> bool {
+        self.buckets.iter().any(|pattern| pattern == "*")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredToken {
+    #[serde(flatten)]
+    token: ApiToken,
+    hash: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// The API tokens, kept in memory and stored in the key_value table of the datastore
+pub struct TokenStore {
+    datastore: Datastore,
+    // By the hash of the token
+    tokens: RwLock<HashMap<String, ApiToken>>,
+}
+
+impl TokenStore {
+    pub fn load(datastore: Datastore) -> TokenStore {
+        let mut tokens = HashMap::new();
+        let keys = datastore
+            .get_keys_starting(&format!("{}%", TOKEN_KEY_PREFIX))
+            .expect("Failed to load API tokens");
+        for key in keys {
+            let value = datastore
+                .get_key_value(&key)
+                .expect("Failed to load API token")
+                .value;
+            match serde_json::from_value::<StoredToken>(value) {
+                Ok(stored) => {
+                    tokens.insert(stored.hash, stored.token);
+                }
+                Err(err) => error!("Ignoring invalid API token {}: {}", key, err),
+            }
+        }
+        TokenStore {
+            datastore,
+            tokens: RwLock::new(tokens),
+        }
+    }
+
+    /// Creates a new token, returns it along with the token itself which is not stored anywhere
+    pub fn create(
+        &self,
+        name: String,
+        scopes: Vec<Scope>,
+        buckets: Vec<String>,
+    ) -> Result<(ApiToken, String), DatastoreError> {
+        let secret = to_hex(&rand::random::<[u8; 32]>());
+        let token = ApiToken {
+            id: Uuid::new_v4().simple().to_string(),
+            name,
+            created: Utc::now(),
+            scopes,
+            buckets,
+        };
+        let stored = StoredToken {
+            token: token.clone(),
+            hash: hash_token(&secret),
+        };
+        self.datastore.insert_key_value(
+            &format!("{}{}", TOKEN_KEY_PREFIX, token.id),
+            &serde_json::to_string(&stored).unwrap(),
+        )?;
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(stored.hash, token.clone());
+        Ok((token, secret))
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<(), DatastoreError> {
+        let mut tokens = self.tokens.write().unwrap();
+        let hash = match tokens.iter().find(|(_, token)| token.id == id) {
+            Some((hash, _)) => hash.clone(),
+            None => return Err(DatastoreError::NoSuchKey(id.to_string())),
+        };
+        self.datastore
+            .delete_key_value(&format!("{}{}", TOKEN_KEY_PREFIX, id))?;
+        tokens.remove(&hash);
+        Ok(())
+    }
+
+    /// All tokens, oldest first
+    pub fn list(&self) -> Vec<ApiToken> {
+        let mut tokens: Vec<ApiToken> = self.tokens.read().unwrap().values().cloned().collect();
+        tokens.sort_by_key(|token| token.created);
+        tokens
+    }
+
+    pub fn verify(&self, token: &str) -> Option<ApiToken> {
+        self.tokens.read().unwrap().get(&hash_token(token)).cloned()
+    }
+}
+
+/// Whether the request comes from localhost, the X-Real-IP header is not trusted as anyone can
+/// set it
+pub fn is_local(remote: Option<SocketAddr>) -> bool {
+    match remote {
+        Some(addr) => addr.ip().is_loopback(),
+        None => false,
+    }
+}
+
+fn bearer_token<'r>(request: &'r Request) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one("Authorization")?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// What the client making a request is allowed to do
+///
+/// Requests without a token are allowed everything when token auth is disabled or when they come
+/// from localhost, requests with a token only what the token allows.
+pub struct Access {
+    local: bool,
+    token: Option<ApiToken>,
+    // Token auth is enabled and the request has an invalid token, or none while it needs one
+    unauthorized: bool,
+}
+
+impl Access {
+    fn denied(message: String) -> HttpErrorJson {
+        HttpErrorJson::new(Status::Forbidden, message)
+    }
+
+    fn unauthorized() -> HttpErrorJson {
+        HttpErrorJson::new(
+            Status::Unauthorized,
+            "Missing or invalid API token".to_string(),
+        )
+    }
+
+    /// Errors unless the operation is allowed, on the bucket if it is specific to one
+    pub fn check(&self, scope: Scope, bucket_id: Option<&str>) -> Result<(), HttpErrorJson> {
+        if self.unauthorized {
+            return Err(Access::unauthorized());
+        }
+        let token = match &self.token {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+        if !token.scopes.iter().any(|s| s.includes(scope)) {
+            return Err(Access::denied(format!(
+                "The API token is missing the {} scope",
+                scope.name()
+            )));
+        }
+        match bucket_id {
+            Some(bucket_id) if !token.allows_bucket(bucket_id) => Err(Access::denied(format!(
+                "The API token has no access to bucket '{}'",
+                bucket_id
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Errors unless the operation is allowed on all buckets, for operations on buckets which
+    /// aren't known beforehand
+    pub fn check_all_buckets(&self, scope: Scope) -> Result<(), HttpErrorJson> {
+        self.check(scope, None)?;
+        match &self.token {
+            Some(token) if !token.allows_all_buckets() => Err(Access::denied(
+                "The API token has no access to all buckets".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn allows_bucket(&self, bucket_id: &str) -> bool {
+        match &self.token {
+            Some(token) => token.allows_bucket(bucket_id),
+            None => !self.unauthorized,
+        }
+    }
+
+    /// Buckets a query is allowed to read, None if it can read all of them
+    pub fn query_buckets(&self) -> Option<BucketAccess> {
+        if self.unauthorized {
+            return Some(BucketAccess::new(|_| false));
+        }
+        match &self.token {
+            Some(token) if !token.allows_all_buckets() => {
+                let token = token.clone();
+                Some(BucketAccess::new(move |bucket_id| {
+                    token.allows_bucket(bucket_id)
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Errors unless the request comes from localhost or has a token with the admin scope, for
+    /// endpoints which only the user of the machine the server runs on should be able to use
+    pub fn require_admin(&self) -> Result<(), HttpErrorJson> {
+        if self.unauthorized {
+            return Err(Access::unauthorized());
+        }
+        match &self.token {
+            Some(token) if token.scopes.contains(&Scope::Admin) => Ok(()),
+            None if self.local => Ok(()),
+            _ => Err(Access::denied(
+                "This endpoint is only available from localhost or with an admin API token"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Access {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Access, ()> {
+        let local = is_local(request.remote());
+        let enabled = match request.rocket().state::<AWConfig>() {
+            Some(config) => config.token_auth,
+            None => false,
+        };
+        let (token, unauthorized) = match (enabled, bearer_token(request)) {
+            (false, _) => (None, false),
+            (true, None) => (None, !local),
+            (true, Some(secret)) => {
+                let tokens = request.rocket().state::<TokenStore>().unwrap();
+                let token = tokens.verify(secret);
+                let unauthorized = token.is_none();
+                (token, unauthorized)
+            }
+        };
+        request::Outcome::Success(Access {
+            local,
+            token,
+            unauthorized,
+        })
+    }
+}
+
+pub struct TokenAuth {
+    enabled: bool,
+}
+
+impl TokenAuth {
+    pub fn new(config: &AWConfig) -> TokenAuth {
+        TokenAuth {
+            enabled: config.token_auth,
+        }
+    }
+}
+
+/// Create a `Handler` for Fairing error handling
+#[derive(Clone)]
+struct FairingErrorRoute {}
+
+#[rocket::async_trait]
+impl rocket::route::Handler for FairingErrorRoute {
+    async fn handle<'r>(
+        &self,
+        request: &'r Request<'_>,
+        _: rocket::Data<'r>,
+    ) -> rocket::route::Outcome<'r> {
+        let err = HttpErrorJson::new(
+            Status::Unauthorized,
+            "Missing or invalid API token".to_string(),
+        );
+        Outcome::from(request, err)
+    }
+}
+
+/// Create a new `Route` for Fairing handling
+fn fairing_route() -> Route {
+    Route::ranked(1, Method::Get, "/", FairingErrorRoute {})
+}
+
+fn redirect_unauthorized(request: &mut Request) {
+    let uri = FAIRING_ROUTE_BASE.to_string();
+    let origin = Origin::parse_owned(uri).unwrap();
+    request.set_method(Method::Get);
+    request.set_uri(origin);
+}
+
+#[rocket::async_trait]
+impl Fairing for TokenAuth {
+    fn info(&self) -> rocket::fairing::Info {
+        rocket::fairing::Info {
+            name: "TokenAuth",
+            kind: rocket::fairing::Kind::Ignite | rocket::fairing::Kind::Request,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<rocket::Build>) -> rocket::fairing::Result {
+        match self.enabled {
+            true => Ok(rocket.mount(FAIRING_ROUTE_BASE, vec![fairing_route()])),
+            false => Ok(rocket),
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if !self.enabled {
+            return;
+        }
+        // The web UI is not protected, and CORS preflight requests never have credentials
+        let is_api = request.uri().path().segments().next() == Some("api");
+        if !is_api || request.method() == Method::Options {
+            return;
+        }
+        // The scopes of tokens sent from localhost are enforced by the Access request guard
+        if is_local(request.remote()) {
+            return;
+        }
+
+        let tokens = request.rocket().state::<TokenStore>().unwrap();
+        if bearer_token(request)
+            .and_then(|token| tokens.verify(token))
+            .is_none()
+        {
+            info!(
+                "Request from {:?} without a valid API token, denying request",
+                request.remote()
+            );
+            redirect_unauthorized(request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use rocket::http::{Header, Status};
+    use rocket::local::blocking::Client;
+
+    use super::{Access, Scope, TokenStore};
+    use crate::config::AWConfig;
+    use crate::endpoints::HttpErrorJson;
+
+    #[get("/<bucket_id>")]
+    fn read_bucket(access: Access, bucket_id: &str) -> Result<(), HttpErrorJson> {
+        access.check(Scope::Read, Some(bucket_id))
+    }
+
+    // Without the TokenAuth fairing, so that only the request guard protects the route
+    fn setup_client(token_auth: bool) -> (Client, Header<'static>) {
+        let tokens = TokenStore::load(aw_datastore::Datastore::new_in_memory(false));
+        let (_, secret) = tokens
+            .create(
+                "reader".to_string(),
+                vec![Scope::Read],
+                vec!["aw-watcher-afk_*".to_string()],
+            )
+            .unwrap();
+        let config = AWConfig {
+            token_auth,
+            ..Default::default()
+        };
+        let rocket = rocket::build()
+            .manage(config)
+            .manage(tokens)
+            .mount("/", routes![read_bucket]);
+        let client = Client::untracked(rocket).expect("valid instance");
+        let header = Header::new("Authorization", format!("Bearer {}", secret));
+        (client, header)
+    }
+
+    #[test]
+    fn test_access_without_fairing() {
+        let (client, token) = setup_client(true);
+        let lan: SocketAddr = "192.168.0.2:40000".parse().unwrap();
+
+        let res = client.get("/aw-watcher-afk_host").remote(lan).dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = client
+            .get("/aw-watcher-afk_host")
+            .remote(lan)
+            .header(Header::new("Authorization", "Bearer invalid"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = client
+            .get("/aw-watcher-afk_host")
+            .remote(lan)
+            .header(token.clone())
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .get("/aw-watcher-window_host")
+            .remote(lan)
+            .header(token)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+
+        // Without token auth nothing is checked
+        let (client, _) = setup_client(false);
+        let res = client.get("/aw-watcher-window_host").remote(lan).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn test_access_local_token() {
+        let (client, token) = setup_client(true);
+        let local: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+
+        // Requests from localhost need no token, but the token is enforced when one is sent
+        let res = client
+            .get("/aw-watcher-window_host")
+            .remote(local)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .get("/aw-watcher-afk_host")
+            .remote(local)
+            .header(token.clone())
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .get("/aw-watcher-window_host")
+            .remote(local)
+            .header(token)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .get("/aw-watcher-window_host")
+            .remote(local)
+            .header(Header::new("Authorization", "Bearer invalid"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+    }
+}
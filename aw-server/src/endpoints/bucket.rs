@@ -2,9 +2,6 @@ use std::collections::HashMap;
 
 use rocket::serde::json::Json;
 
-use chrono::DateTime;
-use chrono::Utc;
-
 use aw_models::Bucket;
 use aw_models::BucketsExport;
 use aw_models::Event;
@@ -13,16 +10,27 @@ use aw_models::TryVec;
 use rocket::http::Status;
 use rocket::State;
 
-use crate::endpoints::util::BucketsExportRocket;
+use aw_datastore::NdjsonExport;
+
+use crate::endpoints::auth::{Access, Scope};
+use crate::endpoints::csv_export::{parse_columns, CsvExport, CsvExportRocket};
+use crate::endpoints::util::{
+    parse_datetime_param, ExportFormat, ExportResponse, NdjsonExportRocket,
+};
 use crate::endpoints::{HttpErrorJson, ServerState};
 
 #[get("/")]
 pub fn buckets_get(
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<Json<HashMap<String, Bucket>>, HttpErrorJson> {
+    access.check(Scope::Read, None)?;
     let datastore = endpoints_get_lock!(state.datastore);
     match datastore.get_buckets() {
-        Ok(bucketlist) => Ok(Json(bucketlist)),
+        Ok(mut bucketlist) => {
+            bucketlist.retain(|bucket_id, _| access.allows_bucket(bucket_id));
+            Ok(Json(bucketlist))
+        }
         Err(err) => Err(err.into()),
     }
 }
@@ -30,8 +38,10 @@ pub fn buckets_get(
 #[get("/<bucket_id>")]
 pub fn bucket_get(
     bucket_id: String,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<Json<Bucket>, HttpErrorJson> {
+    access.check(Scope::Read, Some(&bucket_id))?;
     let datastore = endpoints_get_lock!(state.datastore);
     match datastore.get_bucket(&bucket_id) {
         Ok(bucket) => Ok(Json(bucket)),
@@ -43,8 +53,10 @@ pub fn bucket_get(
 pub fn bucket_new(
     bucket_id: String,
     message: Json<Bucket>,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<(), HttpErrorJson> {
+    access.check(Scope::Heartbeat, Some(&bucket_id))?;
     let mut bucket = message.into_inner();
     if bucket.id != bucket_id {
         bucket.id = bucket_id;
@@ -63,36 +75,12 @@ pub fn bucket_events_get(
     start: Option<String>,
     end: Option<String>,
     limit: Option<u64>,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<Json<Vec<Event>>, HttpErrorJson> {
-    let starttime: Option<DateTime<Utc>> = match start {
-        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
-            Ok(dt) => Some(dt.with_timezone(&Utc)),
-            Err(e) => {
-                let err_msg = format!(
-                    "Failed to parse starttime, datetime needs to be in rfc3339 format: {}",
-                    e
-                );
-                warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
-            }
-        },
-        None => None,
-    };
-    let endtime: Option<DateTime<Utc>> = match end {
-        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
-            Ok(dt) => Some(dt.with_timezone(&Utc)),
-            Err(e) => {
-                let err_msg = format!(
-                    "Failed to parse endtime, datetime needs to be in rfc3339 format: {}",
-                    e
-                );
-                warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
-            }
-        },
-        None => None,
-    };
+    access.check(Scope::Read, Some(&bucket_id))?;
+    let starttime = parse_datetime_param("starttime", start)?;
+    let endtime = parse_datetime_param("endtime", end)?;
     let datastore = endpoints_get_lock!(state.datastore);
     let res = datastore.get_events(&bucket_id, starttime, endtime, limit);
     match res {
@@ -108,8 +96,10 @@ pub fn bucket_events_get_single(
     bucket_id: String,
     event_id: i64,
     _unused: Option<u64>,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<Json<Event>, HttpErrorJson> {
+    access.check(Scope::Read, Some(&bucket_id))?;
     let datastore = endpoints_get_lock!(state.datastore);
     let res = datastore.get_event(&bucket_id, event_id);
     match res {
@@ -122,8 +112,10 @@ pub fn bucket_events_get_single(
 pub fn bucket_events_create(
     bucket_id: String,
     events: Json<Vec<Event>>,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<Json<Vec<Event>>, HttpErrorJson> {
+    access.check(Scope::WriteEvents, Some(&bucket_id))?;
     let datastore = endpoints_get_lock!(state.datastore);
     let res = datastore.insert_events(&bucket_id, &events);
     match res {
@@ -141,8 +133,10 @@ pub fn bucket_events_heartbeat(
     bucket_id: String,
     heartbeat_json: Json<Event>,
     pulsetime: f64,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<Json<Event>, HttpErrorJson> {
+    access.check(Scope::Heartbeat, Some(&bucket_id))?;
     let heartbeat = heartbeat_json.into_inner();
     let datastore = endpoints_get_lock!(state.datastore);
     match datastore.heartbeat(&bucket_id, heartbeat, pulsetime) {
@@ -154,8 +148,10 @@ pub fn bucket_events_heartbeat(
 #[get("/<bucket_id>/events/count")]
 pub fn bucket_event_count(
     bucket_id: String,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<Json<u64>, HttpErrorJson> {
+    access.check(Scope::Read, Some(&bucket_id))?;
     let datastore = endpoints_get_lock!(state.datastore);
     let res = datastore.get_event_count(&bucket_id, None, None);
     match res {
@@ -168,8 +164,10 @@ pub fn bucket_event_count(
 pub fn bucket_events_delete_by_id(
     bucket_id: String,
     event_id: i64,
+    access: Access,
     state: &State<ServerState>,
 ) -> Result<(), HttpErrorJson> {
+    access.check(Scope::WriteEvents, Some(&bucket_id))?;
     let datastore = endpoints_get_lock!(state.datastore);
     match datastore.delete_events_by_id(&bucket_id, vec![event_id]) {
         Ok(_) => Ok(()),
@@ -177,11 +175,20 @@ pub fn bucket_events_delete_by_id(
     }
 }
 
-#[get("/<bucket_id>/export")]
+#[get("/<bucket_id>/export?<format>&<columns>&<start>&<end>")]
 pub fn bucket_export(
     bucket_id: String,
+    format: Option<&str>,
+    columns: Option<&str>,
+    start: Option<String>,
+    end: Option<String>,
+    access: Access,
     state: &State<ServerState>,
-) -> Result<BucketsExportRocket, HttpErrorJson> {
+) -> Result<ExportResponse, HttpErrorJson> {
+    access.check(Scope::Read, Some(&bucket_id))?;
+    let format = ExportFormat::parse(format)?;
+    let starttime = parse_datetime_param("starttime", start)?;
+    let endtime = parse_datetime_param("endtime", end)?;
     let datastore = endpoints_get_lock!(state.datastore);
     let mut export = BucketsExport {
         buckets: HashMap::new(),
@@ -190,18 +197,46 @@ pub fn bucket_export(
         Ok(bucket) => bucket,
         Err(err) => return Err(err.into()),
     };
+    if let ExportFormat::Ndjson = format {
+        if starttime.is_some() || endtime.is_some() {
+            return Err(HttpErrorJson::new(
+                Status::BadRequest,
+                "NDJSON exports can't be limited to a time range".to_string(),
+            ));
+        }
+        let filename = format!("aw-bucket-export_{}.ndjson", bucket_id);
+        let export = NdjsonExport::new(datastore.clone(), vec![bucket_id]);
+        return Ok(ExportResponse::Ndjson(NdjsonExportRocket::new(
+            export, filename,
+        )));
+    }
+    if let ExportFormat::Csv = format {
+        let events = match datastore.get_events(&bucket_id, starttime, endtime, None) {
+            Ok(events) => events,
+            Err(err) => return Err(err.into()),
+        };
+        let mut export = CsvExport::new(parse_columns(columns));
+        export.add_events(&bucket_id, events);
+        let filename = format!("aw-bucket-export_{}.csv", bucket_id);
+        return Ok(ExportResponse::Csv(CsvExportRocket::new(export, filename)));
+    }
     /* TODO: Replace expect with http error */
     let events = datastore
-        .get_events(&bucket_id, None, None, None)
+        .get_events(&bucket_id, starttime, endtime, None)
         .expect("Failed to get events for bucket");
     bucket.events = Some(TryVec::new(events));
     export.buckets.insert(bucket_id.clone(), bucket);
 
-    Ok(export.into())
+    Ok(ExportResponse::Json(export.into()))
 }
 
 #[delete("/<bucket_id>")]
-pub fn bucket_delete(bucket_id: String, state: &State<ServerState>) -> Result<(), HttpErrorJson> {
+pub fn bucket_delete(
+    bucket_id: String,
+    access: Access,
+    state: &State<ServerState>,
+) -> Result<(), HttpErrorJson> {
+    access.check(Scope::Admin, Some(&bucket_id))?;
     let datastore = endpoints_get_lock!(state.datastore);
     match datastore.delete_bucket(&bucket_id) {
         Ok(_) => Ok(()),
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::pin::Pin;
+
+use rocket::futures::stream::{self, Stream};
+use rocket::http::ContentType;
+use rocket::http::Header;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::stream::TextStream;
+use rocket::response::{self, Responder};
+use serde_json::Value;
+
+use aw_models::Event;
+
+use crate::endpoints::HttpErrorJson;
+
+/*
+ * Exports events as CSV with one row per event
+ *
+ * The data of each event is flattened into columns, nested objects get their keys joined with
+ * dots ({"url": {"host": "example.com"}} becomes the column data.url.host) and arrays are
+ * written as JSON. If no columns are selected all data keys found in the events are used.
+ *
+ * All events are held in memory, as they are sorted by time across buckets and the columns can
+ * depend on any of them. Only writing the CSV is done in chunks, so a large export is never
+ * held in memory as CSV as well.
+ */
+pub struct CsvExport {
+    columns: Option<Vec<String>>,
+    rows: Vec<(String, Event)>,
+}
+
+fn flatten_value(key: String, value: &Value, flattened: &mut BTreeMap<String, String>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (subkey, subvalue) in map {
+                flatten_value(format!("{}.{}", key, subkey), subvalue, flattened);
+            }
+        }
+        Value::Null => {
+            flattened.insert(key, String::new());
+        }
+        Value::String(s) => {
+            flattened.insert(key, escape_cell(s.clone()));
+        }
+        value => {
+            flattened.insert(key, value.to_string());
+        }
+    }
+}
+
+fn flatten_data(event: &Event) -> BTreeMap<String, String> {
+    let mut flattened = BTreeMap::new();
+    for (key, value) in &event.data {
+        flatten_value(key.clone(), value, &mut flattened);
+    }
+    flattened
+}
+
+/* Number of rows written to each chunk of a streamed export */
+static CHUNK_ROWS: usize = 1000;
+
+/*
+ * Spreadsheet programs evaluate cells starting with one of these characters as formulas, and
+ * string values such as window titles and URLs can come from anyone. Prefixing the cell with a
+ * ' makes it be shown as text. Numbers are never escaped, so that -1 stays a number.
+ */
+fn escape_cell(cell: String) -> String {
+    match cell.chars().next() {
+        Some('=' | '+' | '-' | '@' | '\t' | '\r') => format!("'{}", cell),
+        _ => cell,
+    }
+}
+
+impl CsvExport {
+    /// Creates an export with the given data columns, or all data keys if None
+    pub fn new(columns: Option<Vec<String>>) -> CsvExport {
+        CsvExport {
+            columns,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn add_events(&mut self, bucket_id: &str, events: Vec<Event>) {
+        for event in events {
+            self.rows.push((bucket_id.to_string(), event));
+        }
+    }
+
+    /// Returns the CSV as chunks of complete rows, the first chunk starts with the header
+    ///
+    /// The rows are written as the chunks are taken, the events are already in memory.
+    pub fn into_chunks(mut self) -> CsvChunks {
+        // Oldest events first, which is what you want in a spreadsheet
+        self.rows
+            .sort_by(|(b1, e1), (b2, e2)| e1.timestamp.cmp(&e2.timestamp).then_with(|| b1.cmp(b2)));
+        let columns = match self.columns {
+            Some(columns) => columns,
+            None => {
+                let mut keys = BTreeSet::new();
+                for (_, event) in &self.rows {
+                    keys.extend(flatten_data(event).into_keys());
+                }
+                keys.into_iter().collect()
+            }
+        };
+        CsvChunks {
+            columns,
+            rows: self.rows.into_iter(),
+            header: true,
+        }
+    }
+
+    pub fn into_csv(self) -> Result<String, csv::Error> {
+        self.into_chunks().collect()
+    }
+}
+
+/// Iterator over chunks of a CSV export, each chunk consists of one or more complete rows
+pub struct CsvChunks {
+    columns: Vec<String>,
+    rows: std::vec::IntoIter<(String, Event)>,
+    // If the header still has to be written
+    header: bool,
+}
+
+impl CsvChunks {
+    fn next_chunk(&mut self) -> Result<Option<String>, csv::Error> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let mut empty = true;
+        if self.header {
+            let mut header = vec![
+                "bucket".to_string(),
+                "timestamp".to_string(),
+                "duration".to_string(),
+                "end".to_string(),
+            ];
+            header.extend(self.columns.iter().map(|column| format!("data.{}", column)));
+            writer.write_record(&header)?;
+            self.header = false;
+            empty = false;
+        }
+        for (bucket_id, event) in self.rows.by_ref().take(CHUNK_ROWS) {
+            let data = flatten_data(&event);
+            let mut record = vec![
+                escape_cell(bucket_id),
+                event.timestamp.to_rfc3339(),
+                (event.duration.num_milliseconds() as f64 / 1000.0).to_string(),
+                event.calculate_endtime().to_rfc3339(),
+            ];
+            for column in &self.columns {
+                record.push(data.get(column).cloned().unwrap_or_default());
+            }
+            writer.write_record(&record)?;
+            empty = false;
+        }
+        if empty {
+            return Ok(None);
+        }
+        let csv = writer
+            .into_inner()
+            .map_err(|err| csv::Error::from(err.into_error()))?;
+        Ok(Some(String::from_utf8(csv).unwrap()))
+    }
+}
+
+impl Iterator for CsvChunks {
+    type Item = Result<String, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_chunk() {
+            Ok(Some(chunk)) => Some(Ok(chunk)),
+            Ok(None) => None,
+            Err(err) => {
+                // Don't continue after an error
+                self.rows = Vec::new().into_iter();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Parses a comma separated list of data columns
+pub fn parse_columns(columns: Option<&str>) -> Option<Vec<String>> {
+    columns.map(|columns| {
+        columns
+            .split(',')
+            .map(|column| column.trim().to_string())
+            .filter(|column| !column.is_empty())
+            .collect()
+    })
+}
+
+pub struct CsvExportRocket {
+    inner: CsvExport,
+    filename: String,
+}
+
+impl CsvExportRocket {
+    pub fn new(export: CsvExport, filename: String) -> CsvExportRocket {
+        CsvExportRocket {
+            inner: export,
+            filename,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'r> for CsvExportRocket {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let mut chunks = self.inner.into_chunks();
+        // Errors in the first chunk can still be responded with, later ones cut the export short
+        let first = match chunks.next() {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(err)) => {
+                return HttpErrorJson::new(
+                    Status::InternalServerError,
+                    format!("Failed to export CSV: {}", err),
+                )
+                .respond_to(req)
+            }
+            None => String::new(),
+        };
+        let rest = chunks.map_while(|chunk| match chunk {
+            Ok(chunk) => Some(chunk),
+            Err(err) => {
+                error!("Failed to export CSV: {}", err);
+                None
+            }
+        });
+        let chunks = std::iter::once(first).chain(rest);
+        let chunks: Pin<Box<dyn Stream<Item = String> + Send>> = Box::pin(stream::iter(chunks));
+        let mut response = TextStream(chunks).respond_to(req)?;
+        response.set_header(ContentType::CSV);
+        response.set_header(Header::new(
+            "Content-Disposition",
+            format!("attachment; filename={}", self.filename),
+        ));
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::{parse_columns, CsvExport, CHUNK_ROWS};
+
+    #[test]
+    fn test_csv_export() {
+        let mut data = serde_json::Map::new();
+        data.insert("app".to_string(), json!("Firefox"));
+        data.insert("title".to_string(), json!("Hello, \"world\""));
+        data.insert(
+            "url".to_string(),
+            json!({"host": "example.com", "port": 80}),
+        );
+        data.insert("audible".to_string(), json!(false));
+        let e1 = Event {
+            id: None,
+            timestamp: DateTime::parse_from_rfc3339("2000-01-01T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            duration: Duration::milliseconds(1500),
+            data,
+        };
+        let mut e2 = e1.clone();
+        e2.timestamp = DateTime::parse_from_rfc3339("2000-01-01T00:01:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        e2.data = serde_json::Map::new();
+
+        let mut export = CsvExport::new(None);
+        export.add_events("b", vec![e2.clone(), e1.clone()]);
+        assert_eq!(
+            export.into_csv().unwrap(),
+            "bucket,timestamp,duration,end,data.app,data.audible,data.title,data.url.host,data.url.port\n\
+             b,2000-01-01T00:00:00+00:00,1.5,2000-01-01T00:00:01.500+00:00,Firefox,false,\"Hello, \"\"world\"\"\",example.com,80\n\
+             b,2000-01-01T00:01:00+00:00,1.5,2000-01-01T00:01:01.500+00:00,,,,,\n"
+        );
+
+        let mut export = CsvExport::new(parse_columns(Some("title, missing")));
+        export.add_events("b", vec![e1]);
+        assert_eq!(
+            export.into_csv().unwrap(),
+            "bucket,timestamp,duration,end,data.title,data.missing\n\
+             b,2000-01-01T00:00:00+00:00,1.5,2000-01-01T00:00:01.500+00:00,\"Hello, \"\"world\"\"\",\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_export_formulas() {
+        let mut data = serde_json::Map::new();
+        data.insert("a".to_string(), json!("=HYPERLINK(\"http://example.com\")"));
+        data.insert("b".to_string(), json!("+1"));
+        data.insert("c".to_string(), json!("-1"));
+        data.insert("d".to_string(), json!("@SUM(A1)"));
+        data.insert("e".to_string(), json!("\tx"));
+        data.insert("f".to_string(), json!("\rx"));
+        data.insert("g".to_string(), json!(-1));
+        data.insert("h".to_string(), json!("a=b"));
+        let event = Event {
+            id: None,
+            timestamp: DateTime::parse_from_rfc3339("2000-01-01T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            duration: Duration::seconds(1),
+            data,
+        };
+
+        let mut export = CsvExport::new(None);
+        export.add_events("=b", vec![event]);
+        assert_eq!(
+            export.into_csv().unwrap(),
+            "bucket,timestamp,duration,end,data.a,data.b,data.c,data.d,data.e,data.f,data.g,data.h\n\
+             '=b,2000-01-01T00:00:00+00:00,1,2000-01-01T00:00:01+00:00,\
+             \"'=HYPERLINK(\"\"http://example.com\"\")\",'+1,'-1,'@SUM(A1),'\tx,\"'\rx\",-1,a=b\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_export_chunks() {
+        let event = Event {
+            id: None,
+            timestamp: DateTime::parse_from_rfc3339("2000-01-01T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            duration: Duration::seconds(1),
+            data: serde_json::Map::new(),
+        };
+        let mut export = CsvExport::new(None);
+        export.add_events("b", vec![event; CHUNK_ROWS + 1]);
+        let chunks: Vec<String> = export.into_chunks().map(|chunk| chunk.unwrap()).collect();
+        assert_eq!(chunks.len(), 2);
+        // The header and the first chunk of rows
+        assert_eq!(chunks[0].lines().count(), CHUNK_ROWS + 1);
+        assert_eq!(chunks[1].lines().count(), 1);
+    }
+}
@@ -1,15 +1,32 @@
 use std::collections::HashMap;
 
+use rocket::http::Status;
 use rocket::State;
 
+use aw_datastore::NdjsonExport;
 use aw_models::BucketsExport;
 use aw_models::TryVec;
 
-use crate::endpoints::util::BucketsExportRocket;
+use crate::endpoints::auth::{Access, Scope};
+use crate::endpoints::csv_export::{parse_columns, CsvExport, CsvExportRocket};
+use crate::endpoints::util::{
+    parse_datetime_param, ExportFormat, ExportResponse, NdjsonExportRocket,
+};
 use crate::endpoints::{HttpErrorJson, ServerState};
 
-#[get("/")]
-pub fn buckets_export(state: &State<ServerState>) -> Result<BucketsExportRocket, HttpErrorJson> {
+#[get("/?<format>&<columns>&<start>&<end>")]
+pub fn buckets_export(
+    format: Option<&str>,
+    columns: Option<&str>,
+    start: Option<String>,
+    end: Option<String>,
+    access: Access,
+    state: &State<ServerState>,
+) -> Result<ExportResponse, HttpErrorJson> {
+    access.check(Scope::Read, None)?;
+    let format = ExportFormat::parse(format)?;
+    let starttime = parse_datetime_param("starttime", start)?;
+    let endtime = parse_datetime_param("endtime", end)?;
     let datastore = endpoints_get_lock!(state.datastore);
     let mut export = BucketsExport {
         buckets: HashMap::new(),
@@ -18,8 +35,38 @@ pub fn buckets_export(state: &State<ServerState>) -> Result<BucketsExportRocket,
         Ok(buckets) => buckets,
         Err(err) => return Err(err.into()),
     };
+    buckets.retain(|bucket_id, _| access.allows_bucket(bucket_id));
+    if let ExportFormat::Ndjson = format {
+        if starttime.is_some() || endtime.is_some() {
+            return Err(HttpErrorJson::new(
+                Status::BadRequest,
+                "NDJSON exports can't be limited to a time range".to_string(),
+            ));
+        }
+        let mut bucket_ids: Vec<String> = buckets.into_keys().collect();
+        bucket_ids.sort();
+        let export = NdjsonExport::new(datastore.clone(), bucket_ids);
+        return Ok(ExportResponse::Ndjson(NdjsonExportRocket::new(
+            export,
+            "aw-buckets-export.ndjson".to_string(),
+        )));
+    }
+    if let ExportFormat::Csv = format {
+        let mut export = CsvExport::new(parse_columns(columns));
+        for bid in buckets.keys() {
+            let events = match datastore.get_events(bid, starttime, endtime, None) {
+                Ok(events) => events,
+                Err(err) => return Err(err.into()),
+            };
+            export.add_events(bid, events);
+        }
+        return Ok(ExportResponse::Csv(CsvExportRocket::new(
+            export,
+            "aw-buckets-export.csv".to_string(),
+        )));
+    }
     for (bid, mut bucket) in buckets.drain() {
-        let events = match datastore.get_events(&bid, None, None, None) {
+        let events = match datastore.get_events(&bid, starttime, endtime, None) {
             Ok(events) => events,
             Err(err) => return Err(err.into()),
         };
@@ -27,5 +74,5 @@ pub fn buckets_export(state: &State<ServerState>) -> Result<BucketsExportRocket,
         export.buckets.insert(bid, bucket);
     }
 
-    Ok(export.into())
+    Ok(ExportResponse::Json(export.into()))
 }
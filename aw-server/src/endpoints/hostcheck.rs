@@ -82,8 +82,11 @@ impl Fairing for HostCheck {
             return;
         }
 
-        // Fetch header
-        let hostheader_opt = request.headers().get_one("host");
+        // Fetch header, HTTP/2 requests (over TLS) have an :authority instead
+        let hostheader_opt = match request.headers().get_one("host") {
+            Some(hostheader) => Some(hostheader.to_string()),
+            None => request.host().map(|host| host.to_string()),
+        };
         if hostheader_opt.is_none() {
             info!("Missing 'Host' header, denying request");
             redirect_bad_request(request);
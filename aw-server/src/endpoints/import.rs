@@ -1,37 +1,82 @@
+use rocket::data::{ByteUnit, Data, Limits};
 use rocket::form::Form;
 use rocket::http::Status;
+use rocket::request::{self, FromRequest};
 use rocket::serde::json::Json;
-use rocket::State;
+use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+use rocket::tokio::task;
+use rocket::{Request, State};
 
 use std::sync::Mutex;
 
 use aw_models::BucketsExport;
+use aw_models::ImportConflictStrategy;
+use aw_models::ImportSummary;
 
 use aw_datastore::Datastore;
+use aw_datastore::DatastoreError;
+use aw_datastore::NdjsonImport;
 
+use crate::endpoints::auth::{Access, Scope};
 use crate::endpoints::{HttpErrorJson, ServerState};
 
-fn import(datastore_mutex: &Mutex<Datastore>, import: BucketsExport) -> Result<(), HttpErrorJson> {
+/// Lines of an NDJSON import which are read before they are imported together
+const NDJSON_IMPORT_LINES: usize = 1000;
+
+fn parse_conflict_strategy(
+    conflict: Option<&str>,
+) -> Result<ImportConflictStrategy, HttpErrorJson> {
+    match conflict {
+        Some(conflict) => match conflict.parse() {
+            Ok(strategy) => Ok(strategy),
+            Err(err_msg) => Err(HttpErrorJson::new(Status::BadRequest, err_msg)),
+        },
+        None => Ok(ImportConflictStrategy::default()),
+    }
+}
+
+/// Importing a bucket which already exists with the fail strategy conflicts with the current
+/// state of the datastore, so it is a 409 Conflict rather than a server error
+fn import_conflict(bucket_id: &str) -> HttpErrorJson {
+    let err_msg = format!(
+        "Failed to import bucket: bucket '{}' already exists",
+        bucket_id
+    );
+    warn!("{}", err_msg);
+    HttpErrorJson::new(Status::Conflict, err_msg)
+}
+
+fn import(
+    datastore_mutex: &Mutex<Datastore>,
+    access: &Access,
+    import: BucketsExport,
+    conflict: Option<&str>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    access.check(Scope::Admin, None)?;
+    for bucket_id in import.buckets.keys() {
+        access.check(Scope::Admin, Some(bucket_id))?;
+    }
+    let strategy = parse_conflict_strategy(conflict)?;
     let datastore = endpoints_get_lock!(datastore_mutex);
-    for (_bucketname, bucket) in import.buckets {
-        match datastore.create_bucket(&bucket) {
-            Ok(_) => (),
-            Err(e) => {
-                let err_msg = format!("Failed to import bucket: {:?}", e);
-                warn!("{}", err_msg);
-                return Err(HttpErrorJson::new(Status::InternalServerError, err_msg));
-            }
+    match datastore.import(import, strategy) {
+        Ok(summary) => Ok(Json(summary)),
+        Err(DatastoreError::BucketAlreadyExists(bucket_id)) => Err(import_conflict(&bucket_id)),
+        Err(e) => {
+            let err_msg = format!("Failed to import bucket: {:?}", e);
+            warn!("{}", err_msg);
+            Err(HttpErrorJson::new(Status::InternalServerError, err_msg))
         }
     }
-    Ok(())
 }
 
-#[post("/", data = "<json_data>", format = "application/json")]
+#[post("/?<conflict>", data = "<json_data>", format = "application/json")]
 pub fn bucket_import_json(
     state: &State<ServerState>,
+    access: Access,
     json_data: Json<BucketsExport>,
-) -> Result<(), HttpErrorJson> {
-    import(&state.datastore, json_data.into_inner())
+    conflict: Option<&str>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    import(&state.datastore, &access, json_data.into_inner(), conflict)
 }
 
 #[derive(FromForm)]
@@ -44,10 +89,139 @@ pub struct ImportForm {
     import: Json<BucketsExport>,
 }
 
-#[post("/", data = "<form>", format = "multipart/form-data")]
+#[post("/?<conflict>", data = "<form>", format = "multipart/form-data")]
 pub fn bucket_import_form(
     state: &State<ServerState>,
+    access: Access,
     form: Form<ImportForm>,
-) -> Result<(), HttpErrorJson> {
-    import(&state.datastore, form.into_inner().import.into_inner())
+    conflict: Option<&str>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    import(
+        &state.datastore,
+        &access,
+        form.into_inner().import.into_inner(),
+        conflict,
+    )
+}
+
+/// Imports the lines on the blocking thread pool, as the datastore calls are blocking
+async fn push_ndjson_lines(
+    mut import: NdjsonImport,
+    lines: Vec<String>,
+) -> Result<NdjsonImport, HttpErrorJson> {
+    let res = task::spawn_blocking(move || -> Result<NdjsonImport, DatastoreError> {
+        for line in &lines {
+            import.push_line(line)?;
+        }
+        Ok(import)
+    })
+    .await;
+    match res {
+        Ok(Ok(import)) => Ok(import),
+        Ok(Err(DatastoreError::BucketAlreadyExists(bucket_id))) => Err(import_conflict(&bucket_id)),
+        Ok(Err(err)) => {
+            warn!("Failed to import NDJSON: {:?}", err);
+            Err(err.into())
+        }
+        Err(err) => Err(HttpErrorJson::new(
+            Status::InternalServerError,
+            format!("Failed to import NDJSON: {}", err),
+        )),
+    }
+}
+
+/// The Content-Length header of a request, if it has a valid one
+pub struct ContentLength(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ContentLength {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<ContentLength, ()> {
+        let len = request
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|len| len.parse().ok());
+        request::Outcome::Success(ContentLength(len))
+    }
+}
+
+fn ndjson_too_large(limit: ByteUnit, imported: bool) -> HttpErrorJson {
+    let mut err_msg = format!("NDJSON import is larger than the limit of {}", limit);
+    if imported {
+        err_msg.push_str(", the events before the limit have been imported");
+    }
+    warn!("{}", err_msg);
+    HttpErrorJson::new(Status::PayloadTooLarge, err_msg)
+}
+
+/*
+ * Imports NDJSON as it is read, NDJSON_IMPORT_LINES lines at a time.
+ *
+ * An import which is larger than the "ndjson" limit is rejected with nothing imported when its
+ * Content-Length tells so. Without a Content-Length it is only noticed once the limit is reached,
+ * by then the batches read before have already been imported and are kept.
+ */
+#[post("/?<conflict>", data = "<data>", format = "application/x-ndjson")]
+pub async fn bucket_import_ndjson(
+    state: &State<ServerState>,
+    access: Access,
+    limits: &Limits,
+    content_length: ContentLength,
+    data: Data<'_>,
+    conflict: Option<&str>,
+) -> Result<Json<ImportSummary>, HttpErrorJson> {
+    // The buckets in the import are only known once it has been read
+    access.check_all_buckets(Scope::Admin)?;
+    let strategy = parse_conflict_strategy(conflict)?;
+    let limit = limits.get("ndjson").unwrap_or(Limits::JSON);
+    if let ContentLength(Some(len)) = content_length {
+        if len > limit.as_u64() {
+            return Err(ndjson_too_large(limit, false));
+        }
+    }
+    let datastore = endpoints_get_lock!(state.datastore).clone();
+    let mut import = NdjsonImport::new(datastore, strategy);
+    // Read the import a number of lines at a time so that it never has to be in memory all at
+    // once. One byte more than the limit is read to tell an import which is too large apart from
+    // one which is exactly at the limit.
+    let mut reader = BufReader::new(data.open(ByteUnit::from(limit.as_u64().saturating_add(1))));
+    let mut read: u64 = 0;
+    let mut imported = false;
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let len = match reader.read_line(&mut line).await {
+            Ok(len) => len,
+            Err(err) => {
+                let err_msg = format!("Failed to read NDJSON import: {}", err);
+                warn!("{}", err_msg);
+                return Err(HttpErrorJson::new(Status::BadRequest, err_msg));
+            }
+        };
+        read += len as u64;
+        // Stop before the line cut off at the limit, and the batch it is in, are imported
+        if read > limit.as_u64() {
+            return Err(ndjson_too_large(limit, imported));
+        }
+        if len > 0 {
+            lines.push(line);
+        }
+        if len == 0 || lines.len() >= NDJSON_IMPORT_LINES {
+            import = push_ndjson_lines(import, std::mem::take(&mut lines)).await?;
+            imported = true;
+        }
+        if len == 0 {
+            break;
+        }
+    }
+    match task::spawn_blocking(move || import.finish()).await {
+        Ok(Ok(summary)) => Ok(Json(summary)),
+        Ok(Err(DatastoreError::BucketAlreadyExists(bucket_id))) => Err(import_conflict(&bucket_id)),
+        Ok(Err(err)) => Err(err.into()),
+        Err(err) => Err(HttpErrorJson::new(
+            Status::InternalServerError,
+            format!("Failed to import NDJSON: {}", err),
+        )),
+    }
 }
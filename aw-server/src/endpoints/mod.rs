@@ -7,6 +7,7 @@ use rocket::serde::json::Json;
 use rocket::State;
 
 use crate::config::AWConfig;
+use crate::webhooks;
 
 use aw_datastore::Datastore;
 use aw_models::Info;
@@ -19,13 +20,17 @@ pub struct ServerState {
 
 #[macro_use]
 mod util;
+mod auth;
 mod bucket;
 mod cors;
+mod csv_export;
 mod export;
 mod hostcheck;
 mod import;
 mod query;
 mod settings;
+mod stream;
+mod tokens;
 
 pub use util::HttpErrorJson;
 
@@ -92,10 +97,23 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
     );
     let cors = cors::cors(&config);
     let hostcheck = hostcheck::HostCheck::new(&config);
+    let token_auth = auth::TokenAuth::new(&config);
+    let datastore = server_state.datastore.lock().unwrap().clone();
+    let broadcaster = stream::EventBroadcaster::new(&datastore);
+    webhooks::start(datastore.clone(), config.webhooks.clone());
+    let tokens = auth::TokenStore::load(datastore);
     rocket::custom(config.to_rocket_config())
         .attach(cors.clone())
         .attach(hostcheck)
+        .attach(token_auth)
         .manage(cors)
+        .manage(query::QueryRunner::new(
+            query::QUERY_CACHE_SIZE,
+            query::query_parallelism(),
+            config.query_limits(),
+        ))
+        .manage(broadcaster)
+        .manage(tokens)
         .manage(server_state)
         .manage(config)
         .mount(
@@ -129,9 +147,22 @@ pub fn build_rocket(server_state: ServerState, config: AWConfig) -> rocket::Rock
         .mount("/api/0/query", routes![query::query])
         .mount(
             "/api/0/import",
-            routes![import::bucket_import_json, import::bucket_import_form],
+            routes![
+                import::bucket_import_json,
+                import::bucket_import_form,
+                import::bucket_import_ndjson
+            ],
         )
         .mount("/api/0/export", routes![export::buckets_export])
+        .mount("/api/0/stream", routes![stream::event_stream])
+        .mount(
+            "/api/0/tokens",
+            routes![
+                tokens::tokens_list,
+                tokens::token_create,
+                tokens::token_delete
+            ],
+        )
         .mount(
             "/api/0/settings",
             routes![
@@ -1,29 +1,188 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use rocket::http::Status;
+use rocket::serde::json::serde_json::Map;
 use rocket::serde::json::{json, Json, Value};
+use rocket::tokio::sync::Semaphore;
+use rocket::tokio::task;
 use rocket::State;
 
 use aw_models::Query;
+use aw_query::QueryCache;
+use aw_query::QueryError;
+use aw_query::QueryLimits;
+use aw_query::QueryOptions;
+use aw_query::QueryProfile;
+use aw_transform::Calendar;
 
+use crate::endpoints::auth::{Access, Scope};
 use crate::endpoints::{HttpErrorJson, ServerState};
 
+/// Max number of query results to keep cached
+pub const QUERY_CACHE_SIZE: usize = 100;
+
+/*
+ * Runs the queries of all requests
+ *
+ * Each timeperiod of a query is evaluated as a separate task on the blocking thread pool, with
+ * at most as many tasks running at once as there are permits, no matter how many requests there
+ * are. The results are cached in the query cache, and each timeperiod is evaluated within the
+ * configured query limits.
+ *
+ * Profiled queries always run without the cache, as a cached result has no timings, and so do
+ * queries by clients which can only read some of the buckets.
+ */
+pub struct QueryRunner {
+    cache: Arc<QueryCache>,
+    permits: Arc<Semaphore>,
+    limits: QueryLimits,
+}
+
+impl QueryRunner {
+    pub fn new(cache_size: usize, max_parallel: usize, limits: QueryLimits) -> QueryRunner {
+        QueryRunner {
+            cache: Arc::new(QueryCache::new(cache_size, limits.clone())),
+            permits: Arc::new(Semaphore::new(max_parallel)),
+            limits,
+        }
+    }
+}
+
+/// Number of timeperiods to evaluate in parallel, one per CPU
+pub fn query_parallelism() -> usize {
+    match std::thread::available_parallelism() {
+        Ok(n) => n.get(),
+        Err(_) => 1,
+    }
+}
+
 #[post("/", data = "<query_req>", format = "application/json")]
-pub fn query(query_req: Json<Query>, state: &State<ServerState>) -> Result<Value, HttpErrorJson> {
-    let query_code = query_req.0.query.join("\n");
-    let intervals = &query_req.0.timeperiods;
+pub async fn query(
+    query_req: Json<Query>,
+    access: Access,
+    state: &State<ServerState>,
+    runner: &State<QueryRunner>,
+) -> Result<Value, HttpErrorJson> {
+    access.check(Scope::Read, None)?;
+    let mut limits = runner.limits.clone();
+    limits.buckets = access.query_buckets();
+    let query_code = Arc::new(query_req.0.query.join("\n"));
+    let profile = query_req.0.profile;
+    let calendar = match Calendar::new(
+        query_req.0.timezone.as_deref().unwrap_or("+00:00"),
+        query_req.0.day_start.as_deref().unwrap_or("00:00"),
+    ) {
+        Ok(calendar) => calendar,
+        Err(err) => return Err(HttpErrorJson::new(Status::BadRequest, err)),
+    };
+    let options = QueryOptions {
+        calendar,
+        limits,
+        profile,
+    };
+    // The datastore can be used from multiple threads at once through clones of it, so the lock
+    // is only needed to get one
+    let datastore = {
+        let datastore = endpoints_get_lock!(state.datastore);
+        datastore.clone()
+    };
+
+    // Set once a timeperiod has failed, the timeperiods which haven't started by then are skipped
+    // as the request fails anyway
+    let failed = Arc::new(AtomicBool::new(false));
+    let mut tasks = Vec::new();
+    for interval in query_req.0.timeperiods {
+        let permit = match runner.permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(err) => {
+                return Err(HttpErrorJson::new(
+                    Status::InternalServerError,
+                    format!("Failed to schedule query: {}", err),
+                ))
+            }
+        };
+        if failed.load(Ordering::Relaxed) {
+            break;
+        }
+        let cache = runner.cache.clone();
+        let query_code = query_code.clone();
+        let datastore = datastore.clone();
+        let options = options.clone();
+        let failed = failed.clone();
+        tasks.push(task::spawn_blocking(move || {
+            if failed.load(Ordering::Relaxed) {
+                return None;
+            }
+            let result = if options.profile || options.limits.buckets.is_some() {
+                aw_query::query_with_options(&query_code, &interval, &datastore, &options)
+                    .map(|output| (output.result, output.profile))
+            } else {
+                cache
+                    .query_in_calendar(&query_code, &interval, &options.calendar, &datastore)
+                    .map(|data| (data, None))
+            };
+            drop(permit);
+            if result.is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+            Some(result)
+        }));
+    }
+
     let mut results = Vec::new();
-    let datastore = endpoints_get_lock!(state.datastore);
-    for interval in intervals {
-        let result = match aw_query::query(&query_code, &interval, &datastore) {
-            Ok(data) => data,
-            Err(e) => {
+    let mut profiles: Vec<QueryProfile> = Vec::new();
+    for task in tasks {
+        let result = match task.await {
+            Ok(Some(Ok((data, profile)))) => {
+                profiles.extend(profile);
+                data
+            }
+            // Skipped as a later timeperiod failed, its error is returned
+            Ok(None) => continue,
+            Ok(Some(Err(e))) => {
                 warn!("Query failed: {:?}", e);
+                return Err(query_error(&e, &query_code));
+            }
+            Err(err) => {
+                failed.store(true, Ordering::Relaxed);
                 return Err(HttpErrorJson::new(
                     Status::InternalServerError,
-                    e.to_string(),
+                    format!("Query task failed: {}", err),
                 ));
             }
         };
         results.push(result);
     }
-    Ok(json!(results))
+    if profile {
+        Ok(json!({ "result": results, "profile": profiles }))
+    } else {
+        Ok(json!(results))
+    }
+}
+
+/// A failed query is the fault of the query, of the client reading buckets it has no access to
+/// or of the datastore, the error tells what kind of error it was and where in the query it
+/// happened
+fn query_error(err: &QueryError, code: &str) -> HttpErrorJson {
+    let mut details = Map::new();
+    details.insert("kind".to_string(), json!(err.kind()));
+    if let Some(span) = err.span() {
+        details.insert(
+            "span".to_string(),
+            json!({
+                "line": span.line,
+                "column": span.column,
+                "lo": span.lo,
+                "hi": span.hi,
+                "snippet": span.snippet(code),
+            }),
+        );
+    }
+    let status = match err.kind() {
+        "AccessDenied" => Status::Forbidden,
+        "DatastoreError" => Status::InternalServerError,
+        _ => Status::BadRequest,
+    };
+    HttpErrorJson::with_details(status, err.to_string(), details)
 }
@@ -7,6 +7,7 @@ use std::sync::MutexGuard;
 use aw_datastore::Datastore;
 use aw_models::{Key, KeyValue};
 
+use crate::endpoints::auth::{Access, Scope};
 use crate::endpoints::HttpErrorJson;
 
 fn parse_key(key: String) -> Result<String, HttpErrorJson> {
@@ -24,8 +25,10 @@ fn parse_key(key: String) -> Result<String, HttpErrorJson> {
 #[post("/", data = "<message>", format = "application/json")]
 pub fn setting_set(
     state: &State<ServerState>,
+    access: Access,
     message: Json<KeyValue>,
 ) -> Result<Status, HttpErrorJson> {
+    access.check(Scope::Admin, None)?;
     let data = message.into_inner();
 
     let setting_key = parse_key(data.key)?;
@@ -40,7 +43,11 @@ pub fn setting_set(
 }
 
 #[get("/")]
-pub fn settings_list_get(state: &State<ServerState>) -> Result<Json<Vec<Key>>, HttpErrorJson> {
+pub fn settings_list_get(
+    state: &State<ServerState>,
+    access: Access,
+) -> Result<Json<Vec<Key>>, HttpErrorJson> {
+    access.check(Scope::Read, None)?;
     let datastore = endpoints_get_lock!(state.datastore);
     let queryresults = match datastore.get_keys_starting("settings.%") {
         Ok(result) => Ok(result),
@@ -58,8 +65,10 @@ pub fn settings_list_get(state: &State<ServerState>) -> Result<Json<Vec<Key>>, H
 #[get("/<key>")]
 pub fn setting_get(
     state: &State<ServerState>,
+    access: Access,
     key: String,
 ) -> Result<Json<KeyValue>, HttpErrorJson> {
+    access.check(Scope::Read, None)?;
     let setting_key = parse_key(key)?;
 
     let datastore = endpoints_get_lock!(state.datastore);
@@ -71,7 +80,12 @@ pub fn setting_get(
 }
 
 #[delete("/<key>")]
-pub fn setting_delete(state: &State<ServerState>, key: String) -> Result<(), HttpErrorJson> {
+pub fn setting_delete(
+    state: &State<ServerState>,
+    access: Access,
+    key: String,
+) -> Result<(), HttpErrorJson> {
+    access.check(Scope::Admin, None)?;
     let setting_key = parse_key(key)?;
 
     let datastore = endpoints_get_lock!(state.datastore);
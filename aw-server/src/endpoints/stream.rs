@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::{Shutdown, State};
+
+use aw_datastore::{Datastore, EventNotification};
+
+use crate::endpoints::auth::{Access, Scope};
+use crate::endpoints::HttpErrorJson;
+
+/// Number of notifications kept for streams which are behind before they start missing some
+const STREAM_CAPACITY: usize = 1024;
+
+/// Forwards the notifications of the datastore to all open event streams
+pub struct EventBroadcaster {
+    sender: broadcast::Sender<Arc<EventNotification>>,
+}
+
+impl EventBroadcaster {
+    pub fn new(datastore: &Datastore) -> Self {
+        let (sender, _) = broadcast::channel(STREAM_CAPACITY);
+        let worker_sender = sender.clone();
+        datastore.subscribe(move |notification| {
+            // Only fails when there are no open streams
+            let _ = worker_sender.send(Arc::new(notification.clone()));
+        });
+        EventBroadcaster { sender }
+    }
+}
+
+/// Server-sent events for every change to the events of the buckets, or of all buckets if none
+/// are specified, of the buckets the client has access to
+///
+/// The event name is the kind of change and the data is the notification as JSON. A stream which
+/// falls too far behind gets a "lagged" event with the number of missed notifications.
+#[get("/?<bucket>")]
+pub fn event_stream(
+    bucket: Vec<String>,
+    access: Access,
+    broadcaster: &State<EventBroadcaster>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], HttpErrorJson> {
+    access.check(Scope::Read, None)?;
+    for bucket_id in &bucket {
+        access.check(Scope::Read, Some(bucket_id))?;
+    }
+    let mut receiver = broadcaster.sender.subscribe();
+    Ok(EventStream! {
+        loop {
+            let notification = select! {
+                // Notifications already received are sent before closing on shutdown
+                biased;
+                msg = receiver.recv() => match msg {
+                    Ok(notification) => notification,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(missed)) => {
+                        yield Event::data(missed.to_string()).event("lagged");
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            let bucket_id = notification.bucket_id();
+            if !bucket.is_empty() && !bucket.iter().any(|b| b == bucket_id) {
+                continue;
+            }
+            if !access.allows_bucket(bucket_id) {
+                continue;
+            }
+            yield Event::json(&*notification).event(notification.kind());
+        }
+    })
+}
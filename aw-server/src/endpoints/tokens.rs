@@ -0,0 +1,68 @@
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::auth::{
+    default_buckets, default_scopes, Access, ApiToken, Scope, TokenStore,
+};
+use crate::endpoints::HttpErrorJson;
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    name: String,
+    #[serde(default = "default_scopes")]
+    scopes: Vec<Scope>,
+    #[serde(default = "default_buckets")]
+    buckets: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    info: ApiToken,
+    // Only ever returned here, as only its hash is stored
+    token: String,
+}
+
+#[get("/")]
+pub fn tokens_list(
+    access: Access,
+    tokens: &State<TokenStore>,
+) -> Result<Json<Vec<ApiToken>>, HttpErrorJson> {
+    access.require_admin()?;
+    Ok(Json(tokens.list()))
+}
+
+#[post("/", data = "<message>", format = "application/json")]
+pub fn token_create(
+    access: Access,
+    message: Json<TokenRequest>,
+    tokens: &State<TokenStore>,
+) -> Result<Json<CreatedToken>, HttpErrorJson> {
+    access.require_admin()?;
+    let request = message.into_inner();
+    if request.scopes.is_empty() || request.buckets.is_empty() {
+        return Err(HttpErrorJson::new(
+            Status::BadRequest,
+            "A token needs at least one scope and one bucket pattern".to_string(),
+        ));
+    }
+    match tokens.create(request.name, request.scopes, request.buckets) {
+        Ok((info, token)) => Ok(Json(CreatedToken { info, token })),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[delete("/<id>")]
+pub fn token_delete(
+    access: Access,
+    id: String,
+    tokens: &State<TokenStore>,
+) -> Result<(), HttpErrorJson> {
+    access.require_admin()?;
+    match tokens.revoke(&id) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
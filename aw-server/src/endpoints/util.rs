@@ -1,19 +1,31 @@
 use std::io::Cursor;
+use std::pin::Pin;
 
+use rocket::futures::stream::{self, Stream};
 use rocket::http::ContentType;
 use rocket::http::Header;
 use rocket::http::Status;
 use rocket::request::Request;
+use rocket::response::stream::TextStream;
 use rocket::response::{self, Responder, Response};
+use rocket::tokio::task;
 use serde::Serialize;
 
+use chrono::{DateTime, Utc};
+
+use aw_datastore::NdjsonExport;
 use aw_models::BucketsExport;
 
+use crate::endpoints::csv_export::CsvExportRocket;
+
 #[derive(Serialize, Debug)]
 pub struct HttpErrorJson {
     #[serde(skip_serializing)]
     status: Status,
     message: String,
+    // Extra fields of the error, added to the body next to the message
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
 impl HttpErrorJson {
@@ -21,6 +33,19 @@ impl HttpErrorJson {
         HttpErrorJson {
             status: status,
             message: format!("{}", err),
+            details: None,
+        }
+    }
+
+    pub fn with_details(
+        status: Status,
+        err: String,
+        details: serde_json::Map<String, serde_json::Value>,
+    ) -> HttpErrorJson {
+        HttpErrorJson {
+            status,
+            message: err,
+            details: Some(details),
         }
     }
 }
@@ -67,6 +92,106 @@ impl<'r> Responder<'r, 'static> for BucketsExportRocket {
     }
 }
 
+pub struct NdjsonExportRocket {
+    inner: NdjsonExport,
+    filename: String,
+}
+
+impl NdjsonExportRocket {
+    pub fn new(export: NdjsonExport, filename: String) -> NdjsonExportRocket {
+        NdjsonExportRocket {
+            inner: export,
+            filename,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'r> for NdjsonExportRocket {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        // Read the chunks on the blocking thread pool as the datastore calls are blocking
+        let chunks = stream::unfold(Some(self.inner), |export| async move {
+            let mut export = export?;
+            let (chunk, export) = task::spawn_blocking(move || (export.next(), export))
+                .await
+                .ok()?;
+            match chunk {
+                Some(Ok(chunk)) => Some((chunk, Some(export))),
+                Some(Err(err)) => {
+                    // Too late to respond with an error, so the export will be cut short
+                    error!("Failed to export events: {:?}", err);
+                    None
+                }
+                None => None,
+            }
+        });
+        let chunks: Pin<Box<dyn Stream<Item = String> + Send>> = Box::pin(chunks);
+        let mut response = TextStream(chunks).respond_to(req)?;
+        response.set_header(ContentType::new("application", "x-ndjson"));
+        response.set_header(Header::new(
+            "Content-Disposition",
+            format!("attachment; filename={}", self.filename),
+        ));
+        Ok(response)
+    }
+}
+
+pub enum ExportResponse {
+    Json(BucketsExportRocket),
+    Ndjson(NdjsonExportRocket),
+    Csv(CsvExportRocket),
+}
+
+impl<'r> Responder<'r, 'r> for ExportResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        match self {
+            ExportResponse::Json(export) => export.respond_to(req),
+            ExportResponse::Ndjson(export) => export.respond_to(req),
+            ExportResponse::Csv(export) => export.respond_to(req),
+        }
+    }
+}
+
+pub enum ExportFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn parse(format: Option<&str>) -> Result<ExportFormat, HttpErrorJson> {
+        match format {
+            None | Some("json") => Ok(ExportFormat::Json),
+            Some("ndjson") => Ok(ExportFormat::Ndjson),
+            Some("csv") => Ok(ExportFormat::Csv),
+            Some(format) => Err(HttpErrorJson::new(
+                Status::BadRequest,
+                format!("Unsupported export format '{}'", format),
+            )),
+        }
+    }
+}
+
+/// Parses an optional datetime query parameter, which needs to be in rfc3339 format
+pub fn parse_datetime_param(
+    name: &str,
+    dt_str: Option<String>,
+) -> Result<Option<DateTime<Utc>>, HttpErrorJson> {
+    match dt_str {
+        Some(dt_str) => match DateTime::parse_from_rfc3339(&dt_str) {
+            Ok(dt) => Ok(Some(dt.with_timezone(&Utc))),
+            Err(e) => {
+                let err_msg = format!(
+                    "Failed to parse {}, datetime needs to be in rfc3339 format: {}",
+                    name, e
+                );
+                warn!("{}", err_msg);
+                Err(HttpErrorJson::new(Status::BadRequest, err_msg))
+            }
+        },
+        None => Ok(None),
+    }
+}
+
 use aw_datastore::DatastoreError;
 
 impl Into<HttpErrorJson> for DatastoreError {
@@ -91,6 +216,7 @@ impl Into<HttpErrorJson> for DatastoreError {
             DatastoreError::InternalError(msg) => {
                 HttpErrorJson::new(Status::InternalServerError, msg)
             }
+            DatastoreError::InvalidData(msg) => HttpErrorJson::new(Status::BadRequest, msg),
             // When upgrade is disabled
             DatastoreError::Uninitialized(msg) => {
                 HttpErrorJson::new(Status::InternalServerError, msg)
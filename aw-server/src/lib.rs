@@ -30,6 +30,8 @@ pub mod device_id;
 pub mod dirs;
 pub mod endpoints;
 pub mod logging;
+pub mod tls;
+pub mod webhooks;
 
 #[cfg(target_os = "android")]
 pub mod android;
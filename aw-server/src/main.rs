@@ -2,9 +2,10 @@
 extern crate log;
 
 use std::env;
+use std::process;
 
 use clap::crate_version;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use aw_server::*;
 
@@ -46,6 +47,58 @@ struct Opts {
     /// Don't import from aw-server-python if no aw-server-rust db found
     #[clap(long)]
     no_legacy_import: bool,
+
+    /// Path to the PEM encoded TLS certificate chain, serves HTTPS along with --tls-key
+    #[clap(long)]
+    tls_cert: Option<String>,
+
+    /// Path to the PEM encoded TLS private key, serves HTTPS along with --tls-cert
+    #[clap(long)]
+    tls_key: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a self-signed TLS certificate and private key at --tls-cert and --tls-key, or in
+    /// the config dir
+    GenerateCert {
+        /// Hostname or IP address the certificate is valid for, can be given multiple times,
+        /// defaults to localhost and the hostname of this machine
+        #[clap(long)]
+        hostname: Vec<String>,
+    },
+}
+
+fn generate_cert(config: &config::AWConfig, hostnames: Vec<String>) {
+    let config_dir = dirs::get_config_dir().expect("Failed to get config dir");
+    let cert_path = match &config.tls_cert {
+        Some(path) => PathBuf::from(path),
+        None => config_dir.join("tls-cert.pem"),
+    };
+    let key_path = match &config.tls_key {
+        Some(path) => PathBuf::from(path),
+        None => config_dir.join("tls-key.pem"),
+    };
+    let hostnames = if hostnames.is_empty() {
+        tls::default_hostnames()
+    } else {
+        hostnames
+    };
+
+    if let Err(err) = tls::generate_self_signed(&hostnames, &cert_path, &key_path) {
+        error!("{}", err);
+        process::exit(1);
+    }
+    println!(
+        "Generated a self-signed certificate for {}",
+        hostnames.join(", ")
+    );
+    println!("To serve HTTPS with it, add these lines to the config file:");
+    println!("tls_cert = {:?}", cert_path.to_string_lossy());
+    println!("tls_key = {:?}", key_path.to_string_lossy());
 }
 
 #[rocket::main]
@@ -75,6 +128,25 @@ async fn main() -> Result<(), rocket::Error> {
         config.port = port.parse().unwrap();
     }
 
+    // set TLS certificate and key if overridden
+    if let Some(tls_cert) = opts.tls_cert {
+        config.tls_cert = Some(tls_cert);
+    }
+    if let Some(tls_key) = opts.tls_key {
+        config.tls_key = Some(tls_key);
+    }
+
+    // Generating a certificate only needs the paths, so it works while just one of them is set
+    if let Some(Command::GenerateCert { hostname }) = opts.command {
+        generate_cert(&config, hostname);
+        return Ok(());
+    }
+
+    if let Err(err) = config.validate() {
+        error!("Invalid config: {}", err);
+        process::exit(1);
+    }
+
     // Set db path if overridden
     let db_path: String = if let Some(dbpath) = opts.dbpath.clone() {
         dbpath
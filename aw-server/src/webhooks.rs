@@ -0,0 +1,646 @@
+use std::collections::HashSet;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use aw_datastore::{Datastore, EventNotification};
+
+use crate::config::WebhookConfig;
+
+/// Undelivered batches are stored in the key_value table under keys with this prefix, so they
+/// survive a restart
+const QUEUE_KEY_PREFIX: &str = "webhooks.queue.";
+
+/// Longest time between two attempts to deliver a batch, in seconds
+const MAX_RETRY_INTERVAL: u64 = 60 * 60;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Notifications which can wait for the webhook thread, further ones are dropped rather than
+/// holding up the datastore worker which sends them
+const MAX_PENDING_NOTIFICATIONS: usize = 10000;
+
+/// Longest time a notification added to an open batch goes without the batch being stored
+const BATCH_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A batch of notifications waiting to be delivered to a webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    #[serde(skip)]
+    key: String,
+    /// Index of the webhook in the config, along with its url in case the config has changed
+    webhook: usize,
+    url: String,
+    payload: Value,
+    created: DateTime<Utc>,
+    attempts: u32,
+    next_attempt: DateTime<Utc>,
+}
+
+/// Notifications collected for a webhook which are not yet queued for delivery
+///
+/// The batch is stored with the queued ones as a delivery which is due at the end of the batch
+/// interval, so it isn't lost if the server stops before then. Rather than storing it again for
+/// every notification, it is stored at most every BATCH_SAVE_INTERVAL while it grows, so only
+/// the last few seconds of notifications can be lost.
+struct Batch {
+    delivery: Delivery,
+    deadline: Instant,
+    // When the batch has to be stored, None if the stored batch is up to date
+    save_at: Option<Instant>,
+}
+
+/// Starts delivering the bucket creations and deletions and the event insertions and updates of
+/// the datastore to the webhooks, along with any batches left undelivered since the last run
+///
+/// Each webhook is sent a POST request with a JSON object with the notifications as
+/// "notifications", in the same format as the event stream. Failed deliveries are retried with an exponential backoff, and the batches of a webhook are delivered
+/// in order. If deliveries fall too far behind, new notifications are dropped with a warning.
+pub fn start(datastore: Datastore, webhooks: Vec<WebhookConfig>) {
+    if webhooks.is_empty() {
+        return;
+    }
+    let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_NOTIFICATIONS);
+    datastore.subscribe(move |notification| match notification {
+        EventNotification::Inserted { .. }
+        | EventNotification::LastEventReplaced { .. }
+        | EventNotification::BucketCreated { .. }
+        | EventNotification::BucketDeleted { .. } => {
+            match sender.try_send(notification.clone()) {
+                Ok(()) => (),
+                Err(TrySendError::Full(notification)) => warn!(
+                    "Webhook deliveries are falling behind, dropping {} notification for {}",
+                    notification.kind(),
+                    notification.bucket_id()
+                ),
+                // The webhook thread has stopped
+                Err(TrySendError::Disconnected(_)) => (),
+            }
+        }
+        _ => (),
+    });
+    thread::spawn(move || {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to create webhook HTTP client");
+        let mut worker = WebhookWorker {
+            datastore,
+            client,
+            batches: webhooks.iter().map(|_| None).collect(),
+            webhooks,
+            queue: Vec::new(),
+        };
+        worker.load_queue();
+        worker.run(receiver);
+    });
+}
+
+/// Whether the bucket id matches the pattern, where a * matches any characters
+pub fn bucket_matches(pattern: &str, bucket_id: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match bucket_id.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No wildcard, so it has to be an exact match
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn retry_delay(webhook: &WebhookConfig, attempts: u32) -> chrono::Duration {
+    let factor = 1u64.checked_shl(attempts - 1).unwrap_or(u64::MAX);
+    let secs = webhook
+        .retry_interval
+        .saturating_mul(factor)
+        .min(MAX_RETRY_INTERVAL);
+    chrono::Duration::seconds(secs as i64)
+}
+
+struct WebhookWorker {
+    datastore: Datastore,
+    client: reqwest::blocking::Client,
+    webhooks: Vec<WebhookConfig>,
+    batches: Vec<Option<Batch>>,
+    // Ordered by creation
+    queue: Vec<Delivery>,
+}
+
+impl WebhookWorker {
+    fn run(&mut self, receiver: Receiver<EventNotification>) {
+        loop {
+            let now = Instant::now();
+            for i in 0..self.webhooks.len() {
+                if let Some(batch) = &self.batches[i] {
+                    if batch.deadline <= now {
+                        self.queue_batch(i);
+                    } else if batch.save_at.map_or(false, |save_at| save_at <= now) {
+                        self.save(&batch.delivery);
+                        if let Some(batch) = &mut self.batches[i] {
+                            batch.save_at = None;
+                        }
+                    }
+                }
+            }
+            let next_attempt = self.deliver();
+
+            let mut timeout = next_attempt.map(|next_attempt| {
+                (next_attempt - Utc::now())
+                    .to_std()
+                    .unwrap_or(Duration::ZERO)
+            });
+            let now = Instant::now();
+            for batch in self.batches.iter().flatten() {
+                let due = batch
+                    .save_at
+                    .map_or(batch.deadline, |save_at| save_at.min(batch.deadline));
+                let until_due = due.saturating_duration_since(now);
+                timeout = Some(timeout.map_or(until_due, |t| t.min(until_due)));
+            }
+            let notification = match timeout {
+                Some(timeout) => match receiver.recv_timeout(timeout) {
+                    Ok(notification) => notification,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match receiver.recv() {
+                    Ok(notification) => notification,
+                    Err(_) => break,
+                },
+            };
+            self.add(notification);
+        }
+        debug!("Datastore closed, stopping webhook deliveries");
+    }
+
+    fn add(&mut self, notification: EventNotification) {
+        let value = serde_json::to_value(&notification).unwrap();
+        for i in 0..self.webhooks.len() {
+            let webhook = &self.webhooks[i];
+            if !webhook
+                .buckets
+                .iter()
+                .any(|pattern| bucket_matches(pattern, notification.bucket_id()))
+            {
+                continue;
+            }
+            let mut batch = self.batches[i].take().unwrap_or_else(|| {
+                let now = Utc::now();
+                Batch {
+                    delivery: Delivery {
+                        key: format!("{}{}", QUEUE_KEY_PREFIX, Uuid::new_v4()),
+                        webhook: i,
+                        url: webhook.url.clone(),
+                        payload: json!({ "notifications": [] }),
+                        created: now,
+                        attempts: 0,
+                        next_attempt: now
+                            + chrono::Duration::seconds(webhook.batch_interval as i64),
+                    },
+                    deadline: Instant::now() + Duration::from_secs(webhook.batch_interval),
+                    save_at: None,
+                }
+            });
+            let notifications = batch.delivery.payload["notifications"]
+                .as_array_mut()
+                .unwrap();
+            notifications.push(value.clone());
+            let full = notifications.len() >= webhook.max_batch_size;
+            batch
+                .save_at
+                .get_or_insert_with(|| Instant::now() + BATCH_SAVE_INTERVAL);
+            self.batches[i] = Some(batch);
+            if full {
+                self.queue_batch(i);
+            }
+        }
+    }
+
+    /// Moves the batch of the webhook to the queue, from where it is delivered
+    fn queue_batch(&mut self, webhook_index: usize) {
+        let mut delivery = match self.batches[webhook_index].take() {
+            Some(batch) => batch.delivery,
+            None => return,
+        };
+        delivery.next_attempt = Utc::now();
+        self.save(&delivery);
+        self.queue.push(delivery);
+    }
+
+    /// Attempts to deliver the batches which are due, returns when the next attempt is due
+    fn deliver(&mut self) -> Option<DateTime<Utc>> {
+        let mut next_attempt: Option<DateTime<Utc>> = None;
+        // Webhooks with an earlier batch which is not yet delivered
+        let mut waiting: HashSet<usize> = HashSet::new();
+        let mut i = 0;
+        while i < self.queue.len() {
+            let delivery = &self.queue[i];
+            if waiting.contains(&delivery.webhook) {
+                i += 1;
+                continue;
+            }
+            if delivery.next_attempt > Utc::now() {
+                next_attempt = Some(next_attempt.map_or(delivery.next_attempt, |next| {
+                    next.min(delivery.next_attempt)
+                }));
+                waiting.insert(delivery.webhook);
+                i += 1;
+                continue;
+            }
+            let webhook = match self
+                .webhooks
+                .get(delivery.webhook)
+                .filter(|webhook| webhook.url == delivery.url)
+            {
+                Some(webhook) => webhook,
+                None => {
+                    info!(
+                        "Dropping queued webhook batch for {} which is no longer configured",
+                        delivery.url
+                    );
+                    self.remove(i);
+                    continue;
+                }
+            };
+            let result = match self
+                .client
+                .post(&delivery.url)
+                .json(&delivery.payload)
+                .send()
+            {
+                Ok(res) if res.status().is_success() => Ok(()),
+                Ok(res) => Err(format!("responded with {}", res.status())),
+                Err(err) => Err(err.to_string()),
+            };
+            let err = match result {
+                Ok(()) => {
+                    self.remove(i);
+                    continue;
+                }
+                Err(err) => err,
+            };
+            let max_attempts = webhook.max_attempts;
+            let delay = retry_delay(webhook, delivery.attempts + 1);
+            let delivery = &mut self.queue[i];
+            delivery.attempts += 1;
+            if delivery.attempts >= max_attempts {
+                warn!(
+                    "Dropping webhook batch for {} after {} failed attempts: {}",
+                    delivery.url, delivery.attempts, err
+                );
+                self.remove(i);
+                continue;
+            }
+            warn!(
+                "Failed to deliver webhook batch to {}, retrying in {}s: {}",
+                delivery.url,
+                delay.num_seconds(),
+                err
+            );
+            delivery.next_attempt = Utc::now() + delay;
+            let delivery = delivery.clone();
+            self.save(&delivery);
+            next_attempt = Some(next_attempt.map_or(delivery.next_attempt, |next| {
+                next.min(delivery.next_attempt)
+            }));
+            waiting.insert(delivery.webhook);
+            i += 1;
+        }
+        next_attempt
+    }
+
+    fn load_queue(&mut self) {
+        let keys = match self
+            .datastore
+            .get_keys_starting(&format!("{}%", QUEUE_KEY_PREFIX))
+        {
+            Ok(keys) => keys,
+            Err(err) => {
+                error!("Failed to load the webhook queue: {:?}", err);
+                return;
+            }
+        };
+        for key in keys {
+            let value = match self.datastore.get_key_value(&key) {
+                Ok(kv) => kv.value,
+                Err(err) => {
+                    error!("Failed to load queued webhook batch {}: {:?}", key, err);
+                    continue;
+                }
+            };
+            match serde_json::from_value::<Delivery>(value) {
+                Ok(mut delivery) => {
+                    delivery.key = key;
+                    self.queue.push(delivery);
+                }
+                Err(err) => {
+                    warn!("Dropping invalid queued webhook batch {}: {}", key, err);
+                    self.delete(&key);
+                }
+            }
+        }
+        self.queue.sort_by_key(|delivery| delivery.created);
+    }
+
+    fn save(&self, delivery: &Delivery) {
+        let data = serde_json::to_string(delivery).unwrap();
+        if let Err(err) = self.datastore.insert_key_value(&delivery.key, &data) {
+            error!("Failed to store webhook batch {}: {:?}", delivery.key, err);
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        let delivery = self.queue.remove(index);
+        self.delete(&delivery.key);
+    }
+
+    fn delete(&self, key: &str) {
+        if let Err(err) = self.datastore.delete_key_value(key) {
+            error!("Failed to delete webhook batch {}: {:?}", key, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use chrono::Utc;
+    use serde_json::{json, Value};
+
+    use aw_datastore::Datastore;
+    use aw_models::{Bucket, BucketMetadata, Event};
+
+    use super::{bucket_matches, start, Delivery, BATCH_SAVE_INTERVAL, QUEUE_KEY_PREFIX};
+    use crate::config::WebhookConfig;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Local stand-in for a webhook, which responds with the statuses in order and then with 200
+    fn webhook_server(statuses: Vec<u16>) -> (String, mpsc::Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut statuses = statuses.into_iter();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let status = statuses.next().unwrap_or(200);
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                if sender.send(serde_json::from_slice(&body).unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        (url, receiver)
+    }
+
+    fn webhook(url: &str, buckets: &[&str]) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            buckets: buckets.iter().map(|b| b.to_string()).collect(),
+            batch_interval: 0,
+            max_batch_size: 1000,
+            retry_interval: 0,
+            max_attempts: 10,
+        }
+    }
+
+    fn create_bucket(ds: &Datastore, id: &str) {
+        let bucket = Bucket {
+            bid: None,
+            id: id.to_string(),
+            _type: "testtype".to_string(),
+            client: "testclient".to_string(),
+            hostname: "testhost".to_string(),
+            created: None,
+            data: json_map! {},
+            metadata: BucketMetadata::default(),
+            events: None,
+            last_updated: None,
+        };
+        ds.create_bucket(&bucket).unwrap();
+    }
+
+    fn insert_event(ds: &Datastore, bucket_id: &str) {
+        let event = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: chrono::Duration::seconds(1),
+            data: json_map! {},
+        };
+        ds.insert_events(bucket_id, &[event]).unwrap();
+    }
+
+    fn heartbeat(ds: &Datastore, bucket_id: &str) {
+        let event = Event {
+            id: None,
+            timestamp: Utc::now(),
+            duration: chrono::Duration::zero(),
+            data: json_map! {"a": json!(1)},
+        };
+        ds.heartbeat(bucket_id, event, 60.0).unwrap();
+    }
+
+    fn notifications(payload: &Value) -> Vec<(String, String)> {
+        payload["notifications"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|n| {
+                (
+                    n["type"].as_str().unwrap().to_string(),
+                    n["bucket_id"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn wait_for_empty_queue(ds: &Datastore) {
+        let start = Instant::now();
+        while !ds
+            .get_keys_starting(&format!("{}%", QUEUE_KEY_PREFIX))
+            .unwrap()
+            .is_empty()
+        {
+            assert!(start.elapsed() < TIMEOUT, "webhook queue was never emptied");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_bucket_matches() {
+        assert!(bucket_matches("*", "aw-watcher-afk_host"));
+        assert!(bucket_matches("aw-watcher-afk_host", "aw-watcher-afk_host"));
+        assert!(!bucket_matches("aw-watcher-afk", "aw-watcher-afk_host"));
+        assert!(bucket_matches("aw-watcher-*", "aw-watcher-afk_host"));
+        assert!(bucket_matches("*_host", "aw-watcher-afk_host"));
+        assert!(bucket_matches("aw-*-afk*", "aw-watcher-afk_host"));
+        assert!(!bucket_matches("aw-*-window*", "aw-watcher-afk_host"));
+        assert!(!bucket_matches("*host*host", "aw-watcher-afk_host"));
+    }
+
+    #[test]
+    fn test_webhook_batches() {
+        let ds = Datastore::new_in_memory(false);
+        let (url, requests) = webhook_server(vec![]);
+        let mut config = webhook(&url, &["test*"]);
+        config.batch_interval = 60 * 60;
+        config.max_batch_size = 3;
+        start(ds.clone(), vec![config]);
+
+        // A full batch is sent without waiting for the batch interval
+        create_bucket(&ds, "test1");
+        create_bucket(&ds, "other");
+        insert_event(&ds, "test1");
+        insert_event(&ds, "other");
+        ds.delete_bucket("test1").unwrap();
+        let payload = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            notifications(&payload),
+            vec![
+                ("bucket_created".to_string(), "test1".to_string()),
+                ("inserted".to_string(), "test1".to_string()),
+                ("bucket_deleted".to_string(), "test1".to_string()),
+            ]
+        );
+        assert_eq!(payload["notifications"][1]["events"][0]["duration"], 1.0);
+        wait_for_empty_queue(&ds);
+    }
+
+    #[test]
+    fn test_webhook_open_batch() {
+        let ds = Datastore::new_in_memory(false);
+        let (url, requests) = webhook_server(vec![]);
+        // The same url twice, the batches of the second are held open for an hour
+        let mut config = webhook(&url, &["test*"]);
+        config.batch_interval = 60 * 60;
+        config.max_batch_size = 3;
+        start(ds.clone(), vec![webhook(&url, &["other"]), config]);
+
+        create_bucket(&ds, "test1");
+        heartbeat(&ds, "test1");
+        ds.force_commit().unwrap();
+        // The open batch is stored so it is delivered after a restart, when its interval is over
+        let start = Instant::now();
+        let timeout = TIMEOUT + BATCH_SAVE_INTERVAL;
+        let delivery = loop {
+            let keys = ds
+                .get_keys_starting(&format!("{}%", QUEUE_KEY_PREFIX))
+                .unwrap();
+            let stored = keys.iter().find_map(|key| {
+                let value = ds.get_key_value(key).unwrap().value;
+                let delivery: Delivery = serde_json::from_value(value).unwrap();
+                let len = delivery.payload["notifications"].as_array().unwrap().len();
+                if len == 2 {
+                    Some(delivery)
+                } else {
+                    None
+                }
+            });
+            if let Some(delivery) = stored {
+                break delivery;
+            }
+            assert!(start.elapsed() < timeout, "open batch was never stored");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(delivery.webhook, 1);
+        assert!(delivery.next_attempt > Utc::now() + chrono::Duration::minutes(59));
+
+        // Merging a heartbeat into the last event is sent the same way as in the event stream
+        heartbeat(&ds, "test1");
+        ds.force_commit().unwrap();
+        let payload = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            notifications(&payload),
+            vec![
+                ("bucket_created".to_string(), "test1".to_string()),
+                ("inserted".to_string(), "test1".to_string()),
+                ("last_event_replaced".to_string(), "test1".to_string()),
+            ]
+        );
+        assert_eq!(
+            payload["notifications"][2]["event"]["data"],
+            json!({"a": 1})
+        );
+        wait_for_empty_queue(&ds);
+    }
+
+    #[test]
+    fn test_webhook_retry() {
+        let ds = Datastore::new_in_memory(false);
+        let (url, requests) = webhook_server(vec![500, 503, 500]);
+
+        // A batch left in the queue by an earlier run is delivered before new ones
+        let queued = Delivery {
+            key: format!("{}earlier", QUEUE_KEY_PREFIX),
+            webhook: 0,
+            url: url.clone(),
+            payload: json!({ "notifications": [] }),
+            created: Utc::now() - chrono::Duration::minutes(1),
+            attempts: 0,
+            next_attempt: Utc::now(),
+        };
+        ds.insert_key_value(&queued.key, &serde_json::to_string(&queued).unwrap())
+            .unwrap();
+
+        let mut config = webhook(&url, &["*"]);
+        config.max_attempts = 3;
+        start(ds.clone(), vec![config]);
+        create_bucket(&ds, "test1");
+
+        // The earlier batch is dropped after failing three times
+        for _ in 0..3 {
+            let payload = requests.recv_timeout(TIMEOUT).unwrap();
+            assert_eq!(payload, queued.payload);
+        }
+        let payload = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(
+            notifications(&payload),
+            vec![("bucket_created".to_string(), "test1".to_string())]
+        );
+        wait_for_empty_queue(&ds);
+    }
+}
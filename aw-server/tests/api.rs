@@ -8,6 +8,7 @@ extern crate aw_server;
 #[cfg(test)]
 mod api_tests {
     use std::collections::HashMap;
+    use std::net::SocketAddr;
     use std::path::PathBuf;
     use std::sync::Mutex;
 
@@ -271,6 +272,406 @@ mod api_tests {
         assert_eq!(res.status(), rocket::http::Status::Ok);
     }
 
+    #[test]
+    fn test_event_stream() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        for bucket_id in ["id", "other"] {
+            let res = client
+                .post(format!("/api/0/buckets/{}", bucket_id))
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(
+                    r#"{
+                    "type": "type",
+                    "client": "client",
+                    "hostname": "hostname"
+                }"#,
+                )
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+        }
+
+        let stream = client
+            .get("/api/0/stream?bucket=id")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(stream.status(), rocket::http::Status::Ok);
+        assert_eq!(stream.content_type(), Some(ContentType::EventStream));
+
+        // Insert an event and merge a heartbeat into it, events in other buckets are not streamed
+        for (bucket_id, timestamp) in [
+            ("id", "2018-01-01T01:01:01Z"),
+            ("id", "2018-01-01T01:01:02Z"),
+            ("other", "2018-01-01T01:01:01Z"),
+        ] {
+            let res = client
+                .post(format!(
+                    "/api/0/buckets/{}/heartbeat?pulsetime=2",
+                    bucket_id
+                ))
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(format!(
+                    r#"{{"timestamp": "{}", "duration": 1.0, "data": {{}}}}"#,
+                    timestamp
+                ))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+        }
+        let res = client
+            .delete("/api/0/buckets/id/events/1")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        // Notifications are sent once the changes are committed
+        let state = client.rocket().state::<endpoints::ServerState>().unwrap();
+        state.datastore.lock().unwrap().force_commit().unwrap();
+        client.rocket().shutdown().notify();
+        let body = stream.into_string().unwrap();
+        let events: Vec<(&str, Value)> = body
+            .split("\n\n")
+            .filter(|message| !message.is_empty())
+            .map(|message| {
+                let mut event = "";
+                let mut data = Value::Null;
+                for line in message.lines() {
+                    if let Some(name) = line.strip_prefix("event:") {
+                        event = name;
+                    } else if let Some(json) = line.strip_prefix("data:") {
+                        data = serde_json::from_str(json).unwrap();
+                    }
+                }
+                (event, data)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    "inserted",
+                    json!({"type": "inserted", "bucket_id": "id", "events": [
+                        {"id": null, "timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {}}
+                    ]})
+                ),
+                (
+                    "last_event_replaced",
+                    json!({"type": "last_event_replaced", "bucket_id": "id", "event":
+                        {"id": null, "timestamp": "2018-01-01T01:01:01Z", "duration": 2.0, "data": {}}
+                    })
+                ),
+                (
+                    "deleted",
+                    json!({"type": "deleted", "bucket_id": "id", "event_ids": [1]})
+                ),
+            ]
+        );
+    }
+
+    fn setup_token_testserver(datastore: aw_datastore::Datastore) -> Client {
+        let state = endpoints::ServerState {
+            datastore: Mutex::new(datastore),
+            asset_path: PathBuf::from("aw-webui/dist"),
+            device_id: "test_id".to_string(),
+        };
+        let aw_config = config::AWConfig {
+            address: "0.0.0.0".to_string(),
+            token_auth: true,
+            ..Default::default()
+        };
+        Client::untracked(endpoints::build_rocket(state, aw_config)).expect("valid instance")
+    }
+
+    #[test]
+    fn test_token_auth() {
+        let datastore = aw_datastore::Datastore::new_in_memory(false);
+        let setup = setup_token_testserver;
+        let local: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let lan: SocketAddr = "192.168.0.2:40000".parse().unwrap();
+        let first_client = setup(datastore.clone());
+
+        // Requests from localhost need no token
+        let res = first_client.get("/api/0/info").remote(local).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = first_client.get("/api/0/info").remote(lan).dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        // Tokens can only be managed from localhost
+        let res = first_client
+            .post("/api/0/tokens/")
+            .header(ContentType::JSON)
+            .remote(lan)
+            .body(r#"{"name": "laptop"}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = first_client
+            .post("/api/0/tokens/")
+            .header(ContentType::JSON)
+            .remote(local)
+            .body(r#"{"name": "laptop"}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let created: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(created["name"], "laptop");
+        let id = created["id"].as_str().unwrap().to_string();
+        let token = created["token"].as_str().unwrap().to_string();
+
+        let res = first_client
+            .get("/api/0/info")
+            .remote(lan)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = first_client
+            .get("/api/0/info")
+            .remote(lan)
+            .header(Header::new("Authorization", "Bearer invalid"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        // Only the hash of the token is stored, and it is never listed
+        let res = first_client.get("/api/0/tokens/").remote(local).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let tokens: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(tokens.as_array().unwrap().len(), 1);
+        assert_eq!(tokens[0]["id"], id.as_str());
+        assert!(tokens[0].get("token").is_none());
+        let stored = datastore.get_key_value(&format!("tokens.{}", id)).unwrap();
+        assert!(!stored.value.to_string().contains(&token));
+
+        // Tokens are loaded from the datastore when the server starts
+        let client = setup(datastore);
+        let res = client
+            .get("/api/0/info")
+            .remote(lan)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let res = client
+            .delete(format!("/api/0/tokens/{}", id))
+            .remote(local)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .get("/api/0/info")
+            .remote(lan)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = client
+            .delete(format!("/api/0/tokens/{}", id))
+            .remote(local)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_token_scopes() {
+        let client = setup_token_testserver(aw_datastore::Datastore::new_in_memory(false));
+        let local: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let lan: SocketAddr = "192.168.0.2:40000".parse().unwrap();
+        let create_token = |body: &str| {
+            let res = client
+                .post("/api/0/tokens/")
+                .header(ContentType::JSON)
+                .remote(local)
+                .body(body)
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            let created: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+            Header::new(
+                "Authorization",
+                format!("Bearer {}", created["token"].as_str().unwrap()),
+            )
+        };
+        let watcher = create_token(
+            r#"{"name": "watcher", "scopes": ["heartbeat"], "buckets": ["aw-watcher-afk_host"]}"#,
+        );
+        let reader = create_token(r#"{"name": "reader", "scopes": ["read"]}"#);
+        let afk_reader = create_token(
+            r#"{"name": "afk reader", "scopes": ["read"], "buckets": ["aw-watcher-afk_*"]}"#,
+        );
+        let res = client
+            .post("/api/0/tokens/")
+            .header(ContentType::JSON)
+            .remote(local)
+            .body(r#"{"name": "nothing", "scopes": []}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+
+        let bucket = r#"{"type": "type", "client": "client", "hostname": "host"}"#;
+        let heartbeat = r#"{"timestamp": "2018-01-01T01:01:01Z", "duration": 1.0, "data": {}}"#;
+        let res = client
+            .post("/api/0/buckets/aw-watcher-window_host")
+            .header(ContentType::JSON)
+            .remote(local)
+            .body(bucket)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        // A watcher can only create its own bucket and heartbeat to it
+        let res = client
+            .post("/api/0/buckets/aw-watcher-afk_host")
+            .header(ContentType::JSON)
+            .header(watcher.clone())
+            .remote(lan)
+            .body(bucket)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .post("/api/0/buckets/aw-watcher-afk_host/heartbeat?pulsetime=1")
+            .header(ContentType::JSON)
+            .header(watcher.clone())
+            .remote(lan)
+            .body(heartbeat)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .post("/api/0/buckets/aw-watcher-window_host/heartbeat?pulsetime=1")
+            .header(ContentType::JSON)
+            .header(watcher.clone())
+            .remote(lan)
+            .body(heartbeat)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .post("/api/0/buckets/aw-watcher-afk_host/events")
+            .header(ContentType::JSON)
+            .header(watcher.clone())
+            .remote(lan)
+            .body(format!("[{}]", heartbeat))
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .get("/api/0/buckets/aw-watcher-afk_host/events")
+            .header(watcher.clone())
+            .remote(lan)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+
+        // The token is enforced on localhost as well, when one is sent
+        let res = client
+            .post("/api/0/buckets/aw-watcher-window_host/heartbeat?pulsetime=1")
+            .header(ContentType::JSON)
+            .header(watcher.clone())
+            .remote(local)
+            .body(heartbeat)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .get("/api/0/tokens/")
+            .header(watcher.clone())
+            .remote(local)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+
+        // A reader can read everything but change nothing
+        let res = client
+            .get("/api/0/buckets/aw-watcher-afk_host/events")
+            .header(reader.clone())
+            .remote(lan)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .post("/api/0/buckets/aw-watcher-afk_host/heartbeat?pulsetime=1")
+            .header(ContentType::JSON)
+            .header(reader.clone())
+            .remote(lan)
+            .body(heartbeat)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .delete("/api/0/buckets/aw-watcher-afk_host")
+            .header(reader.clone())
+            .remote(lan)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .get("/api/0/settings/")
+            .header(reader.clone())
+            .remote(lan)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .post("/api/0/settings/")
+            .header(ContentType::JSON)
+            .header(reader.clone())
+            .remote(lan)
+            .body(r#"{"key": "key", "value": "value"}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .get("/api/0/tokens/")
+            .header(reader.clone())
+            .remote(lan)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        let res = client
+            .post("/api/0/import")
+            .header(ContentType::JSON)
+            .header(reader)
+            .remote(lan)
+            .body(r#"{"buckets": {}}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+
+        // Only the buckets a token has access to are listed, queried and exported
+        let res = client
+            .get("/api/0/buckets/")
+            .header(afk_reader.clone())
+            .remote(lan)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let buckets: HashMap<String, Bucket> =
+            serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            buckets.keys().collect::<Vec<_>>(),
+            vec!["aw-watcher-afk_host"]
+        );
+        let res = client
+            .get("/api/0/export")
+            .header(afk_reader.clone())
+            .remote(lan)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let export: BucketsExport = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(
+            export.buckets.keys().collect::<Vec<_>>(),
+            vec!["aw-watcher-afk_host"]
+        );
+        let query = |code: &str| {
+            client
+                .post("/api/0/query")
+                .header(ContentType::JSON)
+                .header(afk_reader.clone())
+                .remote(lan)
+                .body(
+                    json!({
+                        "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                        "query": [code]
+                    })
+                    .to_string(),
+                )
+                .dispatch()
+                .status()
+        };
+        assert_eq!(
+            query(r#"return query_bucket("aw-watcher-afk_host");"#),
+            Status::Ok
+        );
+        assert_eq!(
+            query(r#"return query_bucket("aw-watcher-window_host");"#),
+            Status::Forbidden
+        );
+        assert_eq!(
+            query(r#"return find_bucket("aw-watcher-afk_");"#),
+            Status::Forbidden
+        );
+    }
+
     #[test]
     fn test_import_export() {
         let server = setup_testserver();
@@ -297,6 +698,10 @@ mod api_tests {
             )
             .dispatch();
         assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"buckets":{"id1":{"imported_as":"id1","inserted":1,"skipped":0}}}"#
+        );
 
         // TODO: test more error cases
         // Import already existing bucket
@@ -319,10 +724,10 @@ mod api_tests {
             }}}"#,
             )
             .dispatch();
-        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
+        assert_eq!(res.status(), rocket::http::Status::Conflict);
         assert_eq!(
             res.into_string().unwrap(),
-            r#"{"message":"Failed to import bucket: BucketAlreadyExists(\"id1\")"}"#
+            r#"{"message":"Failed to import bucket: bucket 'id1' already exists"}"#
         );
 
         // Export single created bucket
@@ -395,6 +800,285 @@ mod api_tests {
         assert_eq!(buckets.len(), 0);
     }
 
+    #[test]
+    fn test_import_conflicts() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let import_body = r#"{"buckets":
+            {"id1": {
+                "id": "id1",
+                "type": "type",
+                "client": "client",
+                "hostname": "hostname",
+                "events": [{
+                    "timestamp":"2000-01-01T00:00:00Z",
+                    "duration":1.0,
+                    "data": {}
+                }]
+            }}}"#;
+        let import = |query: &str| {
+            client
+                .post(format!("/api/0/import{}", query))
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .body(import_body)
+                .dispatch()
+        };
+
+        let res = import("");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        // Fail on the already existing bucket, which is the default
+        let res = import("?conflict=fail");
+        assert_eq!(res.status(), rocket::http::Status::Conflict);
+        let res = import("");
+        assert_eq!(res.status(), rocket::http::Status::Conflict);
+
+        // Invalid conflict strategy
+        let res = import("?conflict=invalid");
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // Skip the already existing bucket
+        let res = import("?conflict=skip");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"buckets":{"id1":{"imported_as":"id1","inserted":0,"skipped":1}}}"#
+        );
+
+        // Merge skips the identical event
+        let res = import("?conflict=merge");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"buckets":{"id1":{"imported_as":"id1","inserted":0,"skipped":1}}}"#
+        );
+
+        // Replace the bucket
+        let res = import("?conflict=replace");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"buckets":{"id1":{"imported_as":"id1","inserted":1,"skipped":0}}}"#
+        );
+
+        // Import under a new bucket ID
+        let res = import("?conflict=rename");
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"buckets":{"id1":{"imported_as":"id1-imported","inserted":1,"skipped":0}}}"#
+        );
+
+        // Check that there are no duplicated events
+        for bucket_id in ["id1", "id1-imported"] {
+            let res = client
+                .get(format!("/api/0/buckets/{}/events", bucket_id))
+                .header(ContentType::JSON)
+                .header(Header::new("Host", "127.0.0.1:5600"))
+                .dispatch();
+            assert_eq!(res.status(), rocket::http::Status::Ok);
+            let events: Vec<Value> = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+            assert_eq!(events.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+
+        let res = client
+            .post("/api/0/import")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{"buckets":
+            {"id1": {
+                "id": "id1",
+                "type": "type",
+                "client": "client",
+                "hostname": "hostname",
+                "events": [{
+                    "timestamp":"2000-01-01T00:00:00Z",
+                    "duration":1.0,
+                    "data": {"app": "a", "title": "first"}
+                }, {
+                    "timestamp":"2000-01-01T00:01:00Z",
+                    "duration":2.0,
+                    "data": {"app": "b"}
+                }]
+            }}}"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+
+        let res = client
+            .get("/api/0/buckets/id1/export?format=csv")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.content_type(), Some(ContentType::CSV));
+        assert_eq!(
+            res.into_string().unwrap(),
+            "bucket,timestamp,duration,end,data.app,data.title\n\
+             id1,2000-01-01T00:00:00+00:00,1,2000-01-01T00:00:01+00:00,a,first\n\
+             id1,2000-01-01T00:01:00+00:00,2,2000-01-01T00:01:02+00:00,b,\n"
+        );
+
+        // Selected columns and time range
+        let res = client
+            .get("/api/0/export?format=csv&columns=title&start=2000-01-01T00:00:30Z")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            "bucket,timestamp,duration,end,data.title\n\
+             id1,2000-01-01T00:01:00+00:00,2,2000-01-01T00:01:02+00:00,\n"
+        );
+
+        let res = client
+            .get("/api/0/export?format=csv&start=yesterday")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+    }
+
+    #[test]
+    fn test_import_export_ndjson() {
+        let server = setup_testserver();
+        let client = Client::untracked(server).expect("valid instance");
+        let ndjson = ContentType::new("application", "x-ndjson");
+
+        let import_body = concat!(
+            r#"{"bucket":{"id":"id1","type":"type","client":"client","hostname":"hostname"}}"#,
+            "\n",
+            r#"{"event":{"timestamp":"2000-01-01T00:00:00Z","duration":1.0,"data":{}}}"#,
+            "\n",
+            r#"{"event":{"timestamp":"2000-01-01T00:00:01Z","duration":1.0,"data":{}}}"#,
+            "\n",
+        );
+        let res = client
+            .post("/api/0/import")
+            .header(ndjson.clone())
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(import_body)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"buckets":{"id1":{"imported_as":"id1","inserted":2,"skipped":0}}}"#
+        );
+
+        // Export single bucket
+        let res = client
+            .get("/api/0/buckets/id1/export?format=ndjson")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.content_type(), Some(ndjson.clone()));
+        let export = res.into_string().unwrap();
+        let lines: Vec<Value> = export
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["bucket"]["id"], "id1");
+        assert_eq!(lines[1]["event"]["timestamp"], "2000-01-01T00:00:00Z");
+        assert_eq!(lines[2]["event"]["timestamp"], "2000-01-01T00:00:01Z");
+
+        // Export all buckets
+        let res = client
+            .get("/api/0/export?format=ndjson")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().unwrap(), export);
+
+        // Unknown formats are rejected
+        let res = client
+            .get("/api/0/export?format=xml")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
+        // The bucket already exists
+        let res = client
+            .post("/api/0/import")
+            .header(ndjson.clone())
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(export.clone())
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Conflict);
+
+        // Import the export under a new bucket ID
+        let res = client
+            .post("/api/0/import?conflict=rename")
+            .header(ndjson)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(export)
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"buckets":{"id1":{"imported_as":"id1-imported","inserted":2,"skipped":0}}}"#
+        );
+    }
+
+    #[test]
+    fn test_import_ndjson_limit() {
+        let bucket = |id: &str| {
+            format!(
+                r#"{{"bucket":{{"id":"{}","type":"type","client":"client","hostname":"hostname"}}}}"#,
+                id
+            )
+        };
+        let event = r#"{"event":{"timestamp":"2000-01-01T00:00:00Z","duration":1.0,"data":{}}}"#;
+        let at_limit = format!("{}\n{}\n", bucket("id1"), event);
+        let too_large = format!("{}\n{}\n{}\n", bucket("id2"), event, event);
+
+        let server = setup_testserver();
+        let figment = server
+            .figment()
+            .clone()
+            .merge(("limits.ndjson", at_limit.len()));
+        let client = Client::untracked(server.configure(figment)).expect("valid instance");
+        let ndjson = ContentType::new("application", "x-ndjson");
+
+        // An import exactly at the limit is accepted
+        let res = client
+            .post("/api/0/import")
+            .header(ndjson.clone())
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(at_limit)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        // Nothing of an import over the limit is imported, with or without a Content-Length
+        let res = client
+            .post("/api/0/import")
+            .header(ndjson.clone())
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(too_large.clone())
+            .dispatch();
+        assert_eq!(res.status(), Status::PayloadTooLarge);
+        let res = client
+            .post("/api/0/import")
+            .header(ndjson)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .header(Header::new("Content-Length", too_large.len().to_string()))
+            .body(too_large)
+            .dispatch();
+        assert_eq!(res.status(), Status::PayloadTooLarge);
+        let res = client
+            .get("/api/0/buckets/id2")
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
     #[test]
     fn test_query() {
         let server = setup_testserver();
@@ -464,6 +1148,83 @@ mod api_tests {
             r#"[[{"data":{},"duration":1.0,"id":1,"timestamp":"2018-01-01T01:01:01Z"}]]"#
         );
 
+        // Multiple timeperiods are evaluated in parallel, but the results keep their order
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": [
+                    "2000-01-01T00:00:00Z/2010-01-01T00:00:00Z",
+                    "2018-01-01T00:00:00Z/2018-01-02T00:00:00Z",
+                    "2019-01-01T00:00:00Z/2020-01-01T00:00:00Z",
+                    "2018-01-01T01:00:00Z/2018-01-01T02:00:00Z"
+                ],
+                "query": ["return query_bucket(\"id\");"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"[[],[{"data":{},"duration":1.0,"id":1,"timestamp":"2018-01-01T01:01:01Z"}],[],[{"data":{},"duration":1.0,"id":1,"timestamp":"2018-01-01T01:01:01Z"}]]"#
+        );
+
+        // Profiled queries return the timings of each timeperiod with the results
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["events = query_bucket(\"id\");", "return events;"],
+                "profile": true
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        let res: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(res["result"][0].as_array().unwrap().len(), 1);
+        let profile = &res["profile"][0];
+        assert_eq!(profile["statements"].as_array().unwrap().len(), 2);
+        assert_eq!(profile["calls"][0]["function"], "query_bucket");
+        assert_eq!(profile["calls"][0]["events_out"], 1);
+
+        // Queries run in the timezone and with the day start they are given
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["return [TIMEZONE, DAY_START];"],
+                "timezone": "Europe/Stockholm",
+                "day_start": "04:00"
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"[["Europe/Stockholm","04:00"]]"#
+        );
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["return TIMEZONE;"],
+                "timezone": "Europe/Atlantis"
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+
         // Test error
         let res = client
             .post("/api/0/query")
@@ -476,8 +1237,31 @@ mod api_tests {
             }"#,
             )
             .dispatch();
-        assert_eq!(res.status(), rocket::http::Status::InternalServerError);
-        assert_eq!(res.into_string().unwrap(), r#"{"message":"EmptyQuery"}"#);
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        assert_eq!(
+            res.into_string().unwrap(),
+            r#"{"message":"EmptyQuery","kind":"EmptyQuery"}"#
+        );
+
+        // Errors point at the code which caused them
+        let res = client
+            .post("/api/0/query")
+            .header(ContentType::JSON)
+            .header(Header::new("Host", "127.0.0.1:5600"))
+            .body(
+                r#"{
+                "timeperiods": ["2000-01-01T00:00:00Z/2020-01-01T00:00:00Z"],
+                "query": ["a = 1;", "return a / 0;"]
+            }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res: Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+        assert_eq!(res["kind"], "MathError");
+        assert_eq!(
+            res["span"],
+            json!({"line": 2, "column": 8, "lo": 14, "hi": 19, "snippet": "a / 0"})
+        );
     }
 
     fn set_setting_request(client: &Client, key: &str, value: Value) -> Status {
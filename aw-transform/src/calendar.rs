@@ -0,0 +1,264 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, Offset,
+    TimeZone, Utc,
+};
+use chrono_tz::Tz;
+
+/// A timezone, either a fixed offset from UTC or a named timezone with DST
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timezone {
+    Offset(FixedOffset),
+    Named(Tz),
+}
+
+impl Timezone {
+    /// Offset from UTC in effect at the time
+    pub fn offset_at(&self, dt: &DateTime<Utc>) -> FixedOffset {
+        match self {
+            Timezone::Offset(offset) => *offset,
+            Timezone::Named(tz) => tz.offset_from_utc_datetime(&dt.naive_utc()).fix(),
+        }
+    }
+
+    pub fn to_local(&self, dt: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        dt.with_timezone(&self.offset_at(dt))
+    }
+
+    /// The time at which the clocks in the timezone show the local time
+    ///
+    /// Local times which happen twice, when clocks are turned back, resolve to the first of
+    /// them. Local times which are skipped, when clocks are turned forward, resolve to when the
+    /// clocks were turned.
+    pub fn from_local(&self, local: &NaiveDateTime) -> DateTime<Utc> {
+        let tz = match self {
+            Timezone::Offset(offset) => {
+                return offset
+                    .from_local_datetime(local)
+                    .unwrap()
+                    .with_timezone(&Utc)
+            }
+            Timezone::Named(tz) => tz,
+        };
+        match tz.from_local_datetime(local) {
+            LocalResult::Single(dt) => dt.with_timezone(&Utc),
+            LocalResult::Ambiguous(first, second) => {
+                first.with_timezone(&Utc).min(second.with_timezone(&Utc))
+            }
+            LocalResult::None => {
+                // The clocks were turned somewhere between reading the local time with the
+                // offset from after and from before they were turned
+                let offset_before = tz.offset_from_utc_datetime(&(*local - Duration::days(1)));
+                let offset_after = tz.offset_from_utc_datetime(&(*local + Duration::days(1)));
+                let with_offset = |offset: &<Tz as TimeZone>::Offset| {
+                    *local - Duration::seconds(offset.fix().local_minus_utc() as i64)
+                };
+                let mut lo = with_offset(&offset_after);
+                let mut hi = with_offset(&offset_before);
+                while hi - lo > Duration::seconds(1) {
+                    let mid = lo + (hi - lo) / 2;
+                    if tz.offset_from_utc_datetime(&mid) == offset_before {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                DateTime::from_utc(hi, Utc)
+            }
+        }
+    }
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Timezone::Offset(FixedOffset::east(0))
+    }
+}
+
+/// Parses an offset from UTC such as "+02:00" or a timezone name such as "Europe/Stockholm"
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('+') || s.starts_with('-') {
+            let offset = parse_hours_minutes(&s[1..])
+                .filter(|offset| *offset < Duration::days(1))
+                .ok_or_else(|| format!("Invalid UTC offset {}, expected such as +02:00", s))?;
+            let seconds = offset.num_seconds() as i32;
+            let seconds = if s.starts_with('-') {
+                -seconds
+            } else {
+                seconds
+            };
+            Ok(Timezone::Offset(FixedOffset::east(seconds)))
+        } else {
+            Ok(Timezone::Named(s.parse()?))
+        }
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Timezone::Offset(offset) => write!(f, "{}", offset),
+            Timezone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+/// Parses a duration written as hours and minutes, such as "04:00"
+fn parse_hours_minutes(s: &str) -> Option<Duration> {
+    let (hours, minutes) = s.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours: i64 = hours.parse().ok()?;
+    let minutes: i64 = minutes.parse().ok()?;
+    if minutes >= 60 {
+        return None;
+    }
+    Some(Duration::hours(hours) + Duration::minutes(minutes))
+}
+
+/// Where days begin, as the timezone and the local time of day at which days start
+///
+/// Days don't have to start at midnight, for people who are often up past it. With a day start
+/// of 04:00, activity at 01:00 on a tuesday counts towards monday.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calendar {
+    pub timezone: Timezone,
+    /// Time after local midnight at which days start
+    pub day_start: Duration,
+}
+
+impl Default for Calendar {
+    fn default() -> Self {
+        Calendar {
+            timezone: Timezone::default(),
+            day_start: Duration::zero(),
+        }
+    }
+}
+
+impl Calendar {
+    /// Creates a calendar from a timezone such as "Europe/Stockholm" or "+02:00", and a day start
+    /// such as "04:00"
+    pub fn new(timezone: &str, day_start: &str) -> Result<Calendar, String> {
+        let timezone = timezone.parse()?;
+        let day_start = parse_hours_minutes(day_start)
+            .filter(|day_start| *day_start < Duration::days(1))
+            .ok_or_else(|| format!("Invalid day start {}, expected such as 04:00", day_start))?;
+        Ok(Calendar {
+            timezone,
+            day_start,
+        })
+    }
+
+    /// When the day starts
+    pub fn start_of_day(&self, date: NaiveDate) -> DateTime<Utc> {
+        self.timezone
+            .from_local(&(date.and_hms(0, 0, 0) + self.day_start))
+    }
+
+    /// The day the time is in
+    pub fn date_of(&self, dt: &DateTime<Utc>) -> NaiveDate {
+        let local = self.timezone.to_local(dt).naive_local();
+        let date = (local - self.day_start).date();
+        // When the clocks are turned back right after the day started, the local time can
+        // be before the day start for a while even though the next day has started
+        let next = date.succ();
+        if self.start_of_day(next) <= *dt {
+            next
+        } else {
+            date
+        }
+    }
+
+    /// The monday of the week the time is in
+    pub fn monday_of(&self, dt: &DateTime<Utc>) -> NaiveDate {
+        let date = self.date_of(dt);
+        date - Duration::days(date.weekday().num_days_from_monday() as i64)
+    }
+}
+
+impl fmt::Display for Calendar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:02}:{:02}",
+            self.timezone,
+            self.day_start.num_hours(),
+            self.day_start.num_minutes() % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Duration, NaiveDate, Utc};
+
+    use super::{Calendar, Timezone};
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_timezone_parse() {
+        assert_eq!(Timezone::from_str("+02:00").unwrap().to_string(), "+02:00");
+        assert_eq!(Timezone::from_str("-05:30").unwrap().to_string(), "-05:30");
+        assert_eq!(
+            Timezone::from_str("Europe/Stockholm").unwrap().to_string(),
+            "Europe/Stockholm"
+        );
+        assert!(Timezone::from_str("+2").is_err());
+        assert!(Timezone::from_str("+24:00").is_err());
+        assert!(Timezone::from_str("Europe/Atlantis").is_err());
+
+        let calendar = Calendar::new("Europe/Stockholm", "04:00").unwrap();
+        assert_eq!(calendar.day_start, Duration::hours(4));
+        assert_eq!(calendar.to_string(), "Europe/Stockholm 04:00");
+        assert!(Calendar::new("UTC", "4").is_err());
+        assert!(Calendar::new("UTC", "24:00").is_err());
+    }
+
+    #[test]
+    fn test_dst() {
+        let calendar = Calendar::new("Europe/Stockholm", "00:00").unwrap();
+        // The clocks were turned forward from 02:00 to 03:00 on 2020-03-29
+        let date = NaiveDate::from_ymd(2020, 3, 29);
+        assert_eq!(
+            calendar.start_of_day(date).to_rfc3339(),
+            "2020-03-28T23:00:00+00:00"
+        );
+        let end = calendar.start_of_day(date.succ());
+        assert_eq!(end.to_rfc3339(), "2020-03-29T22:00:00+00:00");
+        assert_eq!(end - calendar.start_of_day(date), Duration::hours(23));
+        // 02:30 never happened that day
+        let calendar = Calendar::new("Europe/Stockholm", "02:30").unwrap();
+        assert_eq!(
+            calendar.start_of_day(date).to_rfc3339(),
+            "2020-03-29T01:00:00+00:00"
+        );
+
+        // And turned back from 03:00 to 02:00 on 2020-10-25
+        let calendar = Calendar::new("Europe/Stockholm", "02:00").unwrap();
+        let date = NaiveDate::from_ymd(2020, 10, 25);
+        assert_eq!(
+            calendar.start_of_day(date).to_rfc3339(),
+            "2020-10-25T00:00:00+00:00"
+        );
+        // 02:30 happened twice, both times on the new day
+        assert_eq!(calendar.date_of(&utc("2020-10-25T00:30:00Z")), date);
+        assert_eq!(calendar.date_of(&utc("2020-10-25T01:30:00Z")), date);
+        assert_eq!(calendar.date_of(&utc("2020-10-24T23:30:00Z")), date.pred());
+        assert_eq!(
+            calendar.start_of_day(date.succ()) - calendar.start_of_day(date),
+            Duration::hours(25)
+        );
+    }
+}
@@ -3,10 +3,23 @@
 /// Based on code in aw_research: https://github.com/ActivityWatch/aw-research/blob/master/aw_research/classify.py
 use aw_models::Event;
 use fancy_regex::Regex;
+use serde_json::Value;
 
+/// This enum defines the rules for classification.
+/// Rules match on the string values in the event data, with a regex, a glob-pattern or an exact
+/// value, and can be combined into larger rules.
+/// It's puropse is to make the API easy to extend in the future without having to break backwards
+/// compatibility (or have to maintain "old" query2 functions).
 pub enum Rule {
     None,
     Regex(RegexRule),
+    Glob(GlobRule),
+    Exact(ExactRule),
+    /// Matches if all of the rules match, or if there are no rules
+    All(Vec<Rule>),
+    /// Matches if any of the rules match
+    Any(Vec<Rule>),
+    Not(Box<Rule>),
 }
 
 impl RuleTrait for Rule {
@@ -14,6 +27,11 @@ impl RuleTrait for Rule {
         match self {
             Rule::None => false,
             Rule::Regex(rule) => rule.matches(event),
+            Rule::Glob(rule) => rule.matches(event),
+            Rule::Exact(rule) => rule.matches(event),
+            Rule::All(rules) => rules.iter().all(|rule| rule.matches(event)),
+            Rule::Any(rules) => rules.iter().any(|rule| rule.matches(event)),
+            Rule::Not(rule) => !rule.matches(event),
         }
     }
 }
@@ -22,8 +40,25 @@ trait RuleTrait {
     fn matches(&self, event: &Event) -> bool;
 }
 
+/// Checks the string values of the selected keys, or all string values if no keys are selected
+fn any_string_value(
+    event: &Event,
+    select_keys: &Option<Vec<String>>,
+    f: impl Fn(&str) -> bool,
+) -> bool {
+    match select_keys {
+        Some(keys) => keys
+            .iter()
+            .filter_map(|key| event.data.get(key))
+            .filter_map(Value::as_str)
+            .any(f),
+        None => event.data.values().filter_map(Value::as_str).any(f),
+    }
+}
+
 pub struct RegexRule {
     regex: Regex,
+    select_keys: Option<Vec<String>>,
 }
 
 impl RegexRule {
@@ -37,28 +72,121 @@ impl RegexRule {
             Regex::new(regex_str)?
         };
 
-        Ok(RegexRule { regex })
+        Ok(RegexRule {
+            regex,
+            select_keys: None,
+        })
+    }
+
+    /// Only match the values of the keys, such as "title", instead of all string values
+    pub fn select_keys(mut self, keys: Vec<String>) -> RegexRule {
+        self.select_keys = Some(keys);
+        self
+    }
+
+    /// Only match the value of the key, such as "title", instead of all string values
+    pub fn with_key(self, key: &str) -> RegexRule {
+        self.select_keys(vec![key.to_string()])
     }
 }
 
-/// This struct defines the rules for classification.
-/// For now it just needs to contain the regex to match with, but in the future it might contain a
-/// glob-pattern, or other options for classifying.
-/// It's puropse is to make the API easy to extend in the future without having to break backwards
-/// compatibility (or have to maintain "old" query2 functions).
 impl RuleTrait for RegexRule {
     fn matches(&self, event: &Event) -> bool {
-        event
-            .data
-            .values()
-            .filter(|val| val.is_string())
-            .any(|val| self.regex.is_match(val.as_str().unwrap()).unwrap())
+        any_string_value(event, &self.select_keys, |val| {
+            self.regex.is_match(val).unwrap()
+        })
     }
 }
 
 impl From<Regex> for Rule {
     fn from(re: Regex) -> Self {
-        Rule::Regex(RegexRule { regex: re })
+        Rule::Regex(RegexRule {
+            regex: re,
+            select_keys: None,
+        })
+    }
+}
+
+/// Matches string values against a glob pattern, where `*` matches any number of characters and
+/// `?` matches a single one. The pattern has to match the whole value.
+pub struct GlobRule {
+    regex: Regex,
+    select_keys: Option<Vec<String>>,
+}
+
+impl GlobRule {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<GlobRule, fancy_regex::Error> {
+        let mut regex_str = String::from(if ignore_case { "(?is)^" } else { "(?s)^" });
+        for part in pattern.split_inclusive(&['*', '?'][..]) {
+            let (literal, wildcard) = match part.strip_suffix('*') {
+                Some(literal) => (literal, ".*"),
+                None => match part.strip_suffix('?') {
+                    Some(literal) => (literal, "."),
+                    None => (part, ""),
+                },
+            };
+            regex_str.push_str(&fancy_regex::escape(literal));
+            regex_str.push_str(wildcard);
+        }
+        regex_str.push('$');
+        Ok(GlobRule {
+            regex: Regex::new(&regex_str)?,
+            select_keys: None,
+        })
+    }
+
+    /// Only match the values of the keys, such as "title", instead of all string values
+    pub fn select_keys(mut self, keys: Vec<String>) -> GlobRule {
+        self.select_keys = Some(keys);
+        self
+    }
+}
+
+impl RuleTrait for GlobRule {
+    fn matches(&self, event: &Event) -> bool {
+        any_string_value(event, &self.select_keys, |val| {
+            self.regex.is_match(val).unwrap()
+        })
+    }
+}
+
+/// Matches string values which are exactly equal to the value
+pub struct ExactRule {
+    value: String,
+    ignore_case: bool,
+    select_keys: Option<Vec<String>>,
+}
+
+impl ExactRule {
+    pub fn new(value: &str, ignore_case: bool) -> ExactRule {
+        let value = if ignore_case {
+            value.to_lowercase()
+        } else {
+            value.to_string()
+        };
+        ExactRule {
+            value,
+            ignore_case,
+            select_keys: None,
+        }
+    }
+
+    /// Only match the values of the keys, such as "title", instead of all string values
+    pub fn select_keys(mut self, keys: Vec<String>) -> ExactRule {
+        self.select_keys = Some(keys);
+        self
+    }
+}
+
+impl RuleTrait for ExactRule {
+    fn matches(&self, event: &Event) -> bool {
+        any_string_value(event, &self.select_keys, |val| {
+            if self.ignore_case {
+                val.to_lowercase() == self.value
+            } else {
+                val == self.value
+            }
+        })
     }
 }
 
@@ -145,6 +273,88 @@ fn test_rule() {
     assert_eq!(rule_none.matches(&e_match), false);
 }
 
+#[test]
+fn test_rule_types() {
+    let mut e = Event::default();
+    e.data
+        .insert("title".into(), serde_json::json!("Report.pdf - Viewer"));
+    e.data.insert("app".into(), serde_json::json!("viewer"));
+
+    let glob = |pattern, ignore_case| Rule::Glob(GlobRule::new(pattern, ignore_case).unwrap());
+    assert!(glob("*.pdf - Viewer", false).matches(&e));
+    assert!(glob("Report.pd? *", false).matches(&e));
+    // The whole value has to match, and dots aren't wildcards
+    assert!(!glob("Report", false).matches(&e));
+    assert!(glob("Report?pdf*", false).matches(&e));
+    assert!(!glob("Report.pdf?", false).matches(&e));
+    assert!(!glob("report*", false).matches(&e));
+    assert!(glob("report*", true).matches(&e));
+
+    let exact = |value, ignore_case| Rule::Exact(ExactRule::new(value, ignore_case));
+    assert!(exact("viewer", false).matches(&e));
+    assert!(!exact("Viewer", false).matches(&e));
+    assert!(exact("Viewer", true).matches(&e));
+
+    let regex = |regex, key| {
+        let rule = RegexRule::new(regex, false).unwrap();
+        Rule::Regex(match key {
+            Some(key) => rule.with_key(key),
+            None => rule,
+        })
+    };
+    assert!(regex("^viewer", None).matches(&e));
+    assert!(!regex("^viewer", Some("title")).matches(&e));
+    assert!(regex("^viewer", Some("app")).matches(&e));
+    assert!(!regex("^viewer", Some("url")).matches(&e));
+
+    let all = Rule::All(vec![exact("viewer", false), glob("*.pdf*", false)]);
+    assert!(all.matches(&e));
+    let all = Rule::All(vec![exact("viewer", false), glob("*.doc*", false)]);
+    assert!(!all.matches(&e));
+    let any = Rule::Any(vec![exact("editor", false), glob("*.pdf*", false)]);
+    assert!(any.matches(&e));
+    assert!(!Rule::Any(vec![]).matches(&e));
+    assert!(Rule::All(vec![]).matches(&e));
+    let not = Rule::Not(Box::new(exact("viewer", false)));
+    assert!(!not.matches(&e));
+    let not = Rule::Not(Box::new(Rule::None));
+    assert!(not.matches(&e));
+}
+
+#[test]
+fn test_select_keys() {
+    let mut e = Event::default();
+    e.data.insert("title".into(), serde_json::json!("Code"));
+    e.data.insert("app".into(), serde_json::json!("code"));
+    e.data.insert("count".into(), serde_json::json!(1));
+
+    let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+    let regex = |select_keys| {
+        Rule::Regex(
+            RegexRule::new("^code$", false)
+                .unwrap()
+                .select_keys(select_keys),
+        )
+    };
+    assert!(!regex(keys(&["title"])).matches(&e));
+    assert!(regex(keys(&["app"])).matches(&e));
+    assert!(regex(keys(&["title", "app"])).matches(&e));
+    // Missing keys and values which aren't strings never match
+    assert!(!regex(keys(&["url", "count"])).matches(&e));
+    assert!(!regex(keys(&[])).matches(&e));
+
+    let glob = Rule::Glob(
+        GlobRule::new("C*", false)
+            .unwrap()
+            .select_keys(keys(&["app"])),
+    );
+    assert!(!glob.matches(&e));
+    let exact = Rule::Exact(ExactRule::new("code", false).select_keys(keys(&["title"])));
+    assert!(!exact.matches(&e));
+    let exact = Rule::Exact(ExactRule::new("code", true).select_keys(keys(&["title"])));
+    assert!(exact.matches(&e));
+}
+
 #[test]
 fn test_rule_lookahead() {
     // Originally requested by a user here, to match aw-server-python: https://canary.discord.com/channels/755040852727955476/755334543891759194/994291987878522961
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{Map, Value};
+
+use aw_models::Event;
+
+/// A value computed over all events in a group
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregation {
+    /// Sum of the durations of the events, in seconds
+    SumDuration,
+    /// Number of events
+    Count,
+    /// Timestamp of the earliest event
+    FirstTimestamp,
+    /// Timestamp of the latest event
+    LastTimestamp,
+    /// Number of distinct values of a key, events without the key are not counted
+    DistinctCount(String),
+}
+
+struct Group {
+    key_values: Vec<Value>,
+    count: u64,
+    duration: Duration,
+    first: DateTime<Utc>,
+    last: DateTime<Utc>,
+    // One set of seen values for every DistinctCount aggregation
+    distinct: Vec<HashSet<String>>,
+}
+
+/// Groups events with the same values at the specified keys and aggregates each group
+///
+/// Returns one dict per group, with the values of the keys of the group and the result of each
+/// aggregation under its name. Events which are missing any of the keys are dropped. The groups
+/// are ordered by when they first appear in the events.
+///
+/// # Example
+/// ```ignore
+/// keys: ["a"]
+/// aggregations: [("duration", SumDuration), ("count", Count), ("bs", DistinctCount("b"))]
+/// input:
+///   { duration: 1.0, data: { "a": 1, "b": 1 } }
+///   { duration: 2.0, data: { "a": 2, "b": 1 } }
+///   { duration: 3.0, data: { "a": 1, "b": 2 } }
+///   { duration: 1.0, data: { "b": 1 } }
+/// output:
+///   { "a": 1, "duration": 4.0, "count": 2, "bs": 2 }
+///   { "a": 2, "duration": 2.0, "count": 1, "bs": 1 }
+/// ```
+pub fn group_by(
+    events: Vec<Event>,
+    keys: &[String],
+    aggregations: &[(String, Aggregation)],
+) -> Vec<Map<String, Value>> {
+    let distinct_keys: Vec<&String> = aggregations
+        .iter()
+        .filter_map(|(_, aggregation)| match aggregation {
+            Aggregation::DistinctCount(key) => Some(key),
+            _ => None,
+        })
+        .collect();
+
+    let mut groups: Vec<Group> = Vec::new();
+    let mut group_index: HashMap<String, usize> = HashMap::new();
+    'event: for event in events {
+        let mut key_values = Vec::new();
+        for key in keys {
+            match event.data.get(key) {
+                Some(v) => key_values.push(v.clone()),
+                None => continue 'event,
+            }
+        }
+        let group_key = Value::Array(key_values.clone()).to_string();
+        let index = *group_index.entry(group_key).or_insert_with(|| {
+            groups.push(Group {
+                key_values,
+                count: 0,
+                duration: Duration::zero(),
+                first: event.timestamp,
+                last: event.timestamp,
+                distinct: vec![HashSet::new(); distinct_keys.len()],
+            });
+            groups.len() - 1
+        });
+        let group = &mut groups[index];
+        group.count += 1;
+        group.duration = group.duration + event.duration;
+        group.first = group.first.min(event.timestamp);
+        group.last = group.last.max(event.timestamp);
+        for (seen, key) in group.distinct.iter_mut().zip(distinct_keys.iter()) {
+            if let Some(v) = event.data.get(*key) {
+                seen.insert(v.to_string());
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    for group in groups {
+        let mut dict = Map::new();
+        for (key, value) in keys.iter().zip(group.key_values) {
+            dict.insert(key.clone(), value);
+        }
+        let mut distinct = group.distinct.iter();
+        for (name, aggregation) in aggregations {
+            let value = match aggregation {
+                Aggregation::SumDuration => {
+                    Value::from((group.duration.num_milliseconds() as f64) / 1000.0)
+                }
+                Aggregation::Count => Value::from(group.count),
+                Aggregation::FirstTimestamp => Value::from(group.first.to_rfc3339()),
+                Aggregation::LastTimestamp => Value::from(group.last.to_rfc3339()),
+                Aggregation::DistinctCount(_) => Value::from(distinct.next().unwrap().len()),
+            };
+            dict.insert(name.clone(), value);
+        }
+        result.push(dict);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::DateTime;
+    use chrono::Duration;
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::{group_by, Aggregation};
+
+    #[test]
+    fn test_group_by() {
+        let event = |timestamp: &str, duration: i64, data| Event {
+            id: None,
+            timestamp: DateTime::from_str(timestamp).unwrap(),
+            duration: Duration::seconds(duration),
+            data,
+        };
+        let events = vec![
+            event(
+                "2000-01-01T00:00:01Z",
+                1,
+                json_map! {"app": json!("a"), "title": json!("1")},
+            ),
+            event(
+                "2000-01-01T00:00:02Z",
+                2,
+                json_map! {"app": json!("b"), "title": json!("1")},
+            ),
+            event(
+                "2000-01-01T00:00:00Z",
+                3,
+                json_map! {"app": json!("a"), "title": json!("2")},
+            ),
+            event(
+                "2000-01-01T00:00:03Z",
+                4,
+                json_map! {"app": json!("a"), "title": json!("1")},
+            ),
+            event("2000-01-01T00:00:04Z", 5, json_map! {"title": json!("1")}),
+        ];
+        let aggregations = vec![
+            ("duration".to_string(), Aggregation::SumDuration),
+            ("count".to_string(), Aggregation::Count),
+            ("first".to_string(), Aggregation::FirstTimestamp),
+            ("last".to_string(), Aggregation::LastTimestamp),
+            (
+                "titles".to_string(),
+                Aggregation::DistinctCount("title".to_string()),
+            ),
+        ];
+        let res = group_by(events, &["app".to_string()], &aggregations);
+        assert_eq!(
+            json!(res),
+            json!([
+                {
+                    "app": "a",
+                    "duration": 8.0,
+                    "count": 3,
+                    "first": "2000-01-01T00:00:00+00:00",
+                    "last": "2000-01-01T00:00:03+00:00",
+                    "titles": 2,
+                },
+                {
+                    "app": "b",
+                    "duration": 2.0,
+                    "count": 1,
+                    "first": "2000-01-01T00:00:02+00:00",
+                    "last": "2000-01-01T00:00:02+00:00",
+                    "titles": 1,
+                },
+            ])
+        );
+
+        // Without keys all events are in the same group
+        let events = vec![
+            event("2000-01-01T00:00:00Z", 1, json_map! {"app": json!("a")}),
+            event("2000-01-01T00:00:01Z", 1, json_map! {}),
+        ];
+        let res = group_by(events, &[], &[("count".to_string(), Aggregation::Count)]);
+        assert_eq!(json!(res), json!([{"count": 2}]));
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, FixedOffset, Timelike, Utc};
+use serde_json::Value;
+
+use aw_models::Event;
+
+use crate::calendar::Calendar;
+
+/// Calendar unit to split time at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Hour,
+    Day,
+    /// Weeks start on monday
+    Week,
+}
+
+impl TimeUnit {
+    /// Start of the hour, day or week containing the time, with days starting as in the calendar
+    pub fn start_of(&self, dt: &DateTime<Utc>, calendar: &Calendar) -> DateTime<Utc> {
+        match self {
+            TimeUnit::Hour => {
+                let local = calendar.timezone.to_local(dt);
+                *dt - Duration::minutes(local.minute() as i64)
+                    - Duration::seconds(local.second() as i64)
+                    - Duration::nanoseconds(local.nanosecond() as i64)
+            }
+            TimeUnit::Day => calendar.start_of_day(calendar.date_of(dt)),
+            TimeUnit::Week => calendar.start_of_day(calendar.monday_of(dt)),
+        }
+    }
+
+    /// End of the hour, day or week containing the time, which is the start of the next one
+    ///
+    /// Days are not always 24 hours long, as the clocks might be turned during them.
+    pub fn end_of(&self, dt: &DateTime<Utc>, calendar: &Calendar) -> DateTime<Utc> {
+        match self {
+            TimeUnit::Hour => self.start_of(dt, calendar) + Duration::hours(1),
+            TimeUnit::Day => calendar.start_of_day(calendar.date_of(dt).succ()),
+            TimeUnit::Week => calendar.start_of_day(calendar.monday_of(dt) + Duration::weeks(1)),
+        }
+    }
+}
+
+/// Splits events which cross the boundaries between hours, days or weeks, so that every event is
+/// within a single one of them
+///
+/// # Example
+/// ```ignore
+/// unit: Hour
+/// input:  [00:30 - 02:15]
+/// output: [00:30 - 01:00][01:00 - 02:00][02:00 - 02:15]
+/// ```
+pub fn split_at_boundaries(events: Vec<Event>, unit: TimeUnit, calendar: &Calendar) -> Vec<Event> {
+    let mut split_events = Vec::new();
+    for event in events {
+        let end = event.calculate_endtime();
+        let mut start = event.timestamp;
+        loop {
+            let boundary = unit.end_of(&start, calendar);
+            if boundary >= end {
+                break;
+            }
+            let mut part = event.clone();
+            part.timestamp = start;
+            part.duration = boundary - start;
+            split_events.push(part);
+            start = boundary;
+        }
+        let mut part = event;
+        part.duration = end - start;
+        part.timestamp = start;
+        split_events.push(part);
+    }
+    split_events
+}
+
+/// Summed duration of the events within an hour, day or week
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramBin {
+    pub start: DateTime<FixedOffset>,
+    /// Value of the key the histogram was split by
+    pub value: Option<Value>,
+    pub duration: Duration,
+}
+
+/// Sums up the duration of the events in every hour, day or week, optionally also split by the
+/// values of a key
+///
+/// Only bins with events in them are returned, ordered by time. When split by a key the bins with
+/// the same start are ordered by duration, longest first, and events without the key are dropped.
+///
+/// # Example
+/// ```ignore
+/// unit: Hour
+/// key: "a"
+/// input:  [00:30 - 01:30, a: 1][00:45 - 01:00, a: 2][01:30 - 02:00, a: 1]
+/// output: [00:00, a: 1, 30m][00:00, a: 2, 15m][01:00, a: 1, 60m]
+/// ```
+pub fn histogram(
+    events: Vec<Event>,
+    unit: TimeUnit,
+    calendar: &Calendar,
+    key: Option<&str>,
+) -> Vec<HistogramBin> {
+    let mut bins: Vec<HistogramBin> = Vec::new();
+    let mut bin_index: HashMap<(i64, String), usize> = HashMap::new();
+    for event in split_at_boundaries(events, unit, calendar) {
+        let value = match key {
+            Some(key) => match event.data.get(key) {
+                Some(value) => Some(value.clone()),
+                None => continue,
+            },
+            None => None,
+        };
+        let start = unit.start_of(&event.timestamp, calendar);
+        let index_key = (
+            start.timestamp(),
+            value.as_ref().map(|v| v.to_string()).unwrap_or_default(),
+        );
+        let index = *bin_index.entry(index_key).or_insert_with(|| {
+            bins.push(HistogramBin {
+                start: calendar.timezone.to_local(&start),
+                value,
+                duration: Duration::zero(),
+            });
+            bins.len() - 1
+        });
+        bins[index].duration = bins[index].duration + event.duration;
+    }
+    bins.sort_by(|a, b| a.start.cmp(&b.start).then(b.duration.cmp(&a.duration)));
+    bins
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, Duration, Utc};
+    use serde_json::json;
+
+    use aw_models::Event;
+
+    use super::{histogram, split_at_boundaries, HistogramBin, TimeUnit};
+    use crate::calendar::Calendar;
+
+    fn event(
+        timestamp: &str,
+        minutes: i64,
+        data: serde_json::Map<String, serde_json::Value>,
+    ) -> Event {
+        Event {
+            id: None,
+            timestamp: DateTime::from_str(timestamp).unwrap(),
+            duration: Duration::minutes(minutes),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_start_of() {
+        let utc = Calendar::default();
+        let plus_two = Calendar::new("+02:00", "00:00").unwrap();
+        // A saturday
+        let dt = DateTime::<Utc>::from_str("2000-01-01T23:30:00Z").unwrap();
+        let start = |unit: TimeUnit, calendar| unit.start_of(&dt, calendar).to_rfc3339();
+        assert_eq!(start(TimeUnit::Hour, &utc), "2000-01-01T23:00:00+00:00");
+        assert_eq!(start(TimeUnit::Day, &utc), "2000-01-01T00:00:00+00:00");
+        assert_eq!(start(TimeUnit::Week, &utc), "1999-12-27T00:00:00+00:00");
+        // Which is already sunday at +02:00
+        assert_eq!(start(TimeUnit::Day, &plus_two), "2000-01-01T22:00:00+00:00");
+        assert_eq!(
+            start(TimeUnit::Week, &plus_two),
+            "1999-12-26T22:00:00+00:00"
+        );
+        // But still saturday if days start at 04:00
+        let calendar = Calendar::new("+02:00", "04:00").unwrap();
+        assert_eq!(start(TimeUnit::Day, &calendar), "2000-01-01T02:00:00+00:00");
+        assert_eq!(
+            TimeUnit::Day.end_of(&dt, &calendar).to_rfc3339(),
+            "2000-01-02T02:00:00+00:00"
+        );
+        // Offsets which aren't whole hours
+        let calendar = Calendar::new("+05:30", "00:00").unwrap();
+        assert_eq!(
+            start(TimeUnit::Hour, &calendar),
+            "2000-01-01T23:30:00+00:00"
+        );
+        // Hours are still an hour long when the clocks are turned back
+        let calendar = Calendar::new("Europe/Stockholm", "02:00").unwrap();
+        let dt = DateTime::<Utc>::from_str("2020-10-25T01:30:00Z").unwrap();
+        assert_eq!(
+            TimeUnit::Hour.start_of(&dt, &calendar).to_rfc3339(),
+            "2020-10-25T01:00:00+00:00"
+        );
+        assert_eq!(
+            TimeUnit::Day.end_of(&dt, &calendar) - TimeUnit::Day.start_of(&dt, &calendar),
+            Duration::hours(25)
+        );
+    }
+
+    #[test]
+    fn test_split_at_boundaries() {
+        let events = vec![
+            event("2000-01-01T00:30:00Z", 105, json_map! {}),
+            event("2000-01-01T03:00:00Z", 60, json_map! {}),
+        ];
+        let res = split_at_boundaries(events, TimeUnit::Hour, &Calendar::default());
+        let res: Vec<(String, i64)> = res
+            .iter()
+            .map(|e| (e.timestamp.to_rfc3339(), e.duration.num_minutes()))
+            .collect();
+        assert_eq!(
+            res,
+            vec![
+                ("2000-01-01T00:30:00+00:00".to_string(), 30),
+                ("2000-01-01T01:00:00+00:00".to_string(), 60),
+                ("2000-01-01T02:00:00+00:00".to_string(), 15),
+                ("2000-01-01T03:00:00+00:00".to_string(), 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let calendar = Calendar::new("+01:00", "00:00").unwrap();
+        let events = vec![
+            event("2000-01-01T00:30:00Z", 60, json_map! {"a": json!(1)}),
+            event("2000-01-01T00:45:00Z", 15, json_map! {"a": json!(2)}),
+            event("2000-01-01T01:30:00Z", 30, json_map! {"a": json!(1)}),
+            event("2000-01-01T01:30:00Z", 30, json_map! {}),
+        ];
+        let res = histogram(events.clone(), TimeUnit::Hour, &calendar, None);
+        let bin = |start: &str, value, minutes| HistogramBin {
+            start: DateTime::parse_from_rfc3339(start).unwrap(),
+            value,
+            duration: Duration::minutes(minutes),
+        };
+        assert_eq!(
+            res,
+            vec![
+                bin("2000-01-01T01:00:00+01:00", None, 45),
+                bin("2000-01-01T02:00:00+01:00", None, 90),
+            ]
+        );
+
+        let res = histogram(events, TimeUnit::Hour, &calendar, Some("a"));
+        assert_eq!(
+            res,
+            vec![
+                bin("2000-01-01T01:00:00+01:00", Some(json!(1)), 30),
+                bin("2000-01-01T01:00:00+01:00", Some(json!(2)), 15),
+                bin("2000-01-01T02:00:00+01:00", Some(json!(1)), 60),
+            ]
+        );
+
+        // The day the clocks were turned forward is an hour shorter
+        let calendar = Calendar::new("Europe/Stockholm", "00:00").unwrap();
+        let events = vec![event("2020-03-28T23:00:00Z", 24 * 60, json_map! {})];
+        let res = histogram(events, TimeUnit::Day, &calendar, None);
+        assert_eq!(
+            res,
+            vec![
+                bin("2020-03-29T00:00:00+01:00", None, 23 * 60),
+                bin("2020-03-30T00:00:00+02:00", None, 60),
+            ]
+        );
+    }
+}
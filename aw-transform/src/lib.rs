@@ -30,9 +30,18 @@ pub use flood::flood;
 mod merge;
 pub use merge::merge_events_by_keys;
 
+mod group_by;
+pub use group_by::{group_by, Aggregation};
+
 mod chunk;
 pub use chunk::chunk_events_by_key;
 
+mod calendar;
+pub use calendar::{Calendar, Timezone};
+
+mod histogram;
+pub use histogram::{histogram, split_at_boundaries, HistogramBin, TimeUnit};
+
 mod sort;
 pub use sort::{sort_by_duration, sort_by_timestamp};
 